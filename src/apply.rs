@@ -0,0 +1,207 @@
+use crate::builtins::{conjunction, cons, equals, fresh, goal};
+use crate::terms::Term;
+
+// library(apply): predicates that call a closure on list elements, with the elements added to the
+// closure's arguments as call/N does. A proper list is unrolled into one conjunction; a partial list
+// gets the two clauses of the usual definition, so the list is built on backtracking.
+
+fn call(closure: &Term, extra: Vec<Term>) -> Term {
+    goal("call", [closure.clone()].into_iter().chain(extra).collect())
+}
+
+// The length of the first proper list among `lists`
+fn known_length(lists: &[Term]) -> Option<usize> {
+    lists.iter().find_map(Term::to_vec).map(|items| items.len())
+}
+
+// The elements of each list, as `length` fresh variables unless the list is already proper.
+// Returns the goals shaping the lists and the elements, or None if a proper list is too short or long.
+fn elements(lists: &[Term], length: usize, suffix: usize) -> Option<(Vec<Term>, Vec<Vec<Term>>)> {
+    let mut shapes = vec![];
+    let mut columns = vec![];
+    for (k, list) in lists.iter().enumerate() {
+        match list.to_vec() {
+            Some(items) if items.len() == length => columns.push(items),
+            Some(_) => return None,
+            None => {
+                let items: Vec<Term> = (0..length).map(|i| fresh(&format!("Item{}_{}", k, i), suffix)).collect();
+                shapes.push(equals(list.clone(), Term::list_from_vec(items.clone())));
+                columns.push(items);
+            }
+        }
+    }
+    Some((shapes, columns))
+}
+
+// The heads and tails for the recursive clause over lists that are not all proper
+fn split(lists: &[Term], suffix: usize) -> (Vec<Term>, Vec<Term>, Vec<Term>) {
+    let heads: Vec<Term> = (0..lists.len()).map(|k| fresh(&format!("Head{}", k), suffix)).collect();
+    let tails: Vec<Term> = (0..lists.len()).map(|k| fresh(&format!("Tail{}", k), suffix)).collect();
+    let shapes = lists.iter().zip(heads.iter().zip(&tails)).map(|(list, (head, tail))| equals(list.clone(), cons(head.clone(), tail.clone()))).collect();
+    (shapes, heads, tails)
+}
+
+// maplist(G, L1, ..., Ln): call(G, X1, ..., Xn) for the elements at each position in turn
+pub fn maplist(args: &[Term], suffix: usize) -> Vec<Term> {
+    let (closure, lists) = (&args[0], &args[1..]);
+    if let Some(length) = known_length(lists) {
+        let Some((mut goals, columns)) = elements(lists, length, suffix) else { return vec![] };
+        goals.extend((0..length).map(|i| call(closure, columns.iter().map(|items| items[i].clone()).collect())));
+        return vec![conjunction(goals)];
+    }
+    // maplist(_, [], ..., []).
+    // maplist(G, [X1|T1], ..., [Xn|Tn]) :- call(G, X1, ..., Xn), maplist(G, T1, ..., Tn).
+    let (mut step, heads, tails) = split(lists, suffix);
+    step.push(call(closure, heads));
+    step.push(goal("maplist", [closure.clone()].into_iter().chain(tails).collect()));
+    vec![
+        conjunction(lists.iter().map(|list| equals(list.clone(), Term::EmptyList)).collect()),
+        conjunction(step),
+    ]
+}
+
+// foldl(G, L1, ..., Ln, V0, V): call(G, X1, ..., Xn, V0, V1) for each position, threading the value
+pub fn foldl(args: &[Term], suffix: usize) -> Vec<Term> {
+    let (closure, lists) = (&args[0], &args[1..args.len() - 2]);
+    let (start, end) = (&args[args.len() - 2], &args[args.len() - 1]);
+    if let Some(length) = known_length(lists) {
+        let Some((mut goals, columns)) = elements(lists, length, suffix) else { return vec![] };
+        let mut value = start.clone();
+        for i in 0..length {
+            let next = if i + 1 == length { end.clone() } else { fresh(&format!("Value{}", i), suffix) };
+            let extra = columns.iter().map(|items| items[i].clone()).chain([value, next.clone()]).collect();
+            goals.push(call(closure, extra));
+            value = next;
+        }
+        if length == 0 {
+            goals.push(equals(start.clone(), end.clone()));
+        }
+        return vec![conjunction(goals)];
+    }
+    // foldl(_, [], ..., [], V, V).
+    // foldl(G, [X1|T1], ..., [Xn|Tn], V0, V) :- call(G, X1, ..., Xn, V0, V1), foldl(G, T1, ..., Tn, V1, V).
+    let (mut step, heads, tails) = split(lists, suffix);
+    let value = fresh("Value", suffix);
+    step.push(call(closure, heads.into_iter().chain([start.clone(), value.clone()]).collect()));
+    step.push(goal("foldl", [closure.clone()].into_iter().chain(tails).chain([value, end.clone()]).collect()));
+    let mut empty: Vec<Term> = lists.iter().map(|list| equals(list.clone(), Term::EmptyList)).collect();
+    empty.push(equals(start.clone(), end.clone()));
+    vec![conjunction(empty), conjunction(step)]
+}
+
+// partition(P, List, Included, Excluded): each element goes to Included if call(P, X) succeeds,
+// to Excluded otherwise. include/3 and exclude/3 are the same with one side ignored.
+pub fn partition(name: &str, args: &[Term], suffix: usize) -> Vec<Term> {
+    let (pred, list) = (&args[0], &args[1]);
+    let ignored = fresh("Ignored", suffix);
+    let (included, excluded) = match name {
+        "include" => (&args[2], &ignored),
+        "exclude" => (&ignored, &args[2]),
+        _ => (&args[2], &args[3]),
+    };
+    let step = |item: &Term, included: &Term, excluded: &Term, rest_included: Term, rest_excluded: Term| {
+        goal(";", vec![
+            goal("->", vec![
+                call(pred, vec![item.clone()]),
+                conjunction(vec![equals(included.clone(), cons(item.clone(), rest_included.clone())), equals(excluded.clone(), rest_excluded.clone())]),
+            ]),
+            conjunction(vec![equals(included.clone(), rest_included), equals(excluded.clone(), cons(item.clone(), rest_excluded))]),
+        ])
+    };
+    match (list, list.to_vec()) {
+        (_, Some(items)) => {
+            let mut goals = vec![];
+            let (mut included, mut excluded) = (included.clone(), excluded.clone());
+            for (i, item) in items.iter().enumerate() {
+                let (next_included, next_excluded) = (fresh(&format!("Included{}", i), suffix), fresh(&format!("Excluded{}", i), suffix));
+                goals.push(step(item, &included, &excluded, next_included.clone(), next_excluded.clone()));
+                (included, excluded) = (next_included, next_excluded);
+            }
+            goals.push(equals(included, Term::EmptyList));
+            goals.push(equals(excluded, Term::EmptyList));
+            vec![conjunction(goals)]
+        }
+        (Term::List(_, _) | Term::Variable(_), None) => {
+            let (head, tail) = (fresh("Head", suffix), fresh("Tail", suffix));
+            let (rest_included, rest_excluded) = (fresh("Included", suffix), fresh("Excluded", suffix));
+            vec![
+                conjunction(vec![equals(list.clone(), Term::EmptyList), equals(included.clone(), Term::EmptyList), equals(excluded.clone(), Term::EmptyList)]),
+                conjunction(vec![
+                    equals(list.clone(), cons(head.clone(), tail.clone())),
+                    step(&head, included, excluded, rest_included.clone(), rest_excluded.clone()),
+                    goal("partition", vec![pred.clone(), tail, rest_included, rest_excluded]),
+                ]),
+            ]
+        }
+        _ => vec![],
+    }
+}
+
+// Goals for a library(apply) predicate, with its arguments already resolved
+pub fn solve(name: &str, args: &[Term], suffix: usize) -> Vec<Term> {
+    match name {
+        "maplist" => maplist(args, suffix),
+        "foldl" => foldl(args, suffix),
+        _ => partition(name, args, suffix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::{answers, load_program};
+
+    fn var(name: &str) -> Term {
+        Term::Variable(name.to_string())
+    }
+
+    #[test]
+    fn test_maplist_unrolls_proper_lists() {
+        let list = Term::list_from_vec(vec![Term::Integer(1), Term::Integer(2)]);
+        let goals = maplist(&[var("G"), list, var("L")], 0);
+        let items = vec![fresh("Item1_0", 0), fresh("Item1_1", 0)];
+        assert_eq!(goals, vec![conjunction(vec![
+            equals(var("L"), Term::list_from_vec(items.clone())),
+            call(&var("G"), vec![Term::Integer(1), items[0].clone()]),
+            call(&var("G"), vec![Term::Integer(2), items[1].clone()]),
+        ])]);
+        let short = Term::list_from_vec(vec![Term::Integer(1)]);
+        assert!(maplist(&[var("G"), short, Term::EmptyList], 0).is_empty());
+    }
+
+    #[test]
+    fn test_partial_lists_use_both_clauses() {
+        assert_eq!(maplist(&[var("G"), var("L")], 0).len(), 2);
+        assert_eq!(foldl(&[var("G"), var("L"), var("V0"), var("V")], 0).len(), 2);
+        assert!(partition("include", &[var("P"), Term::Constant("a".into()), var("I")], 0).is_empty());
+    }
+
+    const PROGRAM: &str = "double(X, Y) :- Y is X * 2.
+add(X, Y, Z) :- Z is X + Y.
+small(X) :- X < 3.
+dot(X, Y, A0, A) :- A is A0 + X * Y.";
+
+    #[test]
+    fn test_maplist_calls_closures_with_extra_arguments() {
+        let mut db = load_program(PROGRAM);
+        assert_eq!(answers(&mut db, "maplist(double, [1, 2, 3], X)."), "[2, 4, 6]");
+        assert_eq!(answers(&mut db, "maplist(add, [1, 2], [3, 4], X)."), "[4, 6]");
+        assert_eq!(answers(&mut db, "maplist(add(10), [1, 2], X)."), "[11, 12]");
+        assert_eq!(answers(&mut db, "maplist(=(a), L), length(L, 2), !, X = L."), "[a, a]");
+        assert_eq!(answers(&mut db, "length(X, 2), maplist(=(b), X)."), "[b, b]");
+        assert_eq!(answers(&mut db, "maplist(member, [X, Y], [[1, 2], [3]])."), "1 ; 2");
+        assert_eq!(answers(&mut db, "use_module(library(apply)), maplist(atom, [a, b]) -> X = yes ; X = no."), "yes");
+        assert_eq!(answers(&mut db, "use_module(library(apply)), maplist(atom, [a, 1]) -> X = yes ; X = no."), "no");
+    }
+
+    #[test]
+    fn test_foldl_and_filters() {
+        let mut db = load_program(PROGRAM);
+        assert_eq!(answers(&mut db, "foldl(add, [1, 2, 3], 0, X)."), "6");
+        assert_eq!(answers(&mut db, "foldl(dot, [1, 2], [3, 4], 0, X)."), "11");
+        assert_eq!(answers(&mut db, "include(small, [1, 5, 2, 4], X)."), "[1, 2]");
+        assert_eq!(answers(&mut db, "exclude(small, [1, 5, 2, 4], X)."), "[5, 4]");
+        assert_eq!(answers(&mut db, "partition(small, [1, 5, 2], I, E), X = I/E."), "[1, 2]/[5]");
+        assert_eq!(answers(&mut db, "include(integer, [a, 1, f(b), 2], X)."), "[1, 2]");
+    }
+}
@@ -0,0 +1,233 @@
+use std::cmp::Ordering;
+
+use crate::builtins::{equals, goal};
+use crate::errors;
+use crate::lists::proper_list;
+use crate::terms::Term;
+
+// library(assoc): AVL trees kept in plain terms, so an association list can be bound, copied and
+// backtracked over like any other term. The empty tree is `t`; a node is t(Key, Value, Balance,
+// Left, Right), where Balance is `<` if Left is the lower subtree, `>` if it is the higher one and
+// `=` if they have the same height. Keys are compared in the standard order of terms.
+
+// A node of a tree, borrowed from the term
+struct Node<'a> {
+    key: &'a Term,
+    value: &'a Term,
+    balance: Ordering, // The height of Left compared to that of Right
+    left: &'a Term,
+    right: &'a Term,
+}
+
+fn empty() -> Term {
+    Term::Constant("t".to_string())
+}
+
+fn node(key: Term, value: Term, balance: Ordering, left: Term, right: Term) -> Term {
+    let symbol = match balance {
+        Ordering::Less => "<",
+        Ordering::Equal => "=",
+        Ordering::Greater => ">",
+    };
+    goal("t", vec![key, value, Term::Constant(symbol.to_string()), left, right])
+}
+
+// None for the empty tree
+fn parse(assoc: &Term) -> Result<Option<Node<'_>>, Term> {
+    match assoc {
+        Term::Constant(name) if name == "t" => Ok(None),
+        Term::Compound(name, args) if name == "t" && args.len() == 5 => {
+            let balance = match &args[2] {
+                Term::Constant(symbol) if symbol == "<" => Ordering::Less,
+                Term::Constant(symbol) if symbol == "=" => Ordering::Equal,
+                Term::Constant(symbol) if symbol == ">" => Ordering::Greater,
+                _ => return Err(errors::type_error("assoc", assoc.clone())),
+            };
+            Ok(Some(Node { key: &args[0], value: &args[1], balance, left: &args[3], right: &args[4] }))
+        }
+        Term::Variable(_) => Err(errors::instantiation_error()),
+        _ => Err(errors::type_error("assoc", assoc.clone())),
+    }
+}
+
+// Inserts or replaces the value of a key, returning the new tree and whether it grew higher
+fn insert(assoc: &Term, key: &Term, value: &Term) -> Result<(Term, bool), Term> {
+    let Some(node) = parse(assoc)? else {
+        return Ok((self::node(key.clone(), value.clone(), Ordering::Equal, empty(), empty()), true));
+    };
+    let side = key.standard_order(node.key);
+    let (left, right, grew) = match side {
+        Ordering::Equal => {
+            let updated = self::node(key.clone(), value.clone(), node.balance, node.left.clone(), node.right.clone());
+            return Ok((updated, false));
+        }
+        Ordering::Less => {
+            let (left, grew) = insert(node.left, key, value)?;
+            (left, node.right.clone(), grew)
+        }
+        Ordering::Greater => {
+            let (right, grew) = insert(node.right, key, value)?;
+            (node.left.clone(), right, grew)
+        }
+    };
+    let (key, value) = (node.key.clone(), node.value.clone());
+    // Unless it grew, the subtree on `side` is as high as before
+    match node.balance {
+        _ if !grew => Ok((self::node(key, value, node.balance, left, right), false)),
+        Ordering::Equal => Ok((self::node(key, value, side.reverse(), left, right), true)),
+        balance if balance == side => Ok((self::node(key, value, Ordering::Equal, left, right), false)),
+        _ => Ok((rebalance(key, value, left, right, side)?, false)),
+    }
+}
+
+// Rotates a node whose subtree on `side` has become two levels higher than the other one
+fn rebalance(key: Term, value: Term, left: Term, right: Term, side: Ordering) -> Result<Term, Term> {
+    let left_heavy = side == Ordering::Less;
+    let (higher, other) = if left_heavy { (left, right) } else { (right, left) };
+    let child = parse(&higher)?.expect("a higher subtree is not empty");
+    // Subtrees and balances from here on are seen from the heavy side: outer, then inner
+    let oriented = |balance: Ordering| if left_heavy { balance } else { balance.reverse() };
+    let join = |key: Term, value: Term, balance: Ordering, outer: Term, inner: Term| match left_heavy {
+        true => node(key, value, balance, outer, inner),
+        false => node(key, value, balance.reverse(), inner, outer),
+    };
+    let (outer, inner) = if left_heavy { (child.left, child.right) } else { (child.right, child.left) };
+    if oriented(child.balance) == Ordering::Greater {
+        // Single rotation: the child becomes the root
+        let lowered = join(key, value, Ordering::Equal, inner.clone(), other);
+        return Ok(join(child.key.clone(), child.value.clone(), Ordering::Equal, outer.clone(), lowered));
+    }
+    // Double rotation: the child's inner subtree becomes the root
+    let grandchild = parse(inner)?.expect("a higher subtree is not empty");
+    let (near, far) = if left_heavy { (grandchild.left, grandchild.right) } else { (grandchild.right, grandchild.left) };
+    let leaning = oriented(grandchild.balance);
+    let child_balance = if leaning == Ordering::Less { Ordering::Greater } else { Ordering::Equal };
+    let node_balance = if leaning == Ordering::Greater { Ordering::Less } else { Ordering::Equal };
+    let first = join(child.key.clone(), child.value.clone(), child_balance, outer.clone(), near.clone());
+    let second = join(key, value, node_balance, far.clone(), other);
+    Ok(join(grandchild.key.clone(), grandchild.value.clone(), Ordering::Equal, first, second))
+}
+
+fn lookup<'a>(mut assoc: &'a Term, key: &Term) -> Result<Option<&'a Term>, Term> {
+    while let Some(node) = parse(assoc)? {
+        assoc = match key.standard_order(node.key) {
+            Ordering::Equal => return Ok(Some(node.value)),
+            Ordering::Less => node.left,
+            Ordering::Greater => node.right,
+        };
+    }
+    Ok(None)
+}
+
+// The Key-Value pairs in order of their keys
+fn pairs<'a>(assoc: &'a Term, pairs: &mut Vec<(&'a Term, &'a Term)>) -> Result<(), Term> {
+    if let Some(node) = parse(assoc)? {
+        self::pairs(node.left, pairs)?;
+        pairs.push((node.key, node.value));
+        self::pairs(node.right, pairs)?;
+    }
+    Ok(())
+}
+
+// The node with the largest or smallest key
+fn extreme(assoc: &Term, max: bool) -> Result<Option<Node<'_>>, Term> {
+    let Some(mut node) = parse(assoc)? else { return Ok(None) };
+    loop {
+        match parse(if max { node.right } else { node.left })? {
+            Some(child) => node = child,
+            None => return Ok(Some(node)),
+        }
+    }
+}
+
+fn from_list(list: &Term) -> Result<Term, Term> {
+    let mut assoc = empty();
+    for pair in proper_list(list)? {
+        match &pair {
+            Term::Compound(name, args) if name == "-" && args.len() == 2 => {
+                if lookup(&assoc, &args[0])?.is_some() {
+                    return Err(errors::domain_error("unique_key_pairs", list.clone()));
+                }
+                assoc = insert(&assoc, &args[0], &args[1])?.0;
+            }
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            other => return Err(errors::type_error("pair", other.clone())),
+        }
+    }
+    Ok(assoc)
+}
+
+// Goals for a library(assoc) predicate, with its arguments already resolved
+pub fn solve(name: &str, args: &[Term]) -> Result<Vec<Term>, Term> {
+    let pair = |key: Term, value: Term| goal("-", vec![key, value]);
+    Ok(match name {
+        "empty_assoc" => vec![equals(args[0].clone(), empty())],
+        "put_assoc" => vec![equals(args[3].clone(), insert(&args[1], &args[0], &args[2])?.0)],
+        "get_assoc" => match lookup(&args[1], &args[0])? {
+            Some(value) => vec![equals(args[2].clone(), value.clone())],
+            None => vec![],
+        },
+        "list_to_assoc" => vec![equals(args[1].clone(), from_list(&args[0])?)],
+        "assoc_to_list" | "assoc_to_keys" | "assoc_to_values" => {
+            let mut found = vec![];
+            pairs(&args[0], &mut found)?;
+            let items = found.into_iter().map(|(key, value)| match name {
+                "assoc_to_list" => pair(key.clone(), value.clone()),
+                "assoc_to_keys" => key.clone(),
+                _ => value.clone(),
+            });
+            vec![equals(args[1].clone(), Term::list_from_vec(items.collect()))]
+        }
+        _ => match extreme(&args[0], name == "max_assoc")? {
+            Some(node) => vec![equals(pair(args[1].clone(), args[2].clone()), pair(node.key.clone(), node.value.clone()))],
+            None => vec![],
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::{answers, load_program};
+
+    // The height of a tree, checking that each node's balance is right
+    fn height(assoc: &Term) -> usize {
+        match parse(assoc).unwrap() {
+            None => 0,
+            Some(node) => {
+                let (left, right) = (height(node.left), height(node.right));
+                assert_eq!(node.balance, left.cmp(&right), "{}", assoc);
+                assert!(left.abs_diff(right) <= 1);
+                left.max(right) + 1
+            }
+        }
+    }
+
+    #[test]
+    fn test_insertions_keep_the_tree_balanced() {
+        for keys in [(0..100).collect::<Vec<i64>>(), (0..100).rev().collect(), (0..100).map(|n| (n * 37) % 101).collect()] {
+            let mut assoc = empty();
+            for key in &keys {
+                assoc = insert(&assoc, &Term::Integer(*key), &Term::Integer(key * 2)).unwrap().0;
+            }
+            assert!(height(&assoc) <= 8);
+            assert_eq!(lookup(&assoc, &Term::Integer(42)).unwrap(), Some(&Term::Integer(84)));
+            assert_eq!(lookup(&assoc, &Term::Integer(200)).unwrap(), None);
+            let mut found = vec![];
+            pairs(&assoc, &mut found).unwrap();
+            assert_eq!(found.len(), keys.len());
+            assert!(found.windows(2).all(|pair| pair[0].0.standard_order(pair[1].0).is_lt()));
+        }
+    }
+
+    #[test]
+    fn test_assoc_predicates() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "list_to_assoc([b-2, a-1], A), put_assoc(c, A, 3, A2), get_assoc(c, A2, X)."), "3");
+        assert_eq!(answers(&mut db, "list_to_assoc([b-2, a-1, c-3], A), assoc_to_list(A, X)."), "[a-1, b-2, c-3]");
+        assert_eq!(answers(&mut db, "empty_assoc(E), put_assoc(k, E, v, A), put_assoc(k, A, w, A2), assoc_to_values(A2, X)."), "[w]");
+        assert_eq!(answers(&mut db, "numlist(1, 50, L), pairs_keys_values(P, L, L), list_to_assoc(P, A), max_assoc(A, K, V), X = K-V."), "50-50");
+        assert_eq!(answers(&mut db, "list_to_assoc([x-1], A), (get_assoc(y, A, _) -> X = found ; X = missing)."), "missing");
+        assert_eq!(answers(&mut db, "catch(list_to_assoc([a-1, a-2], _), error(E, _), X = E)."), "domain_error(unique_key_pairs, [a-1, a-2])");
+    }
+}
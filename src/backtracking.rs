@@ -1,9 +1,83 @@
+use std::rc::Rc;
+
+use crate::database::ModuleName;
 use crate::environment::Environment;
-use crate::terms::Term;
+use crate::terms::{Clause, Term};
+
+// Goals still to be proven, shared between choice points so capturing them is cheap
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Continuation(Option<Rc<Frame>>);
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub goal: Term,
+    pub cut_barrier: usize, // Height of the choice point stack that a `!` in this goal cuts back to
+    pub module: ModuleName, // Context module the goal is called in
+    pub next: Continuation,
+}
+
+impl Continuation {
+    pub fn done() -> Self {
+        Continuation(None)
+    }
+
+    pub fn push(&self, goal: Term, cut_barrier: usize, module: ModuleName) -> Self {
+        Continuation(Some(Rc::new(Frame { goal, cut_barrier, module, next: self.clone() })))
+    }
+
+    pub fn pop(&self) -> Option<&Frame> {
+        self.0.as_deref()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Alternatives {
+    Goals(Vec<Term>),                                // Goals to try in turn, e.g. the right side of `;`
+    Clauses(ClauseIteration),                        // Remaining clauses of a predicate
+    Catch(Term, Term),                               // Catcher and recovery goal of an active catch/3
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClauseMode {
+    Call,
+    Retract,
+    Inspect, // clause/2: unify head and body without running or removing the clause
+}
+
+// Clauses are taken from a snapshot of the predicate, so a running call doesn't see clauses that
+// are added or removed while it iterates (the logical update view)
+#[derive(Debug, PartialEq)]
+pub struct ClauseIteration {
+    pub mode: ClauseMode,
+    pub module: ModuleName, // Module the clauses are defined in
+    pub head: Term,
+    pub body: Term,
+    pub clauses: Rc<Vec<Rc<Clause>>>,
+    pub next: usize,
+}
 
 pub struct ChoicePoint {
     pub env: Environment,
-    pub alternatives: Vec<Term>,
+    pub goals: Continuation,
+    pub cut_barrier: usize,
+    pub module: ModuleName, // Context module the alternatives run in
+    pub alternatives: Alternatives,
+}
+
+impl ChoicePoint {
+    pub fn new(env: Environment, alternatives: Vec<Term>) -> Self {
+        ChoicePoint {
+            env,
+            goals: Continuation::done(),
+            cut_barrier: 0,
+            module: "user".into(),
+            alternatives: Alternatives::Goals(alternatives),
+        }
+    }
 }
 
 pub struct BacktrackingStack {
@@ -20,6 +94,19 @@ impl BacktrackingStack {
     pub fn pop(&mut self) -> Option<ChoicePoint> {
         self.stack.pop()
     }
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+    pub fn last(&self) -> Option<&ChoicePoint> {
+        self.stack.last()
+    }
+    // Removes every choice point above `height`, as done by `!`
+    pub fn cut(&mut self, height: usize) {
+        self.stack.truncate(height);
+    }
 }
 
 #[cfg(test)]
@@ -34,17 +121,14 @@ mod tests {
         let env = Environment::new();
         let alt = vec![Term::Integer(42)];
 
-        let choice = ChoicePoint {
-            env,
-            alternatives: alt.clone(),
-        };
+        let choice = ChoicePoint::new(env, alt.clone());
 
         stack.push(choice);
         let popped = stack.pop();
 
         assert!(popped.is_some());
         let popped_choice = popped.unwrap();
-        assert_eq!(popped_choice.alternatives, alt);
+        assert_eq!(popped_choice.alternatives, Alternatives::Goals(alt));
     }
 
     #[test]
@@ -57,24 +141,39 @@ mod tests {
     fn test_stack_lifo_order() {
         let mut stack = BacktrackingStack::new();
 
-        let cp1 = ChoicePoint {
-            env: Environment::new(),
-            alternatives: vec![Term::Integer(1)],
-        };
-        let cp2 = ChoicePoint {
-            env: Environment::new(),
-            alternatives: vec![Term::Integer(2)],
-        };
+        let cp1 = ChoicePoint::new(Environment::new(), vec![Term::Integer(1)]);
+        let cp2 = ChoicePoint::new(Environment::new(), vec![Term::Integer(2)]);
 
         stack.push(cp1);
         stack.push(cp2);
 
         let last = stack.pop().unwrap();
-        assert_eq!(last.alternatives[0], Term::Integer(2));
+        assert_eq!(last.alternatives, Alternatives::Goals(vec![Term::Integer(2)]));
 
         let first = stack.pop().unwrap();
-        assert_eq!(first.alternatives[0], Term::Integer(1));
+        assert_eq!(first.alternatives, Alternatives::Goals(vec![Term::Integer(1)]));
 
         assert!(stack.pop().is_none());
     }
+
+    #[test]
+    fn test_cut_removes_newer_choice_points() {
+        let mut stack = BacktrackingStack::new();
+        for n in 0..3 {
+            stack.push(ChoicePoint::new(Environment::new(), vec![Term::Integer(n)]));
+        }
+        stack.cut(1);
+        assert_eq!(stack.len(), 1);
+        assert_eq!(stack.pop().unwrap().alternatives, Alternatives::Goals(vec![Term::Integer(0)]));
+    }
+
+    #[test]
+    fn test_continuation_is_shared() {
+        let rest = Continuation::done().push(Term::Constant("b".into()), 0, "user".into());
+        let goals = rest.push(Term::Constant("a".into()), 0, "user".into());
+        let frame = goals.pop().unwrap();
+        assert_eq!(frame.goal, Term::Constant("a".into()));
+        assert_eq!(frame.next, rest);
+        assert!(frame.next.pop().unwrap().next.is_done());
+    }
 }
@@ -1,299 +1,393 @@
-use crate::unification::{Substitution, unify};
 use crate::terms::Term;
+use crate::errors;
 
-pub fn builtin_append(args: &[Term]) -> Option<Substitution> {
-    if args.len() != 3 {
-        return None;
-    }
-
-    let list1 = &args[0];
-    let list2 = &args[1];
-    let result = &args[2];
-
-    // Try converting terms to Vec representations
-    match (list1.to_vec(), list2.to_vec(), result.to_vec()) {
-        (Some(vec1), Some(vec2), _) => {
-            // Both input lists known, unify combined with result
-            let combined = [vec1, vec2].concat();
-            let combined_term = Term::from_vec(&combined);
-            let mut subs = Substitution::new();
-            if unify(&args[2], &combined_term, &mut subs) {
-                Some(subs)
-            } else {
-                None
-            }
-        }
-        (Some(vec1), _none, Some(result_vec)) => {
-            // First list and result known, calculate second list
-            if result_vec.starts_with(&vec1) {
-                let remaining = &result_vec[vec1.len()..];
-                let mut subs = Substitution::new();
-                if unify(&args[1], &Term::from_vec(remaining), &mut subs) {
-                    Some(subs)
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        }
-        (_none, Some(vec2), Some(result_vec)) => {
-            // Second list and result known, unify to find first list
-            if result_vec.ends_with(&vec2) {
-                let prefix = &result_vec[..result_vec.len() - vec2.len()];
-                let mut subs = Substitution::new();
-                if unify(&args[0], &Term::from_vec(prefix), &mut subs) {
-                    Some(subs)
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        }
-        _ => None,
-    }
-}
+// Nondeterministic builtins return the goals to try in turn, one for each way they can succeed.
+// A goal may call the builtin again on the rest of the input, so long or endless enumerations
+// such as `between(1, inf, X)` are produced one solution at a time. `suffix` keeps the fresh
+// variables of each call apart, as it does for renamed clauses.
 
-pub fn builtin_member(args: &[Term]) -> Option<Substitution> {
-    if args.len() != 2 { return None }  // Ensure correct arity
-    let element = &args[0];      // Element to check for
-    let list = &args[1];         // List to check
-    match list {
-        Term::List(head, tail) => {
-            let mut subs = Substitution::new();
-            // Check if element matches the head of the list
-            if unify(element, head, &mut subs) {
-                return Some(subs);
-            }
-            // Check recursively in the tail
-            builtin_member(&[element.clone(), tail.as_ref().clone()])
-        }
-        Term::EmptyList => None, // Member of an empty list fails
-        _ => None, // Not a valid list structure
-    }
+pub(crate) fn goal(name: &str, args: Vec<Term>) -> Term {
+    Term::Compound(name.to_string(), args)
 }
 
-pub fn builtin_between(args: &[Term]) -> Option<Substitution> {
-    if args.len() != 3 {
-        return None; // Ensure correct arity
-    }
+pub(crate) fn equals(left: Term, right: Term) -> Term {
+    goal("=", vec![left, right])
+}
 
-    let low = match &args[0] {
-        Term::Integer(n) => *n,
-        _ => return None, // First argument must be an integer
-    };
+pub(crate) fn conjunction(mut goals: Vec<Term>) -> Term {
+    let last = goals.pop().unwrap();
+    goals.into_iter().rev().fold(last, |rest, first| goal(",", vec![first, rest]))
+}
 
-    let high = match &args[1] {
-        Term::Integer(n) => *n,
-        _ => return None, // Second argument must be an integer
-    };
+pub(crate) fn cons(head: Term, tail: Term) -> Term {
+    Term::List(Box::new(head), Box::new(tail))
+}
 
-    let var = match &args[2] {
-        Term::Variable(v) => v.clone(),
-        _ => return None, // Third argument must be a variable
-    };
+pub(crate) fn fresh(name: &str, suffix: usize) -> Term {
+    Term::Variable(format!("_{}_{}", name, suffix))
+}
 
-    if low > high {
-        return None; // Invalid range
+pub(crate) fn integer_argument(term: &Term) -> Result<Option<i64>, Term> {
+    match term {
+        Term::Variable(_) => Ok(None),
+        Term::Integer(n) => Ok(Some(*n)),
+        other => Err(errors::type_error("integer", other.clone())),
     }
-
-    // Collect all values as substitutions
-    let results: Vec<String> = (low..=high)
-        .map(|n| format!("{}", n)) // Convert integers to strings
-        .collect();
-
-    let result_str = results.join("; "); // Format output like "1; 2; 3; 4"
-
-    let mut subs = Substitution::new();
-    subs.extend(var, Term::Constant(result_str));
-
-    Some(subs)
 }
 
-pub fn builtin_length(args: &[Term]) -> Option<Substitution> {
-    if args.len() != 2 {
-        return None; // Ensure correct arity
+// append([], L, L).
+// append([H|T], L, [H|R]) :- append(T, L, R).
+pub fn builtin_append(args: &[Term], suffix: usize) -> Vec<Term> {
+    let (head, tail, rest) = (fresh("Head", suffix), fresh("Tail", suffix), fresh("Rest", suffix));
+    let empty = equals(args[1].clone(), args[2].clone());
+    let step = conjunction(vec![
+        equals(args[2].clone(), cons(head.clone(), rest.clone())),
+        goal("append", vec![tail.clone(), args[1].clone(), rest]),
+    ]);
+    match &args[0] {
+        Term::EmptyList => vec![empty],
+        Term::List(_, _) => vec![conjunction(vec![equals(args[0].clone(), cons(head, tail)), step])],
+        Term::Variable(_) => vec![
+            conjunction(vec![equals(args[0].clone(), Term::EmptyList), empty]),
+            conjunction(vec![equals(args[0].clone(), cons(head, tail)), step]),
+        ],
+        _ => vec![],
     }
+}
 
-    // Extract the list and expected length variable
-    let mut count = 0;
-    let mut current = &args[0];
-
-    // Walk through the list to count elements
-    while let Term::List(_head, tail) = current {
-        count += 1;
-        current = tail; // Move to the next element
+// member(X, [X|_]).
+// member(X, [_|T]) :- member(X, T).
+pub fn builtin_member(args: &[Term], suffix: usize) -> Vec<Term> {
+    match &args[1] {
+        // No choice point is left on the last element
+        Term::List(head, tail) if **tail == Term::EmptyList => vec![equals(args[0].clone(), (**head).clone())],
+        Term::List(head, tail) => vec![
+            equals(args[0].clone(), (**head).clone()),
+            goal("member", vec![args[0].clone(), (**tail).clone()]),
+        ],
+        Term::Variable(_) => {
+            let tail = fresh("Tail", suffix);
+            vec![
+                equals(args[1].clone(), cons(args[0].clone(), fresh("Rest", suffix))),
+                conjunction(vec![
+                    equals(args[1].clone(), cons(fresh("Head", suffix), tail.clone())),
+                    goal("member", vec![args[0].clone(), tail]),
+                ]),
+            ]
+        }
+        _ => vec![],
     }
+}
 
-    // If it's an empty list, count remains 0
-    if matches!(current, Term::EmptyList) {
-        let var = match &args[1] {
-            Term::Variable(v) => v.clone(),
-            Term::Integer(n) if *n == count => return Some(Substitution::new()), // Matches given length
-            _ => return None, // Second argument must be a variable or matching integer
-        };
-
-        let mut subs = Substitution::new();
-        subs.extend(var, Term::Integer(count as i64));
-        return Some(subs);
+// select(X, [X|T], T).
+// select(X, [H|T], [H|R]) :- select(X, T, R).
+pub fn builtin_select(args: &[Term], suffix: usize) -> Vec<Term> {
+    if !matches!(args[1], Term::List(_, _) | Term::Variable(_)) {
+        return vec![];
     }
-
-    None // Not a valid list structure
+    let (head, tail, rest) = (fresh("Head", suffix), fresh("Tail", suffix), fresh("Rest", suffix));
+    vec![
+        equals(args[1].clone(), cons(args[0].clone(), args[2].clone())),
+        conjunction(vec![
+            equals(args[1].clone(), cons(head.clone(), tail.clone())),
+            equals(args[2].clone(), cons(head, rest.clone())),
+            goal("select", vec![args[0].clone(), tail, rest]),
+        ]),
+    ]
 }
 
-pub fn builtin_reverse(args: &[Term]) -> Option<Substitution> {
-    if args.len() != 2 {
-        return None; // Ensure correct arity
+// between(Low, High, X), where High may be `inf` or `infinite`
+pub fn builtin_between(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let low = integer_argument(&args[0])?.ok_or_else(errors::instantiation_error)?;
+    let high = match &args[1] {
+        Term::Constant(name) if name == "inf" || name == "infinite" => i64::MAX,
+        high => integer_argument(high)?.ok_or_else(errors::instantiation_error)?,
+    };
+    match integer_argument(&args[2])? {
+        Some(x) if low <= x && x <= high => Ok(true_goal()),
+        Some(_) => Ok(vec![]),
+        None if low > high => Ok(vec![]),
+        None if low == high => Ok(vec![equals(args[2].clone(), Term::Integer(low))]),
+        None => Ok(vec![
+            equals(args[2].clone(), Term::Integer(low)),
+            goal("between", vec![Term::Integer(low + 1), args[1].clone(), args[2].clone()]),
+        ]),
     }
+}
 
-    let list = match &args[0] {
-        Term::List(head, tail) => {
-            let mut elements = vec![head.as_ref().clone()];
-            let mut current_tail = tail.as_ref();
-            while let Term::List(h, t) = current_tail {
-                elements.push(h.as_ref().clone());
-                current_tail = t.as_ref();
-            }
-            elements.reverse();
-            elements
+// nth0/3 and nth1/3: the element at an index, counted from `base`. A known index picks one
+// element (extending a partial list if it is too short); an unknown one enumerates them.
+pub fn builtin_nth(name: &str, base: i64, args: &[Term], suffix: usize) -> Result<Vec<Term>, Term> {
+    match integer_argument(&args[0])? {
+        Some(index) if index < base => Ok(vec![]),
+        Some(index) => {
+            let skipped = (0..index - base).map(|n| fresh(&format!("Skipped{}", n), suffix)).collect::<Vec<_>>();
+            let pattern = skipped.into_iter().rev().fold(cons(args[2].clone(), fresh("Rest", suffix)), |tail, item| cons(item, tail));
+            Ok(vec![equals(args[1].clone(), pattern)])
         }
-        Term::EmptyList => vec![], // Reverse of an empty list is still empty
-        _ => return None, // First argument must be a list
-    };
+        None if !matches!(args[1], Term::List(_, _) | Term::Variable(_)) => Ok(vec![]),
+        None => {
+            let (tail, index) = (fresh("Tail", suffix), fresh("Index", suffix));
+            Ok(vec![
+                conjunction(vec![
+                    equals(args[1].clone(), cons(args[2].clone(), fresh("Rest", suffix))),
+                    equals(args[0].clone(), Term::Integer(base)),
+                ]),
+                conjunction(vec![
+                    equals(args[1].clone(), cons(fresh("Head", suffix), tail.clone())),
+                    goal(name, vec![index.clone(), tail, args[2].clone()]),
+                    goal("is", vec![args[0].clone(), goal("+", vec![index, Term::Integer(1)])]),
+                ]),
+            ])
+        }
+    }
+}
 
-    let var = match &args[1] {
-        Term::Variable(v) => v.clone(),
-        _ => return None, // Second argument must be a variable
-    };
+pub(crate) fn true_goal() -> Vec<Term> {
+    vec![Term::Constant("true".to_string())]
+}
 
-    let reversed_list = Term::list_from_vec(list);
-    let mut subs = Substitution::new();
-    subs.extend(var, reversed_list);
-    Some(subs)
+// Succeeds with `result` if the argument is unbound, or checks it if it is bound
+fn result_goal(target: &Term, result: i64) -> Vec<Term> {
+    vec![equals(target.clone(), Term::Integer(result))]
 }
 
-pub fn builtin_max(args: &[Term]) -> Option<Substitution> {
-    if args.len() != 3 {
-        return None; // Ensure correct arity
+fn not_negative(n: Option<i64>, term: &Term) -> Result<Option<i64>, Term> {
+    match n {
+        Some(n) if n < 0 => Err(errors::type_error("not_less_than_zero", term.clone())),
+        n => Ok(n),
     }
-
-    let left = match &args[0] {
-        Term::Integer(n) => *n,
-        _ => return None, // First argument must be an integer
-    };
-
-    let right = match &args[1] {
-        Term::Integer(n) => *n,
-        _ => return None, // Second argument must be an integer
-    };
-
-    let var = match &args[2] {
-        Term::Variable(v) => v.clone(),
-        _ => return None, // Third argument must be a variable
-    };
-
-    let max_val = std::cmp::max(left, right);
-    let mut subs = Substitution::new();
-    subs.extend(var, Term::Integer(max_val));
-
-    Some(subs)
 }
 
-pub fn builtin_min(args: &[Term]) -> Option<Substitution> {
-    if args.len() != 3 {
-        return None;
+// The items before a list's tail, and the tail: `[]` for a proper list, a variable for a partial one
+pub(crate) fn list_prefix(list: &Term) -> (Vec<Term>, &Term) {
+    let mut items = vec![];
+    let mut current = list;
+    while let Term::List(head, tail) = current {
+        items.push((**head).clone());
+        current = tail;
     }
+    (items, current)
+}
 
-    let left = match &args[0] {
-        Term::Integer(n) => *n,
-        _ => return None,
-    };
-
-    let right = match &args[1] {
-        Term::Integer(n) => *n,
-        _ => return None,
-    };
-
-    let var = match &args[2] {
-        Term::Variable(v) => v.clone(),
-        _ => return None,
-    };
-
-    let min_val = std::cmp::min(left, right);
-    let mut subs = Substitution::new();
-    subs.extend(var, Term::Integer(min_val));
-
-    Some(subs)
+// length(List, N). A partial list with an unknown length is made one longer on each retry.
+pub fn builtin_length(args: &[Term], suffix: usize) -> Result<Vec<Term>, Term> {
+    let length = integer_argument(&args[1])?;
+    if let Some(n) = length.filter(|n| *n < 0) {
+        return Err(errors::domain_error("not_less_than_zero", Term::Integer(n)));
+    }
+    let (items, tail) = list_prefix(&args[0]);
+    let count = items.len() as i64;
+    match (tail, length) {
+        (Term::EmptyList, _) => Ok(result_goal(&args[1], count)),
+        (Term::Variable(_), Some(n)) if n < count => Ok(vec![]),
+        (Term::Variable(_), Some(n)) => {
+            let fresh_items = (0..n - count).map(|i| fresh(&format!("Item{}", i), suffix)).collect();
+            Ok(vec![equals(tail.clone(), Term::list_from_vec(fresh_items))])
+        }
+        (Term::Variable(_), None) => Ok(vec![
+            conjunction(vec![equals(tail.clone(), Term::EmptyList), equals(args[1].clone(), Term::Integer(count))]),
+            conjunction(vec![
+                equals(tail.clone(), cons(fresh("Item", suffix), fresh("Tail", suffix))),
+                goal("length", vec![args[0].clone(), args[1].clone()]),
+            ]),
+        ]),
+        _ => Err(errors::type_error("list", args[0].clone())),
+    }
 }
 
-pub fn builtin_succ(args: &[Term]) -> Option<Substitution> {
-    if args.len() != 2 {
-        return None;
+// reverse(List, Reversed) works from whichever side is a proper list. With neither, lists of
+// growing length are tried.
+pub fn builtin_reverse(args: &[Term], suffix: usize) -> Vec<Term> {
+    let reversed = |list: &Term| list.to_vec().map(|mut items| {
+        items.reverse();
+        Term::list_from_vec(items)
+    });
+    if let Some(reversed) = reversed(&args[0]) {
+        return vec![equals(args[1].clone(), reversed)];
+    }
+    if let Some(reversed) = reversed(&args[1]) {
+        return vec![equals(args[0].clone(), reversed)];
+    }
+    match list_prefix(&args[0]).1 {
+        Term::Variable(_) => vec![conjunction(vec![
+            goal("length", vec![args[0].clone(), fresh("Length", suffix)]),
+            goal("reverse", args.to_vec()),
+        ])],
+        _ => vec![],
     }
+}
 
-    let number = match &args[0] {
-        Term::Integer(n) => *n,
-        _ => return None, // First argument must be an integer
+// max/3 and min/3: Z is the larger (or smaller) of X and Y. With Z known, a missing X or Y is
+// worked out when only one value fits; when Z equals the known argument any number on its far side
+// would do, which raises an instantiation error.
+fn extreme(args: &[Term], pick: fn(i64, i64) -> i64) -> Result<Vec<Term>, Term> {
+    let values = [integer_argument(&args[0])?, integer_argument(&args[1])?, integer_argument(&args[2])?];
+    let (known, missing, z) = match values {
+        [Some(x), Some(y), _] => return Ok(result_goal(&args[2], pick(x, y))),
+        [Some(x), None, Some(z)] => (x, &args[1], z),
+        [None, Some(y), Some(z)] => (y, &args[0], z),
+        _ => return Err(errors::instantiation_error()),
     };
+    if z == known {
+        Err(errors::instantiation_error())
+    } else if pick(known, z) == z {
+        Ok(result_goal(missing, z))
+    } else {
+        Ok(vec![])
+    }
+}
 
-    let var = match &args[1] {
-        Term::Variable(v) => v.clone(),
-        _ => return None, // Second argument must be a variable
-    };
+pub fn builtin_max(args: &[Term]) -> Result<Vec<Term>, Term> {
+    extreme(args, i64::max)
+}
 
-    let mut subs = Substitution::new();
-    subs.extend(var, Term::Integer(number + 1));
+pub fn builtin_min(args: &[Term]) -> Result<Vec<Term>, Term> {
+    extreme(args, i64::min)
+}
 
-    Some(subs)
+// succ(X, Y): Y is X + 1, for natural numbers, worked out from either side
+pub fn builtin_succ(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let x = not_negative(integer_argument(&args[0])?, &args[0])?;
+    let y = not_negative(integer_argument(&args[1])?, &args[1])?;
+    match (x, y) {
+        (Some(x), _) => Ok(result_goal(&args[1], x + 1)),
+        (None, Some(0)) => Ok(vec![]),
+        (None, Some(y)) => Ok(result_goal(&args[0], y - 1)),
+        (None, None) => Err(errors::instantiation_error()),
+    }
 }
 
-pub fn builtin_sort(args: &[Term]) -> Option<Substitution> {
-    if args.len() != 2 {
-        return None;
+// plus(X, Y, Z): Z is X + Y, with any two of them known
+pub fn builtin_plus(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let values = [integer_argument(&args[0])?, integer_argument(&args[1])?, integer_argument(&args[2])?];
+    match values {
+        [Some(x), Some(y), Some(z)] if x + y == z => Ok(true_goal()),
+        [Some(_), Some(_), Some(_)] => Ok(vec![]),
+        [Some(x), Some(y), None] => Ok(result_goal(&args[2], x + y)),
+        [Some(x), None, Some(z)] => Ok(result_goal(&args[1], z - x)),
+        [None, Some(y), Some(z)] => Ok(result_goal(&args[0], z - y)),
+        _ => Err(errors::instantiation_error()),
     }
+}
 
-    // Ensure first argument is a list
-    let list_term = &args[0];
-    let var = match &args[1] {
-        Term::Variable(v) => v.clone(),
-        _ => return None, // Second argument must be a variable
-    };
+// The items of the list to sort, which has to be proper. The sorted list may be partial.
+fn sort_items(list: &Term, sorted: &Term) -> Result<Vec<Term>, Term> {
+    match list_prefix(sorted).1 {
+        Term::EmptyList | Term::Variable(_) => {}
+        _ => return Err(errors::type_error("list", sorted.clone())),
+    }
+    match list_prefix(list) {
+        (items, Term::EmptyList) => Ok(items),
+        (_, Term::Variable(_)) => Err(errors::instantiation_error()),
+        _ => Err(errors::type_error("list", list.clone())),
+    }
+}
 
-    // Convert the input term into a Vec<Term>
-    let vec = list_term.to_vec()?;
+// The part of an element that sort/4 compares: the element itself for key 0, else that argument
+fn sort_key(item: &Term, key: usize) -> Result<&Term, Term> {
+    match item {
+        _ if key == 0 => Ok(item),
+        Term::Compound(_, args) if args.len() >= key => Ok(&args[key - 1]),
+        Term::List(head, tail) if key <= 2 => Ok(if key == 1 { head } else { tail }),
+        Term::Variable(_) => Err(errors::instantiation_error()),
+        _ => Err(errors::type_error("compound", item.clone())),
+    }
+}
 
-    // Only allow lists of integers for simplicity
-    let mut values: Vec<_> = vec.iter()
-        .filter_map(|t| {
-            if let Term::Integer(i) = t {
-                Some(*i)
-            } else {
-                None
+// sort/2 and msort/2 sort by the standard order of terms, sort/2 also dropping duplicates.
+// keysort/2 sorts Key-Value pairs by key, keeping pairs with equal keys in their order.
+// sort(Key, Order, List, Sorted) sorts on an argument of each element, or the element for key 0;
+// @< and @> drop elements whose keys are equal to an earlier one.
+pub fn builtin_sort(name: &str, args: &[Term]) -> Result<Vec<Term>, Term> {
+    let sorted = &args[args.len() - 1];
+    let mut items = sort_items(&args[args.len() - 2], sorted)?;
+    let (key, order) = match name {
+        "sort" if args.len() == 4 => {
+            let key = integer_argument(&args[0])?.ok_or_else(errors::instantiation_error)?;
+            let key = usize::try_from(key).map_err(|_| errors::domain_error("not_less_than_zero", args[0].clone()))?;
+            let order = match &args[1] {
+                Term::Variable(_) => return Err(errors::instantiation_error()),
+                Term::Constant(order) if ["@<", "@=<", "@>", "@>="].contains(&order.as_str()) => order.as_str(),
+                Term::Constant(_) => return Err(errors::domain_error("order", args[1].clone())),
+                other => return Err(errors::type_error("atom", other.clone())),
+            };
+            (key, order)
+        }
+        "sort" => (0, "@<"),
+        "msort" => (0, "@=<"),
+        _ => {
+            for item in &items {
+                match item {
+                    Term::Compound(name, args) if name == "-" && args.len() == 2 => {}
+                    Term::Variable(_) => return Err(errors::instantiation_error()),
+                    other => return Err(errors::type_error("pair", other.clone())),
+                }
             }
-        })
-        .collect();
-
-    if values.len() != vec.len() {
-        return None; // Input list must only contain integers
+            (1, "@=<")
+        }
+    };
+    for item in &items {
+        sort_key(item, key)?;
     }
+    let by_key = |a: &Term, b: &Term| sort_key(a, key).unwrap().standard_order(sort_key(b, key).unwrap());
+    match order {
+        "@<" | "@=<" => items.sort_by(by_key),
+        _ => items.sort_by(|a, b| by_key(b, a)),
+    }
+    if order == "@<" || order == "@>" {
+        items.dedup_by(|a, b| by_key(a, b).is_eq());
+    }
+    Ok(vec![equals(sorted.clone(), Term::list_from_vec(items))])
+}
 
-    values.sort();
-
-    let sorted_terms: Vec<Term> = values.into_iter().map(Term::Integer).collect();
-    let sorted_term = Term::list_from_vec(sorted_terms);
-
-    let mut subs = Substitution::new();
-    subs.extend(var, sorted_term);
-
-    Some(subs)
+// predsort(P, List, Sorted): a merge sort where call(P, Order, A, B) gives <, > or =, and
+// elements found = to another are dropped. The halves are merged by '$predmerge'/4.
+pub fn builtin_predsort(args: &[Term], suffix: usize) -> Result<Vec<Term>, Term> {
+    let mut items = sort_items(&args[1], &args[2])?;
+    if items.len() < 2 {
+        return Ok(vec![equals(args[2].clone(), Term::list_from_vec(items))]);
+    }
+    let second = items.split_off(items.len() / 2);
+    let (left, right) = (fresh("Left", suffix), fresh("Right", suffix));
+    Ok(vec![conjunction(vec![
+        goal("predsort", vec![args[0].clone(), Term::list_from_vec(items), left.clone()]),
+        goal("predsort", vec![args[0].clone(), Term::list_from_vec(second), right.clone()]),
+        goal("$predmerge", vec![args[0].clone(), left, right, args[2].clone()]),
+    ])])
+}
 
+// '$predmerge'(P, Left, Right, Merged) for predsort/3
+pub fn builtin_predmerge(args: &[Term], suffix: usize) -> Vec<Term> {
+    let (pred, merged) = (&args[0], &args[3]);
+    let (Term::List(first, rest_left), Term::List(second, rest_right)) = (&args[1], &args[2]) else {
+        let rest = if args[1] == Term::EmptyList { &args[2] } else { &args[1] };
+        return vec![equals(merged.clone(), rest.clone())];
+    };
+    let (order, rest) = (fresh("Order", suffix), fresh("Merged", suffix));
+    let branch = |symbol: &str, head: &Term, left: &Term, right: &Term| {
+        goal("->", vec![
+            equals(order.clone(), Term::Constant(symbol.to_string())),
+            conjunction(vec![
+                equals(merged.clone(), cons(head.clone(), rest.clone())),
+                goal("$predmerge", vec![pred.clone(), left.clone(), right.clone(), rest.clone()]),
+            ]),
+        ])
+    };
+    vec![conjunction(vec![
+        goal("call", vec![pred.clone(), order.clone(), (**first).clone(), (**second).clone()]),
+        goal(";", vec![
+            branch("<", first, rest_left, &args[2]),
+            goal(";", vec![branch(">", second, &args[1], rest_right), branch("=", first, rest_left, rest_right)]),
+        ]),
+    ])]
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::terms::Term;
+    use crate::solver::{answers, load_program};
 
     #[test]
     fn test_builtin_append_basic() {
@@ -301,9 +395,11 @@ mod tests {
         let list2 = Term::list_from_vec(vec![Term::Integer(3)]);
         let result = Term::list_from_vec(vec![Term::Integer(1), Term::Integer(2), Term::Integer(3)]);
 
-        let args = vec![list1, list2, result];
-        let subs = builtin_append(&args);
-        assert!(subs.is_some());
+        // A known first list leaves only the recursive clause to try
+        let goals = builtin_append(&[list1, list2, result], 0);
+        assert_eq!(goals.len(), 1);
+        let goals = builtin_append(&[Term::Variable("X".into()), Term::Variable("Y".into()), Term::EmptyList], 0);
+        assert_eq!(goals.len(), 2);
     }
 
     #[test]
@@ -314,47 +410,46 @@ mod tests {
             Term::Integer(3),
         ]);
         let args = vec![Term::Integer(2), list];
-        let subs = builtin_member(&args);
-        assert!(subs.is_some());
+        let goals = builtin_member(&args, 0);
+        assert_eq!(goals[0], equals(Term::Integer(2), Term::Integer(1)));
+        assert_eq!(goals.len(), 2);
     }
 
     #[test]
     fn test_builtin_member_not_found() {
-        let list = Term::list_from_vec(vec![Term::Integer(1), Term::Integer(3)]);
-        let args = vec![Term::Integer(2), list];
-        let subs = builtin_member(&args);
-        assert!(subs.is_none());
+        let args = vec![Term::Integer(2), Term::EmptyList];
+        assert!(builtin_member(&args, 0).is_empty());
     }
 
     #[test]
     fn test_builtin_between_valid_range() {
-        let args = vec![
-            Term::Integer(1),
-            Term::Integer(3),
-            Term::Variable("X".into()),
-        ];
-        let subs = builtin_between(&args);
-        assert!(subs.is_some());
-        assert_eq!(subs.unwrap().get("X"), Some(&Term::Constant("1; 2; 3".into())));
+        let x = Term::Variable("X".into());
+        let goals = builtin_between(&[Term::Integer(1), Term::Integer(3), x.clone()]).unwrap();
+        assert_eq!(goals, vec![
+            equals(x.clone(), Term::Integer(1)),
+            goal("between", vec![Term::Integer(2), Term::Integer(3), x.clone()]),
+        ]);
+        assert!(builtin_between(&[Term::Integer(4), Term::Integer(3), x.clone()]).unwrap().is_empty());
+        assert_eq!(builtin_between(&[x.clone(), Term::Integer(3), x]), Err(errors::instantiation_error()));
     }
 
     #[test]
     fn test_builtin_length_correct() {
         let list = Term::list_from_vec(vec![Term::Integer(1), Term::Integer(2), Term::Integer(3)]);
-        let args = vec![list, Term::Variable("N".into())];
-        let subs = builtin_length(&args);
-        assert!(subs.is_some());
-        assert_eq!(subs.unwrap().get("N"), Some(&Term::Integer(3)));
+        let n = Term::Variable("N".into());
+        assert_eq!(builtin_length(&[list, n.clone()], 0).unwrap(), vec![equals(n.clone(), Term::Integer(3))]);
+        let goals = builtin_length(&[Term::Variable("L".into()), Term::Integer(2)], 0).unwrap();
+        assert_eq!(goals[0].to_string(), "L=[_Item0_0, _Item1_0]");
+        assert_eq!(builtin_length(&[Term::EmptyList, Term::Integer(-1)], 0), Err(errors::domain_error("not_less_than_zero", Term::Integer(-1))));
     }
 
     #[test]
     fn test_builtin_reverse() {
         let list = Term::list_from_vec(vec![Term::Integer(1), Term::Integer(2)]);
         let expected = Term::list_from_vec(vec![Term::Integer(2), Term::Integer(1)]);
-        let args = vec![list, Term::Variable("X".into())];
-        let subs = builtin_reverse(&args);
-        assert!(subs.is_some());
-        assert_eq!(subs.unwrap().get("X"), Some(&expected));
+        let x = Term::Variable("X".into());
+        assert_eq!(builtin_reverse(&[list.clone(), x.clone()], 0), vec![equals(x.clone(), expected.clone())]);
+        assert_eq!(builtin_reverse(&[x.clone(), list], 0), vec![equals(x, expected)]);
     }
 
     #[test]
@@ -364,9 +459,13 @@ mod tests {
             Term::Integer(5),
             Term::Variable("M".into()),
         ];
-        let subs = builtin_max(&args);
-        assert!(subs.is_some());
-        assert_eq!(subs.unwrap().get("M"), Some(&Term::Integer(5)));
+        assert_eq!(builtin_max(&args).unwrap(), vec![equals(args[2].clone(), Term::Integer(5))]);
+        let x = Term::Variable("X".into());
+        let solve = |args: [i64; 2]| [x.clone(), Term::Integer(args[0]), Term::Integer(args[1])];
+        assert_eq!(builtin_max(&solve([3, 5])).unwrap(), vec![equals(x.clone(), Term::Integer(5))]);
+        assert_eq!(builtin_max(&[Term::Integer(2), x.clone(), Term::Integer(5)]).unwrap(), vec![equals(x.clone(), Term::Integer(5))]);
+        assert!(builtin_max(&solve([5, 3])).unwrap().is_empty());
+        assert_eq!(builtin_max(&solve([3, 3])), Err(errors::instantiation_error()));
     }
 
     #[test]
@@ -376,37 +475,69 @@ mod tests {
             Term::Integer(5),
             Term::Variable("M".into()),
         ];
-        let subs = builtin_min(&args);
-        assert!(subs.is_some());
-        assert_eq!(subs.unwrap().get("M"), Some(&Term::Integer(3)));
+        assert_eq!(builtin_min(&args).unwrap(), vec![equals(args[2].clone(), Term::Integer(3))]);
+        let x = Term::Variable("X".into());
+        assert_eq!(builtin_min(&[x.clone(), Term::Integer(5), Term::Integer(3)]).unwrap(), vec![equals(x.clone(), Term::Integer(3))]);
+        assert!(builtin_min(&[x.clone(), Term::Integer(3), Term::Integer(5)]).unwrap().is_empty());
     }
 
     #[test]
     fn test_builtin_succ() {
-        let args = vec![
-            Term::Integer(4),
-            Term::Variable("X".into()),
-        ];
-        let subs = builtin_succ(&args);
-        assert!(subs.is_some());
-        assert_eq!(subs.unwrap().get("X"), Some(&Term::Integer(5)));
+        let x = Term::Variable("X".into());
+        assert_eq!(builtin_succ(&[Term::Integer(4), x.clone()]).unwrap(), vec![equals(x.clone(), Term::Integer(5))]);
+        assert_eq!(builtin_succ(&[x.clone(), Term::Integer(5)]).unwrap(), vec![equals(x.clone(), Term::Integer(4))]);
+        assert!(builtin_succ(&[x.clone(), Term::Integer(0)]).unwrap().is_empty());
+        assert_eq!(builtin_succ(&[x.clone(), Term::Integer(-1)]), Err(errors::type_error("not_less_than_zero", Term::Integer(-1))));
+        assert_eq!(builtin_succ(&[x.clone(), x]), Err(errors::instantiation_error()));
     }
 
     #[test]
     fn test_builtin_sort() {
-        let list = Term::list_from_vec(vec![
-            Term::Integer(3),
-            Term::Integer(1),
-            Term::Integer(2),
-        ]);
-        let expected = Term::list_from_vec(vec![
-            Term::Integer(1),
-            Term::Integer(2),
-            Term::Integer(3),
-        ]);
-        let args = vec![list, Term::Variable("Sorted".into())];
-        let subs = builtin_sort(&args);
-        assert!(subs.is_some());
-        assert_eq!(subs.unwrap().get("Sorted"), Some(&expected));
+        let sorted = Term::Variable("Sorted".into());
+        let list = Term::list_from_vec(vec![Term::Integer(3), Term::Constant("a".into()), Term::Integer(1), Term::Integer(3)]);
+        let goals = builtin_sort("sort", &[list.clone(), sorted.clone()]).unwrap();
+        let expected = Term::list_from_vec(vec![Term::Integer(1), Term::Integer(3), Term::Constant("a".into())]);
+        assert_eq!(goals, vec![equals(sorted.clone(), expected)]);
+
+        let goals = builtin_sort("msort", &[list, sorted.clone()]).unwrap();
+        let expected = Term::list_from_vec(vec![Term::Integer(1), Term::Integer(3), Term::Integer(3), Term::Constant("a".into())]);
+        assert_eq!(goals, vec![equals(sorted.clone(), expected)]);
+
+        let partial = cons(Term::Integer(1), Term::Variable("T".into()));
+        assert_eq!(builtin_sort("sort", &[partial, sorted.clone()]), Err(errors::instantiation_error()));
+        let pairs = Term::list_from_vec(vec![Term::Constant("a".into())]);
+        assert_eq!(builtin_sort("keysort", &[pairs, sorted]), Err(errors::type_error("pair", Term::Constant("a".into()))));
+    }
+
+    #[test]
+    fn test_sorting_by_standard_order() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "sort([c, 2, f(a), b, 1, b, g(a, b), [z], e(x, y)], X)."), "[1, 2, b, c, f(a), [z], e(x, y), g(a, b)]");
+        assert_eq!(answers(&mut db, "msort([b, a, b], X)."), "[a, b, b]");
+        assert_eq!(answers(&mut db, "keysort([b-1, a-2, b-0, a-1], X)."), "[a-2, a-1, b-1, b-0]");
+        assert_eq!(answers(&mut db, "sort(0, @>=, [1, 3, 2, 3], X)."), "[3, 3, 2, 1]");
+        assert_eq!(answers(&mut db, "sort(1, @<, [f(2, a), f(1, b), f(2, c)], X)."), "[f(1, b), f(2, a)]");
+        assert_eq!(answers(&mut db, "sort(2, @>, [f(2, a), f(1, b)], X)."), "[f(1, b), f(2, a)]");
+        assert_eq!(answers(&mut db, "catch(sort(a, X), error(E, _), X = E)."), "type_error(list, a)");
+        assert_eq!(answers(&mut db, "catch(sort(0, <, [], X), error(E, _), X = E)."), "domain_error(order, <)");
+    }
+
+    #[test]
+    fn test_predsort_with_compare() {
+        let mut db = load_program("by_value(O, _-A, _-B) :- compare(O, A, B).\nreversed(O, A, B) :- compare(O, B, A).");
+        assert_eq!(answers(&mut db, "predsort(by_value, [x-3, y-1, z-3, w-2], X)."), "[y-1, w-2, x-3]");
+        assert_eq!(answers(&mut db, "predsort(reversed, [b, a, c, a], X)."), "[c, b, a]");
+    }
+
+    #[test]
+    fn test_comparing_terms() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "compare(X, 1, a)."), "<");
+        assert_eq!(answers(&mut db, "compare(X, f(b), f(a))."), ">");
+        assert_eq!(answers(&mut db, "(compare(<, a, a) -> X = yes ; X = no)."), "no");
+        assert_eq!(answers(&mut db, "catch(compare(less, a, b), error(E, _), X = E)."), "domain_error(order, less)");
+        assert_eq!(answers(&mut db, "Y = a, (a == Y -> X = yes ; X = no)."), "yes");
+        assert_eq!(answers(&mut db, "(A \\== B, A @< B, f(A) @=< f(A), b @> a, 2 @>= 1 -> X = yes ; X = no)."), "yes");
+        assert_eq!(answers(&mut db, "(a == _ -> X = yes ; X = no)."), "no");
     }
 }
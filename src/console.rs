@@ -0,0 +1,90 @@
+// What the program wrote to user_output and user_error, one entry per query, for the console pane
+pub struct ConsoleEntry {
+    pub query: String, // The query, or what else ran, that wrote the text
+    pub text: String,
+}
+
+#[derive(Default)]
+pub struct Console {
+    entries: Vec<ConsoleEntry>,
+}
+
+impl Console {
+    // Keeps the output of one query, with colour codes taken out since the pane can't show them
+    pub fn record(&mut self, query: &str, output: String) {
+        if !output.is_empty() {
+            self.entries.push(ConsoleEntry { query: query.to_string(), text: strip_ansi(&output) });
+        }
+    }
+
+    pub fn entries(&self) -> &[ConsoleEntry] {
+        &self.entries
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // Everything in the console, for the clipboard
+    pub fn text(&self) -> String {
+        self.entries.iter().map(|entry| format!("?- {}\n{}", entry.query, entry.text)).collect::<Vec<_>>().join("\n")
+    }
+}
+
+// Removes ANSI escape sequences: CSI sequences like `ESC [ 1 ; 31 m`, OSC sequences ended by BEL
+// or `ESC \`, character set selections like `ESC ( B`, and two-character escapes
+pub fn strip_ansi(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '\u{1b}' {
+            stripped.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                for ch in chars.by_ref() {
+                    if ('\u{40}'..='\u{7e}').contains(&ch) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(ch) = chars.next() {
+                    if ch == '\u{7}' || (ch == '\u{1b}' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            Some('(' | ')') => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(strip_ansi("\u{1b}[1;31mred\u{1b}[0m plain"), "red plain");
+        assert_eq!(strip_ansi("\u{1b}]0;title\u{7}text\u{1b}(B"), "text");
+        assert_eq!(strip_ansi("no codes\n"), "no codes\n");
+    }
+
+    #[test]
+    fn test_console_keeps_output_per_query() {
+        let mut console = Console::default();
+        console.record("write(a).", "a".to_string());
+        console.record("true.", String::new());
+        console.record("nl.", "\n".to_string());
+        assert_eq!(console.entries().len(), 2);
+        assert_eq!(console.text(), "?- write(a).\na\n?- nl.\n\n");
+        console.clear();
+        assert!(console.entries().is_empty());
+    }
+}
@@ -0,0 +1,390 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::database::{Database, LoadContext, PredicateKey};
+use crate::dcg;
+use crate::errors;
+use crate::parser::parser::read_program;
+use crate::solver::{Solver, LIBRARY_PREDICATES};
+use crate::terms::{Clause, Expression, Term};
+use crate::unification::Substitution;
+
+// Goals from `:- initialization(G)`, run in their module once the file has loaded, with where
+// the directive was
+type Initialization = Vec<(String, Term, String)>;
+
+// Loads program text as if it were consulted from `file`. Clauses are added in order and each
+// directive runs as a goal once the clauses before it are loaded. Syntax errors, failed directives
+// and exceptions are reported in `db.messages`. Returns the modules the text defined.
+pub fn consult_text(db: &mut Database, text: &str, file: Option<&str>) -> Vec<String> {
+    let mut context = LoadContext::new(file);
+    let mut initialization = vec![];
+    db.loading.extend(file.map(PathBuf::from));
+    load_text(db, &mut context, text, &mut initialization);
+    let defined = db.finish_load(context);
+    for (module, goal, location) in initialization {
+        run_directive(db, &module, &goal, &location);
+    }
+    if file.is_some() {
+        db.loading.pop();
+    }
+    defined
+}
+
+fn load_text(db: &mut Database, context: &mut LoadContext, text: &str, initialization: &mut Initialization) {
+    let (clauses, errors) = read_program(text, &mut db.operators);
+    for error in errors {
+        db.messages.push(format!("Syntax error in {}, {}", context.source.as_deref().unwrap_or("rules"), error));
+    }
+    for read in clauses {
+        context.line = read.line;
+        let clause = Clause::from_tree_clause(dcg::terminal_strings_to_codes(read.clause));
+        // Variables used once are often typos; a leading underscore marks the ones that are meant
+        let singletons: Vec<String> = read.singletons.into_iter().filter(|name| !name.starts_with('_')).collect();
+        if !singletons.is_empty() && !matches!(clause, Clause::Directive(_)) {
+            db.messages.push(format!("Warning: {}: singleton variables [{}]", context.location(), singletons.join(", ")));
+        }
+        for term in expand_term(db, context, clause.to_term()) {
+            load_term(db, context, term, initialization);
+        }
+    }
+}
+
+// Passes a clause to the user's term_expansion/2, which may replace it with one term or a list
+fn expand_term(db: &mut Database, context: &LoadContext, term: Term) -> Vec<Term> {
+    match call_hook(db, context, "term_expansion", &term) {
+        Some((expansion, _)) => expansion.to_vec().unwrap_or_else(|| vec![expansion]),
+        None => vec![term],
+    }
+}
+
+fn load_term(db: &mut Database, context: &mut LoadContext, term: Term, initialization: &mut Initialization) {
+    let loaded = match term.clone() {
+        Term::Compound(name, mut args) if name == ":-" && args.len() == 1 => {
+            let directive = expand_goals(db, context, &args.pop().unwrap(), &[]);
+            load_directive(db, context, directive, initialization);
+            Ok(())
+        }
+        Term::Compound(name, args) if name == "-->" && args.len() == 2 => {
+            dcg::translate_rule(&args[0], &args[1]).map(|rule| load_term(db, context, rule, initialization))
+        }
+        Term::Compound(name, mut args) if name == ":-" && args.len() == 2 => {
+            let body = expand_goals(db, context, &args.pop().unwrap(), &[]);
+            args.push(body);
+            Clause::from_term(Term::Compound(name, args)).map(|clause| db.load_clause(context, clause))
+        }
+        term => Clause::from_term(term).map(|clause| db.load_clause(context, clause)),
+    };
+    if let Err(ball) = loaded {
+        db.messages.push(format!("Warning: {}: clause {} raised {}", context.location(), term, ball));
+    }
+}
+
+// Rewrites the goals of a body with goal_expansion/2, expanding each one again until it no longer
+// changes. Goals inside control constructs are expanded too, but a goal is never expanded again
+// inside its own expansion.
+fn expand_goals(db: &mut Database, context: &LoadContext, body: &Term, expanding: &[Term]) -> Term {
+    match body {
+        Term::Compound(name, args) if is_control(name, args.len()) => {
+            let args = args.iter().map(|arg| expand_goals(db, context, arg, expanding)).collect();
+            Term::Compound(name.clone(), args)
+        }
+        Term::Variable(_) => body.clone(),
+        goal if expanding.contains(goal) => goal.clone(),
+        goal => match call_hook(db, context, "goal_expansion", goal) {
+            Some((expansion, bindings)) if &expansion != goal || !bindings.is_empty() => {
+                let expanding: Vec<Term> = expanding.iter().cloned().chain([goal.clone()]).collect();
+                let expansion = expand_goals(db, context, &expansion, &expanding);
+                bindings.into_iter().rev().fold(expansion, |body, binding| Term::Compound(",".to_string(), vec![binding, body]))
+            }
+            _ => goal.clone(),
+        },
+    }
+}
+
+fn is_control(name: &str, arity: usize) -> bool {
+    matches!((name, arity), (",", 2) | (";", 2) | ("->", 2) | ("*->", 2) | ("\\+", 1))
+}
+
+// Calls a user-defined hook such as term_expansion/2 with `term`, giving what its second argument
+// was bound to. A hook that is not defined, fails or raises an exception leaves the term as it is.
+// The hook's bindings for the term's own variables are kept: a variable bound to another variable
+// keeps its name in the expansion, and one bound to a value comes back as a `Var = Value` goal.
+fn call_hook(db: &mut Database, context: &LoadContext, hook: &str, term: &Term) -> Option<(Term, Vec<Term>)> {
+    db.resolve(&context.module, &(hook.to_string(), 2))?;
+    // The answer only keeps query variables that don't start with `_`, so each variable of the term
+    // is passed under a name it does keep
+    let vars = term.variables();
+    let mut names = Substitution::new();
+    let mut links = Substitution::new();
+    for (index, var) in vars.iter().enumerate() {
+        names.extend(var.clone(), Term::Variable(format!("$Var{}", index)));
+        links.extend(format!("$Var{}", index), Term::Variable(var.clone()));
+    }
+    let goal = Term::Compound(":".to_string(), vec![
+        Term::Constant(context.module.clone()),
+        Term::Compound(hook.to_string(), vec![names.apply(term), Term::Variable("$Expansion".to_string())]),
+    ]);
+    let answer = match Solver::new(&Expression::Term(goal), db).next_solution() {
+        Ok(answer) => answer?,
+        Err(ball) => {
+            db.messages.push(format!("Warning: {}: {} raised {}", context.location(), hook, ball));
+            return None;
+        }
+    };
+    let mut bindings = vec![];
+    for (index, var) in vars.iter().enumerate() {
+        match answer.get(&format!("$Var{}", index)) {
+            Some(Term::Variable(other)) if links.get(other).is_none() => links.extend(other.clone(), Term::Variable(var.clone())),
+            Some(value) => bindings.push(Term::Compound("=".to_string(), vec![Term::Variable(var.clone()), value.clone()])),
+            None => {}
+        }
+    }
+    let expansion = links.apply(answer.get("$Expansion")?);
+    Some((expansion, bindings.iter().map(|binding| links.apply(binding)).collect()))
+}
+
+fn load_directive(db: &mut Database, context: &mut LoadContext, directive: Term, initialization: &mut Initialization) {
+    let result = match &directive {
+        Term::Compound(name, args) if name == "initialization" && args.len() == 1 => {
+            initialization.push((context.module.clone(), args[0].clone(), context.location()));
+            Ok(())
+        }
+        Term::Compound(name, args) if name == "initialization" && args.len() == 2 => match &args[1] {
+            Term::Constant(when) if when == "now" => {
+                run_directive(db, &context.module.clone(), &args[0], &context.location());
+                Ok(())
+            }
+            // There is no program to halt in the GUI, so `main` runs the goal after loading too
+            Term::Constant(when) if when == "main" || when == "after_load" => {
+                initialization.push((context.module.clone(), args[0].clone(), context.location()));
+                Ok(())
+            }
+            Term::Variable(_) => Err(errors::instantiation_error()),
+            other => Err(errors::domain_error("initialization_type", other.clone())),
+        },
+        // The text of an included file is loaded as if it were written in place of the directive
+        Term::Compound(name, args) if name == "include" && args.len() == 1 => {
+            resolve(db, &args[0]).and_then(|path| {
+                let text = fs::read_to_string(&path).map_err(|_| errors::existence_error("source_sink", args[0].clone()))?;
+                let outer = context.source.replace(path.to_string_lossy().into_owned());
+                db.loading.push(path);
+                load_text(db, context, &text, initialization);
+                db.loading.pop();
+                context.source = outer;
+                Ok(())
+            })
+        }
+        directive => match db.load_declaration(context, directive) {
+            Some(result) => result,
+            None => {
+                run_directive(db, &context.module.clone(), directive, &context.location());
+                Ok(())
+            }
+        },
+    };
+    if let Err(ball) = result {
+        db.messages.push(format!("Warning: {}: directive {} raised {}", context.location(), directive, ball));
+    }
+}
+
+// Runs a directive goal once, reporting failure or an uncaught exception
+fn run_directive(db: &mut Database, module: &str, goal: &Term, location: &str) {
+    let qualified = Term::Compound(":".to_string(), vec![Term::Constant(module.to_string()), goal.clone()]);
+    let message = match Solver::new(&Expression::Term(qualified), db).next_solution() {
+        Ok(Some(_)) => return,
+        Ok(None) => format!("Warning: {}: directive {} failed", location, goal),
+        Err(ball) => format!("Warning: {}: directive {} raised {}", location, goal, ball),
+    };
+    db.messages.push(message);
+}
+
+// Finds the file a consult/1 style argument names. `foo` stands for `foo.pl` if that exists, and
+// relative paths are taken from the directory of the file being loaded, if any.
+pub fn resolve(db: &Database, spec: &Term) -> Result<PathBuf, Term> {
+    let name = match spec {
+        Term::Constant(name) => name,
+        Term::Variable(_) => return Err(errors::instantiation_error()),
+        other => return Err(errors::existence_error("source_sink", other.clone())),
+    };
+    let base = db.loading.last().and_then(|file| file.parent()).unwrap_or(Path::new(""));
+    [format!("{}.pl", name), name.clone()]
+        .iter()
+        .map(|candidate| base.join(candidate))
+        .find(|path| path.is_file())
+        .map(|path| fs::canonicalize(&path).unwrap_or(path))
+        .ok_or_else(|| errors::existence_error("source_sink", spec.clone()))
+}
+
+// consult/1: loads a file, first removing the predicates an earlier load of it defined.
+// Returns the modules the file defined.
+pub fn consult(db: &mut Database, spec: &Term) -> Result<Vec<String>, Term> {
+    let path = resolve(db, spec)?;
+    let text = fs::read_to_string(&path).map_err(|_| errors::existence_error("source_sink", spec.clone()))?;
+    let file = path.to_string_lossy().into_owned();
+    db.unload_file(&file);
+    let defined = consult_text(db, &text, Some(&file));
+    if !db.loaded_files.contains(&file) {
+        db.loaded_files.push(file);
+    }
+    Ok(defined)
+}
+
+// ensure_loaded/1: consults a file unless it is already loaded
+pub fn ensure_loaded(db: &mut Database, spec: &Term) -> Result<(), Term> {
+    if let Some(library) = library(spec) {
+        return library;
+    }
+    let path = resolve(db, spec)?;
+    if !db.loaded_files.contains(&path.to_string_lossy().into_owned()) {
+        consult(db, spec)?;
+    }
+    Ok(())
+}
+
+// use_module/1,2: loads a module file unless it is already loaded, then imports it into `context`
+pub fn use_module(db: &mut Database, context: &str, spec: &Term, only: Option<&[PredicateKey]>) -> Result<(), Term> {
+    if let Some(library) = library(spec) {
+        return library;
+    }
+    let Term::Constant(name) = spec else {
+        return Err(match spec {
+            Term::Variable(_) => errors::instantiation_error(),
+            other => errors::existence_error("source_sink", other.clone()),
+        });
+    };
+    // Modules defined earlier, e.g. in the same editor text, can be imported by name
+    let path = resolve(db, spec).ok();
+    let file = path.as_ref().map(|path| path.to_string_lossy().into_owned());
+    let module = match db.loaded_module(name, file.as_deref()) {
+        Some(module) => module,
+        None => match path {
+            Some(_) => consult(db, spec)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| errors::domain_error("module_file", spec.clone()))?,
+            None => return Err(errors::existence_error("source_sink", spec.clone())),
+        },
+    };
+    db.import(context, &module, only)
+}
+
+// `library(Name)` names a library built into the interpreter. Its predicates are always available,
+// so loading it only checks that it exists.
+fn library(spec: &Term) -> Option<Result<(), Term>> {
+    let Term::Compound(name, args) = spec else { return None };
+    if name != "library" || args.len() != 1 {
+        return None;
+    }
+    Some(match &args[0] {
+        Term::Constant(library) if LIBRARY_PREDICATES.iter().any(|(known, _, _)| known == library) => Ok(()),
+        Term::Variable(_) => Err(errors::instantiation_error()),
+        _ => Err(errors::existence_error("source_sink", spec.clone())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::parse_query_with_operators;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("consult_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn holds(db: &mut Database, query: &str) -> bool {
+        let query = Term::from_tree_term(parse_query_with_operators(query, &db.operators).unwrap());
+        Solver::new(&Expression::Term(query), db).next_solution().unwrap().is_some()
+    }
+
+    #[test]
+    fn test_directives_run_in_order() {
+        let mut db = Database::new(vec![]);
+        consult_text(&mut db, "
+            :- initialization(assertz(log(init))).
+            :- dynamic log/1.
+            a(1).
+            :- a(X), assertz(log(X)).
+            :- fail.
+        ", None);
+        assert!(holds(&mut db, "findall(X, log(X), [1, init])."));
+        assert_eq!(db.messages, vec!["Warning: rules:6: directive fail failed".to_string()]);
+    }
+
+    #[test]
+    fn test_load_warnings_carry_file_and_line() {
+        let mut db = Database::new(vec![]);
+        consult_text(&mut db, ":- discontiguous b/1.
+head([H|Y], H).
+a(1).
+b(1).
+a(2).
+b(2).
+write(_).
+tail([_|_T], _T).
+", Some("lists.pl"));
+        assert_eq!(db.messages, vec![
+            "Warning: lists.pl:2: singleton variables [Y]".to_string(),
+            "Warning: lists.pl:5: clauses of a/1 are not together in the source".to_string(),
+            "Warning: lists.pl:7: write/1 is built in, so its clauses are never called".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_term_and_goal_expansion() {
+        let mut db = Database::new(vec![]);
+        consult_text(&mut db, "
+            edge_facts([], []).
+            edge_facts([X-Y | Pairs], [edge(X, Y) | Facts]) :- edge_facts(Pairs, Facts).
+            term_expansion(edges(Pairs), Facts) :- edge_facts(Pairs, Facts).
+            term_expansion(drop_me, []).
+            goal_expansion(twice(G), (G, G)).
+            goal_expansion(noisy, quiet).
+            goal_expansion(quiet, true).
+            edges([a-b, b-c]).
+            drop_me.
+            path(X, Y) :- edge(X, Z), twice(\\+ edge(Z, X)), noisy, edge(Z, Y).
+        ", None);
+        assert!(db.messages.is_empty(), "{:?}", db.messages);
+        assert!(holds(&mut db, "edge(a, b), edge(b, c), path(a, c), \\+ current_predicate(drop_me/0)."));
+        assert!(holds(&mut db, "clause(path(_, _), (_, (\\+ edge(_, _), \\+ edge(_, _)), true, _))."));
+    }
+
+    #[test]
+    fn test_goal_expansion_keeps_the_clause_variables() {
+        let mut db = Database::new(vec![]);
+        consult_text(&mut db, "
+            goal_expansion(sq(X, Y), Y is X * X).
+            goal_expansion(same(X, Y), true) :- X = Y.
+            goal_expansion(zero(X), true) :- X = 0.
+            calc(Y) :- sq(4, Y).
+            pair(X, Y) :- same(X, Y).
+            nothing(X) :- zero(X).
+        ", None);
+        assert!(db.messages.is_empty(), "{:?}", db.messages);
+        assert!(holds(&mut db, "calc(16), pair(a, A), A == a, nothing(0), \\+ nothing(1)."));
+        assert!(holds(&mut db, "clause(calc(A), B), B = (C is 4 * 4), A == C."));
+    }
+
+    #[test]
+    fn test_consult_relative_include_and_reload() {
+        let dir = temp_dir("reload");
+        fs::write(dir.join("main.pl"), ":- include(part).\n:- ensure_loaded(lib).\nmain(1).\n").unwrap();
+        fs::write(dir.join("part.pl"), "part(1).\n").unwrap();
+        fs::write(dir.join("lib.pl"), "lib(1).\n").unwrap();
+        let main = Term::Constant(dir.join("main").to_string_lossy().into_owned());
+
+        let mut db = Database::new(vec![]);
+        consult_text(&mut db, "other(1).", None);
+        consult(&mut db, &main).unwrap();
+        assert!(holds(&mut db, "main(1), part(1), lib(1), other(1)."));
+
+        // Consulting again replaces only what main.pl defines
+        fs::write(dir.join("main.pl"), "main(2).\n").unwrap();
+        consult(&mut db, &main).unwrap();
+        assert!(holds(&mut db, "main(2), \\+ main(1), catch(part(_), error(existence_error(procedure, part/1), _), true), lib(1), other(1)."));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
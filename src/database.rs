@@ -1,12 +1,458 @@
-use crate::terms::Clause;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::errors;
+use crate::parser::operators::Operators;
+use crate::solver::is_builtin;
+use crate::streams::Streams;
+use crate::globals::Globals;
+use crate::flags::Flags;
+use crate::terms::{Clause, Term};
+
+pub type PredicateKey = (String, usize);
+
+// Module names are shared by every goal frame and choice point that runs in the module
+pub type ModuleName = Rc<str>;
+
+#[derive(Debug, Default)]
+pub struct Predicate {
+    // Shared with running calls; modifying a predicate copies the list if a call still holds it
+    pub clauses: Rc<Vec<Rc<Clause>>>,
+    pub dynamic: bool,
+    pub discontiguous: bool, // Declared with `:- discontiguous`, so its clauses may be spread out
+    pub file: Option<String>, // Source file the predicate was loaded from
+}
+
+// A predicate table of its own, so two modules can both define e.g. helper/2
+#[derive(Debug, Default)]
+pub struct Module {
+    predicates: HashMap<PredicateKey, Predicate>,
+    order: Vec<PredicateKey>, // Predicates in the order they were first defined
+    pub exports: Vec<PredicateKey>,
+    imports: HashMap<PredicateKey, ModuleName>,
+    // Argument specifiers from `:- meta_predicate`, e.g. [0, ?] for once/1-like predicates
+    meta_predicates: HashMap<PredicateKey, Vec<Term>>,
+    pub file: Option<String>,
+}
+
+impl Module {
+    fn predicate_mut(&mut self, key: PredicateKey) -> &mut Predicate {
+        if !self.predicates.contains_key(&key) {
+            self.order.push(key.clone());
+        }
+        self.predicates.entry(key).or_default()
+    }
+
+    // Adds a clause at the end of its predicate, or at the front if `first` is set
+    pub fn add_clause(&mut self, clause: Clause, first: bool) {
+        let Some(key) = clause.head().and_then(Term::predicate_key) else { return };
+        let clauses = Rc::make_mut(&mut self.predicate_mut(key).clauses);
+        if first {
+            clauses.insert(0, Rc::new(clause));
+        } else {
+            clauses.push(Rc::new(clause));
+        }
+    }
+
+    pub fn clauses(&self, key: &PredicateKey) -> Option<Rc<Vec<Rc<Clause>>>> {
+        self.predicates.get(key).map(|pred| pred.clauses.clone())
+    }
+
+    pub fn is_defined(&self, key: &PredicateKey) -> bool {
+        self.predicates.contains_key(key)
+    }
+
+    pub fn is_dynamic(&self, key: &PredicateKey) -> bool {
+        self.predicates.get(key).is_some_and(|pred| pred.dynamic)
+    }
+
+    pub fn declare_dynamic(&mut self, key: PredicateKey) {
+        self.predicate_mut(key).dynamic = true;
+    }
+
+    // Declares every `Name/Arity` in a term such as `(foo/1, bar/2)` or `[foo/1, bar/2]`
+    pub fn declare_dynamic_list(&mut self, spec: &Term) -> Result<(), Term> {
+        for key in parse_indicator_list(spec)? {
+            self.declare_dynamic(key);
+        }
+        Ok(())
+    }
+
+    // Records `:- meta_predicate maplist(1, ?)` style declarations
+    pub fn declare_meta_predicate(&mut self, spec: &Term) -> Result<(), Term> {
+        match spec {
+            Term::Compound(name, args) if name == "," && args.len() == 2 => {
+                self.declare_meta_predicate(&args[0])?;
+                self.declare_meta_predicate(&args[1])
+            }
+            Term::Compound(name, args) => {
+                self.meta_predicates.insert((name.clone(), args.len()), args.clone());
+                Ok(())
+            }
+            Term::Variable(_) => Err(errors::instantiation_error()),
+            other => Err(errors::type_error("compound", other.clone())),
+        }
+    }
+
+    pub fn meta_predicate(&self, key: &PredicateKey) -> Option<&Vec<Term>> {
+        self.meta_predicates.get(key)
+    }
+
+    pub fn imported_from(&self, key: &PredicateKey) -> Option<&ModuleName> {
+        self.imports.get(key)
+    }
+
+    // Removes one particular clause, identified by pointer rather than by value
+    pub fn remove_clause(&mut self, key: &PredicateKey, clause: &Rc<Clause>) -> bool {
+        let Some(pred) = self.predicates.get_mut(key) else { return false };
+        let Some(index) = pred.clauses.iter().position(|c| Rc::ptr_eq(c, clause)) else { return false };
+        Rc::make_mut(&mut pred.clauses).remove(index);
+        true
+    }
+
+    pub fn abolish(&mut self, key: &PredicateKey) {
+        if self.predicates.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+    }
+
+    pub fn predicates(&self) -> impl Iterator<Item = (&PredicateKey, &Predicate)> {
+        self.order.iter().filter_map(|key| self.predicates.get(key).map(|pred| (key, pred)))
+    }
+}
 
 #[derive(Debug)]
 pub struct Database {
-    pub clauses: Vec<Clause>,
+    modules: HashMap<String, Module>,
+    module_order: Vec<String>,
+    pub operators: Operators,
+    pub loading: Vec<PathBuf>,      // Files being consulted, innermost last; relative paths are resolved against it
+    pub loaded_files: Vec<String>,
+    pub messages: Vec<String>,      // Warnings and errors from loading, for the GUI to show
+    pub streams: Streams,           // Open streams, with the current input and output
+    pub output: String,             // Text the program wrote to user_output and user_error, for the GUI to show
+    pub globals: Globals,           // Global variables, records and flags, kept across queries
+    pub flags: Flags,               // Prolog flags set with set_prolog_flag/2
 }
 
 impl Database {
     pub fn new(clauses: Vec<Clause>) -> Self {
-        Database { clauses }
+        let mut db = Database {
+            modules: HashMap::new(),
+            module_order: Vec::new(),
+            operators: Operators::default(),
+            loading: Vec::new(),
+            loaded_files: Vec::new(),
+            messages: Vec::new(),
+            streams: Streams::default(),
+            output: String::new(),
+            globals: Globals::default(),
+            flags: Flags::default(),
+        };
+        db.module_mut("system");
+        db.module_mut("user");
+        db.load(clauses, None);
+        db
+    }
+
+    pub fn module(&self, name: &str) -> Option<&Module> {
+        self.modules.get(name)
+    }
+
+    // The named module, created empty if it doesn't exist yet
+    pub fn module_mut(&mut self, name: &str) -> &mut Module {
+        if !self.modules.contains_key(name) {
+            self.module_order.push(name.to_string());
+        }
+        self.modules.entry(name.to_string()).or_default()
+    }
+
+    pub fn user(&self) -> &Module {
+        &self.modules["user"]
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = (&str, &Module)> {
+        self.module_order.iter().map(|name| (name.as_str(), &self.modules[name]))
+    }
+
+    // Finds the module whose definition a call from `context` uses: the context itself, a module it
+    // imports the predicate from, and finally `user`, which every module inherits from
+    pub fn resolve(&self, context: &str, key: &PredicateKey) -> Option<(ModuleName, Rc<Vec<Rc<Clause>>>)> {
+        let module = self.modules.get(context)?;
+        if let Some(clauses) = module.clauses(key) {
+            return Some((context.into(), clauses));
+        }
+        if let Some(source) = module.imported_from(key) {
+            if let Some(clauses) = self.modules.get(&**source).and_then(|m| m.clauses(key)) {
+                return Some((source.clone(), clauses));
+            }
+        }
+        match context {
+            "user" | "system" => None,
+            _ => self.resolve("user", key),
+        }
+    }
+
+    // Makes the exported predicates of `from` (or just `only`, if given) callable from `into`
+    pub fn import(&mut self, into: &str, from: &str, only: Option<&[PredicateKey]>) -> Result<(), Term> {
+        let exports = match self.modules.get(from) {
+            Some(module) => module.exports.clone(),
+            None => return Err(errors::existence_error("module", Term::Constant(from.to_string()))),
+        };
+        let keys = match only {
+            Some(keys) => keys.to_vec(),
+            None => exports,
+        };
+        let source: ModuleName = from.into();
+        let target = self.module_mut(into);
+        for key in keys {
+            target.imports.insert(key, source.clone());
+        }
+        Ok(())
+    }
+
+    // Adds the clauses of a program, remembering which file they came from. Declarations such as
+    // `:- dynamic` are applied and other directives skipped; consult runs those as well.
+    // Returns the modules that were defined.
+    pub fn load(&mut self, clauses: Vec<Clause>, file: Option<&str>) -> Vec<String> {
+        let mut context = LoadContext::new(file);
+        for clause in clauses {
+            match clause {
+                Clause::Directive(body) => {
+                    // Malformed declarations are skipped when loading
+                    let _ = self.load_declaration(&mut context, &body.to_term());
+                }
+                clause => self.load_clause(&mut context, clause),
+            }
+        }
+        self.finish_load(context)
+    }
+
+    // Adds a clause to the module being loaded, warning about clauses that can never be called and
+    // predicates whose clauses are not together
+    pub fn load_clause(&mut self, context: &mut LoadContext, clause: Clause) {
+        if let Some(key) = clause.head().and_then(Term::predicate_key) {
+            let placed = (context.module.clone(), key.clone());
+            let first = context.seen.insert(placed.clone());
+            let discontiguous = self.module(&context.module).and_then(|m| m.predicates.get(&key)).is_some_and(|p| p.discontiguous);
+            if first && is_builtin(&key.0, key.1) {
+                self.messages.push(format!("Warning: {}: {}/{} is built in, so its clauses are never called", context.location(), key.0, key.1));
+            } else if !first && context.last.as_ref() != Some(&placed) && !discontiguous {
+                self.messages.push(format!("Warning: {}: clauses of {}/{} are not together in the source", context.location(), key.0, key.1));
+            }
+            context.last = Some(placed);
+            if let Some(file) = &context.file {
+                self.module_mut(&context.module).predicate_mut(key).file = Some(file.clone());
+            }
+        }
+        self.module_mut(&context.module).add_clause(clause, false);
+    }
+
+    // Applies a declaration such as `:- dynamic foo/1` or `:- module(m, [...])`. Returns None if
+    // the directive is not a declaration, and so has to be run as a goal.
+    pub fn load_declaration(&mut self, context: &mut LoadContext, directive: &Term) -> Option<Result<(), Term>> {
+        let Term::Compound(name, args) = directive else { return None };
+        let result = match (name.as_str(), args.len()) {
+            ("dynamic", 1) => self.module_mut(&context.module).declare_dynamic_list(&args[0]),
+            ("meta_predicate", 1) => self.module_mut(&context.module).declare_meta_predicate(&args[0]),
+            ("discontiguous", 1) => parse_indicator_list(&args[0]).map(|keys| {
+                let module = self.module_mut(&context.module);
+                keys.into_iter().for_each(|key| module.predicate_mut(key).discontiguous = true);
+            }),
+            ("op", 3) => self.operators.define(&args[0], &args[1], &args[2]),
+            ("module", 2) => {
+                let Term::Constant(module_name) = &args[0] else {
+                    return Some(Err(errors::type_error("atom", args[0].clone())));
+                };
+                let exports = match parse_indicator_list(&args[1]) {
+                    Ok(exports) => exports,
+                    Err(error) => return Some(Err(error)),
+                };
+                context.module = module_name.clone();
+                if !context.defined.contains(module_name) {
+                    context.defined.push(module_name.clone());
+                }
+                let module = self.module_mut(module_name);
+                module.exports = exports;
+                module.file = context.file.clone();
+                Ok(())
+            }
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    // Makes the modules a load defined visible from `user`, returning their names
+    pub fn finish_load(&mut self, context: LoadContext) -> Vec<String> {
+        for module in &context.defined {
+            let _ = self.import("user", module, None);
+        }
+        context.defined
+    }
+
+    // Removes every predicate loaded from a file, so consulting it again starts afresh
+    pub fn unload_file(&mut self, file: &str) {
+        for module in self.modules.values_mut() {
+            let keys: Vec<PredicateKey> = module
+                .predicates()
+                .filter(|(_, pred)| pred.file.as_deref() == Some(file))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in keys {
+                module.abolish(&key);
+            }
+        }
+    }
+
+    // The loaded module named by a use_module/1 argument: its name, or the file it came from
+    pub fn loaded_module(&self, name: &str, path: Option<&str>) -> Option<String> {
+        self.modules()
+            .find(|(module_name, module)| *module_name == name || (path.is_some() && module.file.as_deref() == path))
+            .map(|(module_name, _)| module_name.to_string())
+    }
+}
+
+// The state of a load in progress: the module clauses go into and where they come from
+pub struct LoadContext {
+    pub module: String,
+    pub file: Option<String>,
+    pub source: Option<String>, // File the text being read comes from, which differs in an included file
+    pub line: usize,            // Line of the clause being loaded
+    defined: Vec<String>,
+    seen: HashSet<(String, PredicateKey)>, // Predicates this load has added clauses to
+    last: Option<(String, PredicateKey)>,   // The predicate of the previous clause
+}
+
+impl LoadContext {
+    pub fn new(file: Option<&str>) -> Self {
+        LoadContext {
+            module: "user".to_string(),
+            file: file.map(str::to_string),
+            source: file.map(str::to_string),
+            line: 1,
+            defined: vec![],
+            seen: HashSet::new(),
+            last: None,
+        }
+    }
+
+    // Where the clause being loaded came from, as `file:line` for messages
+    pub fn location(&self) -> String {
+        format!("{}:{}", self.source.as_deref().unwrap_or("rules"), self.line)
+    }
+}
+
+// Reads a `Name/Arity` predicate indicator
+pub fn parse_indicator(spec: &Term) -> Result<PredicateKey, Term> {
+    use crate::errors::{instantiation_error, type_error, domain_error};
+    match spec {
+        Term::Compound(slash, args) if slash == "/" && args.len() == 2 => match (&args[0], &args[1]) {
+            (Term::Constant(name), Term::Integer(arity)) if *arity >= 0 => Ok((name.clone(), *arity as usize)),
+            (Term::Variable(_), _) | (_, Term::Variable(_)) => Err(instantiation_error()),
+            (Term::Constant(_), Term::Integer(_)) => Err(domain_error("not_less_than_zero", args[1].clone())),
+            (Term::Constant(_), other) => Err(type_error("integer", other.clone())),
+            (other, _) => Err(type_error("atom", other.clone())),
+        },
+        Term::Variable(_) => Err(instantiation_error()),
+        other => Err(type_error("predicate_indicator", other.clone())),
+    }
+}
+
+// Reads indicators separated by commas or given as a list, e.g. `[foo/1, bar/2]`
+pub fn parse_indicator_list(spec: &Term) -> Result<Vec<PredicateKey>, Term> {
+    match spec {
+        Term::Compound(name, args) if name == "," && args.len() == 2 => {
+            let mut keys = parse_indicator_list(&args[0])?;
+            keys.extend(parse_indicator_list(&args[1])?);
+            Ok(keys)
+        }
+        Term::List(head, tail) => {
+            let mut keys = parse_indicator_list(head)?;
+            keys.extend(parse_indicator_list(tail)?);
+            Ok(keys)
+        }
+        Term::EmptyList => Ok(vec![]),
+        spec => Ok(vec![parse_indicator(spec)?]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terms::Expression;
+
+    fn fact(name: &str, arg: i64) -> Clause {
+        Clause::Fact(Term::Compound(name.into(), vec![Term::Integer(arg)]))
+    }
+
+    fn directive(name: &str, args: Vec<Term>) -> Clause {
+        Clause::Directive(Expression::Term(Term::Compound(name.into(), args)))
+    }
+
+    fn indicator_list(keys: &[(&str, i64)]) -> Term {
+        Term::list_from_vec(keys.iter().map(|(name, arity)| {
+            Term::Compound("/".into(), vec![Term::Constant(name.to_string()), Term::Integer(*arity)])
+        }).collect())
+    }
+
+    #[test]
+    fn test_clauses_are_grouped_by_predicate() {
+        let db = Database::new(vec![fact("a", 1), fact("b", 2), fact("a", 3)]);
+        assert_eq!(db.user().clauses(&("a".into(), 1)).unwrap().len(), 2);
+        assert_eq!(db.user().clauses(&("b".into(), 1)).unwrap().len(), 1);
+        assert!(db.user().clauses(&("a".into(), 2)).is_none());
+    }
+
+    #[test]
+    fn test_snapshot_is_unaffected_by_updates() {
+        let mut db = Database::new(vec![fact("a", 1)]);
+        let snapshot = db.user().clauses(&("a".into(), 1)).unwrap();
+        db.module_mut("user").add_clause(fact("a", 2), false);
+        db.module_mut("user").add_clause(fact("a", 0), true);
+        assert_eq!(snapshot.len(), 1);
+        let live = db.user().clauses(&("a".into(), 1)).unwrap();
+        assert_eq!(*live[0], fact("a", 0));
+        assert_eq!(live.len(), 3);
+    }
+
+    #[test]
+    fn test_remove_clause_by_identity() {
+        let mut db = Database::new(vec![fact("a", 1), fact("a", 1)]);
+        let key = ("a".to_string(), 1);
+        let second = db.user().clauses(&key).unwrap()[1].clone();
+        assert!(db.module_mut("user").remove_clause(&key, &second));
+        assert!(!db.module_mut("user").remove_clause(&key, &second));
+        assert_eq!(db.user().clauses(&key).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_dynamic_declaration() {
+        let db = Database::new(vec![directive("dynamic", vec![
+            Term::Compound("/".into(), vec![Term::Constant("counter".into()), Term::Integer(1)]),
+        ])]);
+        let key = ("counter".to_string(), 1);
+        assert!(db.user().is_dynamic(&key));
+        assert_eq!(db.user().clauses(&key).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_modules_have_separate_predicate_tables() {
+        let db = Database::new(vec![
+            directive("module", vec![Term::Constant("a".into()), indicator_list(&[("run", 1)])]),
+            fact("helper", 1),
+            fact("run", 1),
+            directive("module", vec![Term::Constant("b".into()), indicator_list(&[])]),
+            fact("helper", 2),
+        ]);
+        let helper = ("helper".to_string(), 1);
+        assert_eq!(db.module("a").unwrap().clauses(&helper).unwrap().len(), 1);
+        assert_eq!(db.module("b").unwrap().clauses(&helper).unwrap().len(), 1);
+        assert!(db.user().clauses(&helper).is_none());
+
+        // Only the exported predicate is visible from user
+        let (module, _) = db.resolve("user", &("run".to_string(), 1)).unwrap();
+        assert_eq!(&*module, "a");
+        assert!(db.resolve("user", &helper).is_none());
     }
 }
@@ -0,0 +1,196 @@
+use crate::errors;
+use crate::parser::tree::{Clause as TreeClause, Term as TreeTerm, TermKind};
+use crate::terms::Term;
+
+// Grammar rules are translated to ordinary clauses with two extra arguments: the list still to be
+// parsed before a body runs, and the list left over after it.
+struct Translator {
+    suffix: String,
+    next: usize,
+}
+
+impl Translator {
+    fn new(suffix: String) -> Self {
+        Translator { suffix, next: 0 }
+    }
+
+    fn fresh(&mut self) -> Term {
+        self.next += 1;
+        Term::Variable(format!("_DCG{}{}", self.next, self.suffix))
+    }
+
+    fn body(&mut self, body: &Term, s0: Term, s: Term) -> Result<Term, Term> {
+        let translated = match body {
+            Term::Variable(_) => compound("phrase", vec![body.clone(), s0, s]),
+            Term::Compound(name, args) if args.len() == 2 && (name == "," || name == "->") => {
+                let middle = self.fresh();
+                let first = self.body(&args[0], s0, middle.clone())?;
+                compound(name, vec![first, self.body(&args[1], middle, s)?])
+            }
+            Term::Compound(name, args) if args.len() == 2 && name == ";" => {
+                let either = self.body(&args[0], s0.clone(), s.clone())?;
+                compound(";", vec![either, self.body(&args[1], s0, s)?])
+            }
+            Term::Compound(name, args) if args.len() == 1 && name == "\\+" => {
+                let rest = self.fresh();
+                let negation = compound("\\+", vec![self.body(&args[0], s0.clone(), rest)?]);
+                compound(",", vec![negation, equals(s0, s)])
+            }
+            // `{Goal}` calls Goal without touching the input; a cut inside it cuts the rule
+            Term::Compound(name, args) if args.len() == 1 && name == "{}" => {
+                compound(",", vec![args[0].clone(), equals(s0, s)])
+            }
+            Term::Compound(name, args) if args.len() == 2 && name == ":" => {
+                compound(":", vec![args[0].clone(), self.body(&args[1], s0, s)?])
+            }
+            Term::Constant(name) if name == "!" => compound(",", vec![body.clone(), equals(s0, s)]),
+            Term::Constant(name) if name == "{}" => equals(s0, s),
+            Term::EmptyList | Term::List(_, _) => terminals(body, s0, s)?,
+            // call//N passes the two lists on to the goal it calls, like any other non-terminal
+            _ => non_terminal(body, s0, s)?,
+        };
+        Ok(translated)
+    }
+}
+
+fn compound(name: &str, args: Vec<Term>) -> Term {
+    Term::Compound(name.to_string(), args)
+}
+
+fn equals(left: Term, right: Term) -> Term {
+    compound("=", vec![left, right])
+}
+
+// A list of terminals: S0 = [t1, ..., tn | S]
+fn terminals(list: &Term, s0: Term, s: Term) -> Result<Term, Term> {
+    let items = list.to_vec().ok_or_else(|| errors::type_error("list", list.clone()))?;
+    let matched = items.into_iter().rev().fold(s, |tail, item| Term::List(Box::new(item), Box::new(tail)));
+    Ok(equals(s0, matched))
+}
+
+fn non_terminal(term: &Term, s0: Term, s: Term) -> Result<Term, Term> {
+    match term {
+        Term::Variable(_) => Err(errors::instantiation_error()),
+        Term::Constant(name) => Ok(compound(name, vec![s0, s])),
+        Term::Compound(name, args) => Ok(compound(name, args.iter().cloned().chain([s0, s]).collect())),
+        other => Err(errors::type_error("callable", other.clone())),
+    }
+}
+
+// Translates `Head --> Body` to a `Head' :- Body'` term. A head `H, Pushback` puts the pushback
+// list back in front of the input that is left once the body has run.
+pub fn translate_rule(head: &Term, body: &Term) -> Result<Term, Term> {
+    let mut translator = Translator::new(String::new());
+    let (s0, s) = (translator.fresh(), translator.fresh());
+    let (head, pushback) = match head {
+        Term::Compound(name, args) if name == "," && args.len() == 2 => (&args[0], Some(&args[1])),
+        head => (head, None),
+    };
+    let translated_head = non_terminal(head, s0.clone(), s.clone())?;
+    let translated_body = match pushback {
+        Some(pushback) => {
+            let middle = translator.fresh();
+            let parsed = translator.body(body, s0, middle.clone())?;
+            compound(",", vec![parsed, terminals(pushback, s, middle)?])
+        }
+        None => translator.body(body, s0, s)?,
+    };
+    Ok(compound(":-", vec![translated_head, translated_body]))
+}
+
+// Translates a grammar body for phrase/3 at run time. `suffix` keeps the new variables apart from
+// those of other calls.
+pub fn translate_body(body: &Term, s0: Term, s: Term, suffix: usize) -> Result<Term, Term> {
+    Translator::new(format!("_{}", suffix)).body(body, s0, s)
+}
+
+// Double-quoted text used as terminals in a grammar rule stands for the list of its character
+// codes. Strings inside `{}` goals and non-terminal arguments are left as they are.
+pub fn terminal_strings_to_codes(clause: TreeClause) -> TreeClause {
+    match clause {
+        TreeClause::Fact(rule) => match *rule {
+            TermKind::Compound(name, mut args) if name == "-->" && args.len() == 2 => {
+                let body = codes_in_body(*args.pop().unwrap());
+                let head = match *args.pop().unwrap() {
+                    TermKind::Compound(comma, mut parts) if comma == "," && parts.len() == 2 => {
+                        let pushback = codes_in_body(*parts.pop().unwrap());
+                        parts.push(pushback);
+                        Box::new(TermKind::Compound(comma, parts))
+                    }
+                    head => Box::new(head),
+                };
+                TreeClause::Fact(Box::new(TermKind::Compound(name, vec![head, body])))
+            }
+            rule => TreeClause::Fact(Box::new(rule)),
+        },
+        clause => clause,
+    }
+}
+
+fn codes_in_body(body: TermKind) -> TreeTerm {
+    match body {
+        TermKind::String(text) => text.chars().rev().fold(Box::new(TermKind::EmptyList), |tail, ch| {
+            Box::new(TermKind::List(Box::new(TermKind::Integer(ch as i64)), tail))
+        }),
+        TermKind::Compound(name, args) if [",", ";", "->", "\\+"].contains(&name.as_str()) => {
+            TermKind::Compound(name, args.into_iter().map(|arg| codes_in_body(*arg)).collect()).into()
+        }
+        body => Box::new(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consult::consult_text;
+    use crate::database::Database;
+    use crate::parser::parser::parse_query_with_operators;
+    use crate::solver::Solver;
+    use crate::terms::Expression;
+
+    fn solutions(db: &mut Database, query: &str, var: &str) -> Vec<String> {
+        let query = Term::from_tree_term(parse_query_with_operators(query, &db.operators).unwrap());
+        let mut solver = Solver::new(&Expression::Term(query), db);
+        let mut found = vec![];
+        while let Some(answer) = solver.next_solution().unwrap() {
+            found.push(answer.get(var).map_or("_".to_string(), |value| value.to_string()));
+        }
+        found
+    }
+
+    #[test]
+    fn test_grammar_rules_parse_and_generate() {
+        let mut db = Database::new(vec![]);
+        consult_text(&mut db, "
+            greeting --> \"hi\", name.
+            name --> [0'x], !.
+            name --> [0'y].
+            digits([D|T]) --> digit(D), digits(T).
+            digits([D]) --> digit(D).
+            digit(D) --> [D], { D >= 0'0, D =< 0'9 }.
+            not_digit --> \\+ digit(_), [_].
+            look, [C] --> [C].
+            any --> [_].
+            twice(G) --> call(G), call(G).
+        ", None);
+        assert!(db.messages.is_empty(), "{:?}", db.messages);
+        assert_eq!(solutions(&mut db, "phrase(greeting, `hix`).", "X"), vec!["_"]);
+        assert_eq!(solutions(&mut db, "phrase(greeting, X).", "X").len(), 1);
+        assert_eq!(solutions(&mut db, "phrase(digits(Ds), `12a`, R).", "R"), vec!["[97]", "[50, 97]"]);
+        assert!(solutions(&mut db, "phrase(not_digit, `1`).", "X").is_empty());
+        assert_eq!(solutions(&mut db, "phrase(look, [a, b], R).", "R"), vec!["[a, b]"]);
+        assert_eq!(solutions(&mut db, "phrase(twice(any), `12`, R).", "R"), vec!["[]"]);
+    }
+
+    #[test]
+    fn test_phrase_checks_its_arguments() {
+        let mut db = Database::new(vec![]);
+        let query = |db: &mut Database, text: &str| {
+            let query = Term::from_tree_term(parse_query_with_operators(text, &db.operators).unwrap());
+            Solver::new(&Expression::Term(query), db).next_solution()
+        };
+        assert_eq!(query(&mut db, "phrase(_, [])."), Err(errors::instantiation_error()));
+        assert_eq!(query(&mut db, "phrase([a], foo)."), Err(errors::type_error("list", Term::Constant("foo".to_string()))));
+        assert!(query(&mut db, "phrase(([a], {true}, [b]), [a, b]).").unwrap().is_some());
+    }
+}
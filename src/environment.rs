@@ -1,15 +1,24 @@
 use std::collections::HashMap;
 use crate::terms::Term;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Environment {
     bindings: HashMap<String, Term>,
+    pub trail_mark: usize, // Length of the substitution trail when this environment was captured
 }
 
 impl Environment {
     pub fn new() -> Self {
         Environment {
             bindings: HashMap::new(),
+            trail_mark: 0,
+        }
+    }
+
+    pub fn at(trail_mark: usize) -> Self {
+        Environment {
+            bindings: HashMap::new(),
+            trail_mark,
         }
     }
 }
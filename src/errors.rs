@@ -0,0 +1,88 @@
+use crate::terms::Term;
+
+// Constructors for the ISO `error(Formal, Context)` terms raised by builtins
+
+fn error(formal: Term) -> Term {
+    Term::Compound("error".to_string(), vec![formal, Term::Variable("_".to_string())])
+}
+
+fn atom(name: &str) -> Term {
+    Term::Constant(name.to_string())
+}
+
+pub fn instantiation_error() -> Term {
+    error(atom("instantiation_error"))
+}
+
+pub fn type_error(kind: &str, culprit: Term) -> Term {
+    error(Term::Compound("type_error".to_string(), vec![atom(kind), culprit]))
+}
+
+pub fn domain_error(domain: &str, culprit: Term) -> Term {
+    error(Term::Compound("domain_error".to_string(), vec![atom(domain), culprit]))
+}
+
+pub fn existence_error(kind: &str, culprit: Term) -> Term {
+    error(Term::Compound("existence_error".to_string(), vec![atom(kind), culprit]))
+}
+
+pub fn permission_error(action: &str, kind: &str, culprit: Term) -> Term {
+    error(Term::Compound("permission_error".to_string(), vec![atom(action), atom(kind), culprit]))
+}
+
+// Raised when an argument that has to be a fresh variable, such as open/4's stream, is bound
+pub fn uninstantiation_error(culprit: Term) -> Term {
+    error(Term::Compound("uninstantiation_error".to_string(), vec![culprit]))
+}
+
+pub fn representation_error(flag: &str) -> Term {
+    error(Term::Compound("representation_error".to_string(), vec![atom(flag)]))
+}
+
+// Raised by a unification that would bind Var to a term containing it, under occurs_check=error
+pub fn occurs_check_error(var: Term, term: Term) -> Term {
+    error(Term::Compound("occurs_check".to_string(), vec![var, term]))
+}
+
+pub fn syntax_error(message: &str) -> Term {
+    error(Term::Compound("syntax_error".to_string(), vec![atom(message)]))
+}
+
+// Raised by format/2,3 with a message naming the directive at fault
+pub fn format_error(message: &str) -> Term {
+    error(Term::Compound("format".to_string(), vec![atom(message)]))
+}
+
+pub fn evaluation_error(error_kind: &str) -> Term {
+    error(Term::Compound("evaluation_error".to_string(), vec![atom(error_kind)]))
+}
+
+// `Name/Arity` term used to report predicates in errors
+pub fn indicator(name: &str, arity: usize) -> Term {
+    Term::Compound("/".to_string(), vec![atom(name), Term::Integer(arity as i64)])
+}
+
+// `Module:Name/Arity`, leaving out the module for predicates in `user`
+pub fn qualified_indicator(module: &str, name: &str, arity: usize) -> Term {
+    match module {
+        "user" => indicator(name, arity),
+        _ => Term::Compound(":".to_string(), vec![atom(module), indicator(name, arity)]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_error_shape() {
+        let err = type_error("callable", Term::Integer(1));
+        assert_eq!(
+            err,
+            Term::Compound("error".into(), vec![
+                Term::Compound("type_error".into(), vec![Term::Constant("callable".into()), Term::Integer(1)]),
+                Term::Variable("_".into()),
+            ])
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use crate::errors;
+use crate::terms::Term;
+use crate::unification::OccursCheck;
+
+// Prolog flags, changed with set_prolog_flag/2 and read with current_prolog_flag/2. double_quotes
+// and last_call_optimisation can be set for programs that do, but only keep their value: the reader
+// always reads double-quoted text as an atom, and a last call never keeps a frame on the goal stack.
+#[derive(Debug)]
+pub struct Flags {
+    pub occurs_check: OccursCheck,
+    pub unknown: Unknown,
+    pub double_quotes: DoubleQuotes,
+    pub last_call_optimisation: bool,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Flags {
+            occurs_check: OccursCheck::default(),
+            unknown: Unknown::default(),
+            double_quotes: DoubleQuotes::default(),
+            last_call_optimisation: true,
+        }
+    }
+}
+
+// What calling a procedure with no clauses does
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Unknown {
+    #[default]
+    Error,
+    Fail,
+    Warning,
+}
+
+// What double-quoted text is read as
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum DoubleQuotes {
+    Codes,
+    Chars,
+    #[default]
+    Atom,
+    String,
+}
+
+// Flags whose value can't be changed, as integers are 64 bits
+const READ_ONLY: [&str; 4] = ["bounded", "max_integer", "min_integer", "version"];
+
+fn atom(name: &str) -> Term {
+    Term::Constant(name.to_string())
+}
+
+// The crate version as one integer, 10000 * major + 100 * minor + patch
+fn version() -> i64 {
+    env!("CARGO_PKG_VERSION").split('.').take(3).fold(0, |version, part| version * 100 + part.parse::<i64>().unwrap_or(0))
+}
+
+fn flag_name(flag: &Term) -> Result<&str, Term> {
+    match flag {
+        Term::Variable(_) => Err(errors::instantiation_error()),
+        Term::Constant(name) => Ok(name),
+        other => Err(errors::type_error("atom", other.clone())),
+    }
+}
+
+impl Flags {
+    pub fn set(&mut self, flag: &Term, value: &Term) -> Result<(), Term> {
+        let name = flag_name(flag)?;
+        if let Term::Variable(_) = value {
+            return Err(errors::instantiation_error());
+        }
+        let bad_value = || errors::domain_error("flag_value", Term::Compound("+".to_string(), vec![flag.clone(), value.clone()]));
+        let value = match value {
+            Term::Constant(value) => value.as_str(),
+            _ => "",
+        };
+        match name {
+            "occurs_check" => {
+                self.occurs_check = match value {
+                    "true" => OccursCheck::True,
+                    "false" => OccursCheck::False,
+                    "error" => OccursCheck::Error,
+                    _ => return Err(bad_value()),
+                }
+            }
+            "unknown" => {
+                self.unknown = match value {
+                    "error" => Unknown::Error,
+                    "fail" => Unknown::Fail,
+                    "warning" => Unknown::Warning,
+                    _ => return Err(bad_value()),
+                }
+            }
+            "double_quotes" => {
+                self.double_quotes = match value {
+                    "codes" => DoubleQuotes::Codes,
+                    "chars" => DoubleQuotes::Chars,
+                    "atom" => DoubleQuotes::Atom,
+                    "string" => DoubleQuotes::String,
+                    _ => return Err(bad_value()),
+                }
+            }
+            "last_call_optimisation" => {
+                self.last_call_optimisation = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(bad_value()),
+                }
+            }
+            _ if READ_ONLY.contains(&name) => {
+                return Err(errors::permission_error("modify", "flag", flag.clone()));
+            }
+            _ => return Err(errors::domain_error("prolog_flag", flag.clone())),
+        }
+        Ok(())
+    }
+
+    // Every flag with its value
+    pub fn values(&self) -> Vec<(Term, Term)> {
+        let occurs_check = match self.occurs_check {
+            OccursCheck::True => "true",
+            OccursCheck::False => "false",
+            OccursCheck::Error => "error",
+        };
+        let unknown = match self.unknown {
+            Unknown::Error => "error",
+            Unknown::Fail => "fail",
+            Unknown::Warning => "warning",
+        };
+        let double_quotes = match self.double_quotes {
+            DoubleQuotes::Codes => "codes",
+            DoubleQuotes::Chars => "chars",
+            DoubleQuotes::Atom => "atom",
+            DoubleQuotes::String => "string",
+        };
+        vec![
+            (atom("bounded"), atom("true")),
+            (atom("max_integer"), Term::Integer(i64::MAX)),
+            (atom("min_integer"), Term::Integer(i64::MIN)),
+            (atom("double_quotes"), atom(double_quotes)),
+            (atom("last_call_optimisation"), atom(if self.last_call_optimisation { "true" } else { "false" })),
+            (atom("occurs_check"), atom(occurs_check)),
+            (atom("unknown"), atom(unknown)),
+            (atom("version"), Term::Integer(version())),
+        ]
+    }
+
+    // The flags current_prolog_flag/2 enumerates: all of them, or the one asked for
+    pub fn lookup(&self, flag: &Term) -> Result<Vec<(Term, Term)>, Term> {
+        let values = self.values();
+        if let Term::Variable(_) = flag {
+            return Ok(values);
+        }
+        flag_name(flag)?;
+        match values.into_iter().find(|(name, _)| name == flag) {
+            Some(value) => Ok(vec![value]),
+            None => Err(errors::domain_error("prolog_flag", flag.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::{answers, load_program};
+
+    #[test]
+    fn test_set_checks_flag_and_value() {
+        let mut flags = Flags::default();
+        flags.set(&atom("occurs_check"), &atom("error")).unwrap();
+        assert_eq!(flags.occurs_check, OccursCheck::Error);
+        assert_eq!(flags.set(&atom("nonsense"), &atom("true")), Err(errors::domain_error("prolog_flag", atom("nonsense"))));
+        let bad = Term::Compound("+".to_string(), vec![atom("occurs_check"), atom("maybe")]);
+        assert_eq!(flags.set(&atom("occurs_check"), &atom("maybe")), Err(errors::domain_error("flag_value", bad)));
+    }
+
+    #[test]
+    fn test_read_only_flags() {
+        let mut flags = Flags::default();
+        assert_eq!(flags.set(&atom("bounded"), &atom("false")), Err(errors::permission_error("modify", "flag", atom("bounded"))));
+        assert_eq!(flags.lookup(&atom("max_integer")).unwrap(), vec![(atom("max_integer"), Term::Integer(i64::MAX))]);
+    }
+
+    #[test]
+    fn test_double_quotes_and_last_call_optimisation_keep_their_values() {
+        let mut db = load_program(":- set_prolog_flag(double_quotes, codes).");
+        assert_eq!(answers(&mut db, "current_prolog_flag(double_quotes, X)."), "codes");
+        assert_eq!(answers(&mut db, "set_prolog_flag(last_call_optimisation, false), current_prolog_flag(last_call_optimisation, X)."), "false");
+        assert_eq!(answers(&mut db, "catch(set_prolog_flag(double_quotes, bytes), error(E, _), X = E)."), "domain_error(flag_value, double_quotes+bytes)");
+    }
+
+    #[test]
+    fn test_occurs_check_is_on_by_default() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "current_prolog_flag(occurs_check, X)."), "true");
+        assert_eq!(answers(&mut db, "(A = f(A) -> X = unified ; X = failed)."), "failed");
+    }
+
+    #[test]
+    fn test_occurs_check_error() {
+        let mut db = load_program(":- set_prolog_flag(occurs_check, error).");
+        assert_eq!(answers(&mut db, "catch(A = f(A), error(E, _), X = E)."), "occurs_check(A, f(A))");
+        assert_eq!(answers(&mut db, "catch(set_prolog_flag(occurs_check, maybe), error(E, _), X = E)."), "domain_error(flag_value, occurs_check+maybe)");
+    }
+
+    #[test]
+    fn test_flag_values() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "current_prolog_flag(bounded, X)."), "true");
+        assert_eq!(answers(&mut db, "current_prolog_flag(max_integer, X)."), "9223372036854775807");
+        assert_eq!(answers(&mut db, "current_prolog_flag(unknown, X)."), "error");
+        assert_eq!(answers(&mut db, "catch(set_prolog_flag(bounded, false), error(E, _), X = E)."), "permission_error(modify, flag, bounded)");
+        assert_eq!(answers(&mut db, "catch(current_prolog_flag(colour, _), error(E, _), X = E)."), "domain_error(prolog_flag, colour)");
+    }
+
+    #[test]
+    fn test_unknown_procedures_raise_existence_errors() {
+        let mut db = load_program(":- dynamic seen/1.");
+        assert_eq!(answers(&mut db, "catch(undefined(1), error(E, _), X = E)."), "existence_error(procedure, undefined/1)");
+        assert_eq!(answers(&mut db, "(seen(_) -> X = yes ; X = no)."), "no");
+        assert_eq!(answers(&mut db, "once(member(X, [1, 2]))."), "1");
+        assert_eq!(answers(&mut db, "Y = a, (a == Y -> X = yes ; X = no)."), "yes");
+    }
+
+    #[test]
+    fn test_unknown_fail() {
+        let mut db = load_program(":- set_prolog_flag(unknown, fail).");
+        assert_eq!(answers(&mut db, "(undefined(1) -> X = yes ; X = no)."), "no");
+        assert!(db.output.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_warning() {
+        let mut db = load_program(":- set_prolog_flag(unknown, warning).");
+        assert_eq!(answers(&mut db, "(undefined(1) -> X = yes ; X = no)."), "no");
+        assert!(db.output.contains("Warning: unknown procedure undefined/1"));
+    }
+}
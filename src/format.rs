@@ -0,0 +1,330 @@
+use crate::errors;
+use crate::terms::Term;
+use crate::writer::WriteOptions;
+
+// Writes a term for ~w, ~p and ~q. The solver passes its own writer, so ~p can call portray/1.
+pub type TermWriter<'a> = &'a mut dyn FnMut(&Term, WriteOptions) -> String;
+
+// Where format/3 sends its text
+pub enum Sink {
+    Output,         // The current output
+    Stream(Term),   // A stream or alias
+    Atom(Term),     // atom(A)
+    String(Term),   // string(S); strings are atoms in this system
+    Codes(Term),    // codes(C)
+}
+
+impl Sink {
+    pub fn parse(sink: &Term) -> Result<Self, Term> {
+        match sink {
+            Term::Variable(_) => Err(errors::instantiation_error()),
+            Term::Constant(_) => Ok(Sink::Stream(sink.clone())),
+            Term::Compound(name, _) if name == "$stream" => Ok(Sink::Stream(sink.clone())),
+            Term::Compound(name, args) if args.len() == 1 => match name.as_str() {
+                "atom" => Ok(Sink::Atom(args[0].clone())),
+                "string" => Ok(Sink::String(args[0].clone())),
+                "codes" => Ok(Sink::Codes(args[0].clone())),
+                _ => Err(errors::domain_error("output_sink", sink.clone())),
+            },
+            _ => Err(errors::domain_error("output_sink", sink.clone())),
+        }
+    }
+
+    // The term the text has to unify with, or None to write it to a stream
+    pub fn result(&self, text: &str) -> Option<(Term, Term)> {
+        match self {
+            Sink::Output | Sink::Stream(_) => None,
+            Sink::Atom(term) | Sink::String(term) => Some((term.clone(), Term::Constant(text.to_string()))),
+            Sink::Codes(term) => Some((term.clone(), codes(text))),
+        }
+    }
+}
+
+fn codes(text: &str) -> Term {
+    Term::list_from_vec(text.chars().map(|ch| Term::Integer(ch as i64)).collect())
+}
+
+// The text of an atom, or of a list of character codes or one-character atoms
+pub fn text_of(term: &Term) -> Option<String> {
+    match term {
+        Term::Constant(text) => Some(text.clone()),
+        list => list.to_vec()?.iter().map(|item| match item {
+            Term::Integer(code) => u32::try_from(*code).ok().and_then(char::from_u32),
+            Term::Constant(name) if name.chars().count() == 1 => name.chars().next(),
+            _ => None,
+        }).collect(),
+    }
+}
+
+// Output built by one format call. Column stops (`~N|`, `~N+`) pad the text written since the
+// previous stop, at its fill points (`~t`), or at its end when it has none.
+struct Formatter {
+    out: String,
+    start_column: usize,        // Column the output was at before the call
+    stop: usize,                // Column of the previous column stop
+    fills: Vec<(usize, char)>,  // Fill points in the text since that stop, with their fill characters
+}
+
+impl Formatter {
+    fn column(&self) -> usize {
+        match self.out.rfind('\n') {
+            Some(newline) => self.out[newline + 1..].chars().count(),
+            None => self.start_column + self.out.chars().count(),
+        }
+    }
+
+    fn push(&mut self, text: &str) {
+        self.out.push_str(text);
+        if text.contains('\n') {
+            self.stop = 0;
+            self.fills.clear();
+        }
+    }
+
+    fn column_stop(&mut self, target: usize) {
+        let column = self.column();
+        if target > column {
+            if self.fills.is_empty() {
+                self.fills.push((self.out.len(), ' '));
+            }
+            let padding = target - column;
+            let count = self.fills.len();
+            // Any remainder goes to the last fill points
+            for (index, (position, fill)) in self.fills.drain(..).enumerate().rev() {
+                let width = padding / count + usize::from(index >= count - padding % count);
+                self.out.insert_str(position, &fill.to_string().repeat(width));
+            }
+        }
+        self.stop = target.max(column);
+        self.fills.clear();
+    }
+}
+
+// Runs format/2 on `directives` with `args`, returning the text to write
+pub fn format(directives: &Term, args: &Term, start_column: usize, write: TermWriter) -> Result<String, Term> {
+    let directives = match directives {
+        Term::Variable(_) => return Err(errors::instantiation_error()),
+        other => text_of(other).ok_or_else(|| errors::type_error("text", other.clone()))?,
+    };
+    let mut args = args.to_vec().unwrap_or_else(|| vec![args.clone()]).into_iter();
+    let mut formatter = Formatter { out: String::new(), start_column, stop: start_column, fills: vec![] };
+    let mut chars = directives.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '~' {
+            formatter.push(&ch.to_string());
+            continue;
+        }
+        // An optional numeric argument: digits, `*` to take it from the arguments, or `c for a character
+        let numeric = match chars.peek() {
+            Some('*') => {
+                chars.next();
+                match args.next() {
+                    Some(Term::Integer(n)) if n >= 0 => Some(n as usize),
+                    Some(other) => return Err(errors::format_error(&format!("~* expects a non-negative integer, got {}", other))),
+                    None => return Err(errors::format_error("not enough arguments")),
+                }
+            }
+            Some('`') => {
+                chars.next();
+                chars.next().map(|fill| fill as usize)
+            }
+            _ => {
+                let mut digits = String::new();
+                while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                    digits.push(digit);
+                }
+                digits.parse().ok()
+            }
+        };
+        let Some(directive) = chars.next() else {
+            return Err(errors::format_error("truncated format directive ~"));
+        };
+        let mut next_arg = || args.next().ok_or_else(|| errors::format_error(&format!("not enough arguments for ~{}", directive)));
+        match directive {
+            '~' => formatter.push("~"),
+            'w' => formatter.push(&write(&next_arg()?, WriteOptions::write())),
+            'p' => formatter.push(&write(&next_arg()?, WriteOptions::print())),
+            'q' => formatter.push(&write(&next_arg()?, WriteOptions::writeq())),
+            'a' => match next_arg()? {
+                Term::Variable(_) => return Err(errors::instantiation_error()),
+                arg @ (Term::Constant(_) | Term::Integer(_) | Term::EmptyList) => formatter.push(&write(&arg, WriteOptions::write())),
+                other => return Err(errors::format_error(&format!("~a expects an atomic argument, got {}", other))),
+            },
+            'd' | 'D' => {
+                let n = integer(directive, next_arg()?)?;
+                formatter.push(&decimal(n, numeric.unwrap_or(0), directive == 'D'));
+            }
+            'f' | 'e' | 'g' => {
+                let n = integer(directive, next_arg()?)? as f64;
+                formatter.push(&float(n, directive, numeric.unwrap_or(6)));
+            }
+            's' => {
+                let arg = next_arg()?;
+                let text = text_of(&arg).ok_or_else(|| errors::format_error(&format!("~s expects a list of character codes, got {}", arg)))?;
+                formatter.push(&text);
+            }
+            'c' => {
+                let code = integer(directive, next_arg()?)?;
+                let ch = u32::try_from(code).ok().and_then(char::from_u32)
+                    .ok_or_else(|| errors::format_error(&format!("~c expects a character code, got {}", code)))?;
+                formatter.push(&ch.to_string().repeat(numeric.unwrap_or(1)));
+            }
+            'r' | 'R' => {
+                let n = integer(directive, next_arg()?)?;
+                let radix = match numeric {
+                    Some(radix @ 2..=36) => radix as u32,
+                    _ => return Err(errors::format_error(&format!("~{} needs a radix between 2 and 36", directive))),
+                };
+                formatter.push(&radix_text(n, radix, directive == 'R'));
+            }
+            'n' => formatter.push(&"\n".repeat(numeric.unwrap_or(1))),
+            'i' => {
+                next_arg()?;
+            }
+            't' => {
+                let fill = numeric.and_then(|code| char::from_u32(code as u32)).unwrap_or(' ');
+                formatter.fills.push((formatter.out.len(), fill));
+            }
+            '|' => {
+                let target = numeric.unwrap_or_else(|| formatter.column());
+                formatter.column_stop(target);
+            }
+            '+' => {
+                let target = formatter.stop + numeric.unwrap_or(8);
+                formatter.column_stop(target);
+            }
+            other => return Err(errors::format_error(&format!("unknown directive ~{}", other))),
+        }
+    }
+    if args.next().is_some() {
+        return Err(errors::format_error("too many arguments"));
+    }
+    Ok(formatter.out)
+}
+
+fn integer(directive: char, arg: Term) -> Result<i64, Term> {
+    match arg {
+        Term::Integer(n) => Ok(n),
+        Term::Variable(_) => Err(errors::instantiation_error()),
+        other => Err(errors::format_error(&format!("~{} expects an integer argument, got {}", directive, other))),
+    }
+}
+
+// ~Nd puts a decimal point N digits from the right; ~D also groups the integer part by thousands
+fn decimal(n: i64, point: usize, group: bool) -> String {
+    let digits = n.unsigned_abs().to_string();
+    let digits = format!("{:0>width$}", digits, width = point + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - point);
+    let mut text = String::new();
+    for (index, digit) in whole.chars().enumerate() {
+        if group && index > 0 && (whole.len() - index) % 3 == 0 {
+            text.push(',');
+        }
+        text.push(digit);
+    }
+    if point > 0 {
+        text = format!("{}.{}", text, fraction);
+    }
+    if n < 0 {
+        text.insert(0, '-');
+    }
+    text
+}
+
+// ~f, ~e and ~g as C's printf writes them
+fn float(n: f64, directive: char, precision: usize) -> String {
+    match directive {
+        'f' => format!("{:.*}", precision, n),
+        'e' => exponent(format!("{:.*e}", precision, n)),
+        _ => {
+            let precision = precision.max(1);
+            let exp = if n == 0.0 { 0 } else { n.abs().log10().floor() as i64 };
+            if exp < -4 || exp >= precision as i64 {
+                let text = exponent(format!("{:.*e}", precision - 1, n));
+                let (mantissa, exp) = text.split_once('e').unwrap();
+                format!("{}e{}", trim_zeros(mantissa), exp)
+            } else {
+                trim_zeros(&format!("{:.*}", (precision as i64 - 1 - exp) as usize, n)).to_string()
+            }
+        }
+    }
+}
+
+// Rust writes `1.5e3`; C writes `1.5e+03`
+fn exponent(text: String) -> String {
+    match text.split_once('e') {
+        Some((mantissa, exp)) => {
+            let exp: i32 = exp.parse().unwrap_or(0);
+            format!("{}e{}{:02}", mantissa, if exp < 0 { '-' } else { '+' }, exp.abs())
+        }
+        None => text,
+    }
+}
+
+fn trim_zeros(text: &str) -> &str {
+    match text.contains('.') {
+        true => text.trim_end_matches('0').trim_end_matches('.'),
+        false => text,
+    }
+}
+
+fn radix_text(n: i64, radix: u32, upper: bool) -> String {
+    let mut value = n.unsigned_abs();
+    let mut digits = vec![];
+    loop {
+        let digit = char::from_digit((value % radix as u64) as u32, radix).unwrap();
+        digits.push(if upper { digit.to_ascii_uppercase() } else { digit });
+        value /= radix as u64;
+        if value == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        digits.push('-');
+    }
+    digits.iter().rev().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::parse_query;
+    use crate::writer::{default_operators, Writer};
+
+    fn run(directives: &str, args: &str) -> Result<String, Term> {
+        let args = Term::from_tree_term(parse_query(&format!("{}.", args)).unwrap());
+        let mut write = |term: &Term, options| Writer::new(default_operators(), options).write(term);
+        format(&Term::Constant(directives.to_string()), &args, 0, &mut write)
+    }
+
+    #[test]
+    fn test_directives() {
+        assert_eq!(run("~w and ~q~n", "['a b', 'a b']").unwrap(), "a b and 'a b'\n");
+        assert_eq!(run("~a~~~i~a", "[x, skipped, y]").unwrap(), "x~y");
+        assert_eq!(run("~d ~2d ~D ~2D", "[-42, 314, 1234567, 1234567]").unwrap(), "-42 3.14 1,234,567 12,345.67");
+        assert_eq!(run("~2f ~e ~g ~g", "[3, 1500, 100, 1234567]").unwrap(), "3.00 1.500000e+03 100 1.23457e+06");
+        assert_eq!(run("~s ~c~3c ~8r ~16R", "[[104, 105], 97, 98, 64, 255]").unwrap(), "hi abbb 100 FF");
+        assert_eq!(run("~*c", "[3, 120]").unwrap(), "xxx");
+    }
+
+    #[test]
+    fn test_column_alignment() {
+        assert_eq!(run("~a~10|~a", "[abc, x]").unwrap(), "abc       x");
+        assert_eq!(run("~t~a~6|", "[abc]").unwrap(), "   abc");
+        assert_eq!(run("~t~a~t~7|", "[abc]").unwrap(), "  abc  ");
+        assert_eq!(run("~a~`-t~6+~a", "[ab, c]").unwrap(), "ab----c");
+        assert_eq!(run("~w~t~5+~w~t~5+~w", "[a, b, c]").unwrap(), "a    b    c");
+    }
+
+    #[test]
+    fn test_errors_name_the_directive() {
+        let message = |result: Result<String, Term>| match result.unwrap_err() {
+            Term::Compound(_, args) => args[0].to_string(),
+            other => other.to_string(),
+        };
+        assert_eq!(message(run("~d", "[a]")), "format('~d expects an integer argument, got a')");
+        assert_eq!(message(run("~w ~w", "[a]")), "format('not enough arguments for ~w')");
+        assert_eq!(message(run("~w", "[a, b]")), "format('too many arguments')");
+        assert_eq!(message(run("~y", "[]")), "format('unknown directive ~y')");
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+
+use crate::errors;
+use crate::terms::Term;
+
+// Global state that outlives backtracking: nb_setval/2 variables, the recorded database and
+// flag/3 counters. Stored terms are copies whose variables are numbered apart from the program's,
+// so later bindings can't change them; they are renamed again each time they are taken out.
+#[derive(Debug, Default)]
+pub struct Globals {
+    values: HashMap<String, Term>,
+    records: Vec<Record>,
+    next_record: usize,
+    flags: HashMap<String, Term>,
+}
+
+#[derive(Debug)]
+struct Record {
+    id: usize,
+    key: Term,
+    term: Term,
+}
+
+// A copy of a term with its variables renamed `_Stored0`, `_Stored1`, ... in order of appearance
+pub fn copy(term: &Term) -> Term {
+    fn rename(term: &Term, names: &mut Vec<String>) -> Term {
+        match term {
+            Term::Variable(name) => {
+                let index = names.iter().position(|seen| seen == name).unwrap_or_else(|| {
+                    names.push(name.clone());
+                    names.len() - 1
+                });
+                Term::Variable(format!("_Stored{}", index))
+            }
+            Term::Compound(name, args) => Term::Compound(name.clone(), args.iter().map(|arg| rename(arg, names)).collect()),
+            Term::List(head, tail) => Term::List(Box::new(rename(head, names)), Box::new(rename(tail, names))),
+            _ => term.clone(),
+        }
+    }
+    rename(term, &mut vec![])
+}
+
+// The name of a global variable, which has to be an atom
+pub fn variable_name(key: &Term) -> Result<&str, Term> {
+    match key {
+        Term::Constant(name) => Ok(name),
+        Term::Variable(_) => Err(errors::instantiation_error()),
+        other => Err(errors::type_error("atom", other.clone())),
+    }
+}
+
+// Records and flags are filed under an atom, an integer or the name and arity of a compound
+pub fn record_key(key: &Term) -> Result<Term, Term> {
+    match key {
+        Term::Constant(_) | Term::Integer(_) => Ok(key.clone()),
+        Term::Compound(name, args) => {
+            Ok(Term::Compound(name.clone(), (0..args.len()).map(|i| Term::Variable(format!("_Key{}", i))).collect()))
+        }
+        Term::Variable(_) => Err(errors::instantiation_error()),
+        other => Err(errors::type_error("key", other.clone())),
+    }
+}
+
+pub fn record_term(id: usize) -> Term {
+    Term::Compound("$record".to_string(), vec![Term::Integer(id as i64)])
+}
+
+impl Globals {
+    pub fn set_value(&mut self, name: &str, value: &Term) {
+        self.values.insert(name.to_string(), copy(value));
+    }
+
+    pub fn value(&self, name: &str) -> Option<&Term> {
+        self.values.get(name)
+    }
+
+    // Adds a record first or last under its key, returning its reference
+    pub fn record(&mut self, key: &Term, term: &Term, first: bool) -> Result<Term, Term> {
+        let record = Record { id: self.next_record, key: record_key(key)?, term: copy(term) };
+        self.next_record += 1;
+        let id = record.id;
+        if first {
+            self.records.insert(0, record);
+        } else {
+            self.records.push(record);
+        }
+        Ok(record_term(id))
+    }
+
+    // The key, term and reference of each record, in order, under the key if it is bound
+    pub fn recorded(&self, key: &Term) -> Result<Vec<(Term, Term, Term)>, Term> {
+        let key = match key {
+            Term::Variable(_) => None,
+            key => Some(record_key(key)?),
+        };
+        Ok(self.records
+            .iter()
+            .filter(|record| key.as_ref().is_none_or(|key| *key == record.key))
+            .map(|record| (record.key.clone(), record.term.clone(), record_term(record.id)))
+            .collect())
+    }
+
+    // Removes a record; a reference to one that was already erased raises an existence error
+    pub fn erase(&mut self, reference: &Term) -> Result<(), Term> {
+        let id = match reference {
+            Term::Compound(name, args) if name == "$record" && args.len() == 1 => match args[0] {
+                Term::Integer(id) => id,
+                _ => return Err(errors::type_error("db_reference", reference.clone())),
+            },
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            _ => return Err(errors::type_error("db_reference", reference.clone())),
+        };
+        let before = self.records.len();
+        self.records.retain(|record| record.id as i64 != id);
+        if self.records.len() == before {
+            return Err(errors::existence_error("db_reference", reference.clone()));
+        }
+        Ok(())
+    }
+
+    // The value of a flag, 0 until it is first set
+    pub fn flag(&self, key: &Term) -> Result<Term, Term> {
+        let key = record_key(key)?.to_string();
+        Ok(self.flags.get(&key).cloned().unwrap_or(Term::Integer(0)))
+    }
+
+    pub fn set_flag(&mut self, key: &Term, value: Term) -> Result<(), Term> {
+        self.flags.insert(record_key(key)?.to_string(), value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::{answers, load_program};
+
+    #[test]
+    fn test_records_keep_copies_in_order() {
+        let mut globals = Globals::default();
+        let key = Term::Compound("point".into(), vec![Term::Integer(1)]);
+        let term = Term::Compound("f".into(), vec![Term::Variable("X".into()), Term::Variable("Y".into()), Term::Variable("X".into())]);
+        globals.record(&key, &term, false).unwrap();
+        let first = globals.record(&Term::Compound("point".into(), vec![Term::Integer(2)]), &Term::Integer(0), true).unwrap();
+        let found = globals.recorded(&key).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].1, Term::Integer(0));
+        assert_eq!(found[1].1.to_string(), "f(_Stored0, _Stored1, _Stored0)");
+        globals.erase(&first).unwrap();
+        assert_eq!(globals.erase(&first), Err(errors::existence_error("db_reference", first.clone())));
+        assert_eq!(globals.recorded(&Term::Variable("K".into())).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_backtrackable_global_variables() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "b_setval(v, 1), (b_setval(v, 2), fail ; true), b_getval(v, X)."), "1");
+        assert_eq!(answers(&mut db, "member(Y, [a, b]), b_setval(v, Y), b_getval(v, X)."), "a ; b");
+        assert_eq!(answers(&mut db, "catch(b_getval(nothing, _), error(E, _), X = E)."), "existence_error(variable, nothing)");
+    }
+
+    #[test]
+    fn test_non_backtrackable_values_outlive_queries() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "nb_setval(cache, f(Y)), Y = bound, nb_getval(cache, f(X)), X = free."), "free");
+        assert_eq!(answers(&mut db, "(nb_setval(counter, 1), fail ; nb_getval(counter, X))."), "1");
+        assert_eq!(answers(&mut db, "nb_getval(counter, X)."), "1");
+    }
+
+    #[test]
+    fn test_recorded_database_and_flags() {
+        let mut db = load_program("count(N) :- flag(hits, N0, N0 + 1), N is N0 + 1.");
+        assert_eq!(answers(&mut db, "recordz(k, one), recordz(k, two, R), recorda(k, zero), recorded(k, X)."), "zero ; one ; two");
+        assert_eq!(answers(&mut db, "recorded(k, two, R), erase(R), findall(T, recorded(k, T), X)."), "[zero, one]");
+        assert_eq!(answers(&mut db, "recorded(k, one, R), erase(R), catch(erase(R), error(E, _), true), E = existence_error(X, _)."), "db_reference");
+        assert_eq!(answers(&mut db, "recordz(p(1), x), recorded(K, x), K = p(Y), Y = any, X = K."), "p(any)");
+        assert_eq!(answers(&mut db, "count(A), count(B), flag(hits, X, X)."), "2");
+    }
+}
@@ -6,4 +6,20 @@ pub mod unification;
 pub mod database;
 pub mod parser;
 pub mod solver;
-pub mod builtins;
\ No newline at end of file
+pub mod builtins;
+pub mod lists;
+pub mod apply;
+pub mod assoc;
+pub mod ordsets;
+pub mod pairs;
+pub mod globals;
+pub mod flags;
+pub mod errors;
+pub mod reflection;
+pub mod writer;
+pub mod format;
+pub mod consult;
+pub mod dcg;
+pub mod read;
+pub mod streams;
+pub mod console;
@@ -0,0 +1,354 @@
+use crate::builtins::{
+    builtin_append, builtin_member, builtin_nth, builtin_reverse, builtin_select, conjunction, cons, equals, fresh, goal,
+    integer_argument, list_prefix,
+};
+use crate::errors;
+use crate::terms::Term;
+use crate::unification::{unify, Substitution};
+
+// library(lists). Like the nondeterministic builtins, each predicate returns the goals to try in
+// turn. Arguments arrive with the current bindings applied.
+
+// The items of a list that has to be proper
+pub(crate) fn proper_list(list: &Term) -> Result<Vec<Term>, Term> {
+    match list_prefix(list) {
+        (items, Term::EmptyList) => Ok(items),
+        (_, Term::Variable(_)) => Err(errors::instantiation_error()),
+        _ => Err(errors::type_error("list", list.clone())),
+    }
+}
+
+fn integer_items(list: &Term) -> Result<Vec<i64>, Term> {
+    proper_list(list)?.iter().map(|item| integer_argument(item)?.ok_or_else(errors::instantiation_error)).collect()
+}
+
+// Whether two terms unify, without binding anything
+fn unifiable(left: &Term, right: &Term) -> bool {
+    unify(left, right, &mut Substitution::new())
+}
+
+fn contains(items: &[Term], item: &Term) -> bool {
+    items.iter().any(|other| unifiable(other, item))
+}
+
+fn list_goal(target: &Term, items: Vec<Term>) -> Vec<Term> {
+    vec![equals(target.clone(), Term::list_from_vec(items))]
+}
+
+// nth0/4 and nth1/4: nth0(N, List, Elem, Rest), where Rest is List without Elem. A known index
+// takes the element out or puts it in; an unknown one tries each position.
+pub fn nth_rest(base: i64, args: &[Term], suffix: usize) -> Result<Vec<Term>, Term> {
+    match integer_argument(&args[0])? {
+        Some(index) if index < base => Ok(vec![]),
+        Some(index) => {
+            let before: Vec<Term> = (0..index - base).map(|n| fresh(&format!("Before{}", n), suffix)).collect();
+            let tail = fresh("Tail", suffix);
+            let list = before.iter().rev().fold(cons(args[2].clone(), tail.clone()), |rest, item| cons(item.clone(), rest));
+            let rest = before.into_iter().rev().fold(tail, |rest, item| cons(item, rest));
+            Ok(vec![conjunction(vec![equals(args[1].clone(), list), equals(args[3].clone(), rest)])])
+        }
+        None => {
+            let (items, inserting) = match (args[1].to_vec(), args[3].to_vec()) {
+                (Some(items), _) => (items, false),
+                (None, Some(rest)) => (rest, true),
+                (None, None) => return Err(errors::instantiation_error()),
+            };
+            let positions = if inserting { items.len() + 1 } else { items.len() };
+            Ok((0..positions).map(|position| {
+                let index = equals(args[0].clone(), Term::Integer(base + position as i64));
+                let mut others = items.clone();
+                if inserting {
+                    others.insert(position, args[2].clone());
+                    conjunction(vec![index, equals(args[1].clone(), Term::list_from_vec(others))])
+                } else {
+                    let item = others.remove(position);
+                    conjunction(vec![index, equals(args[2].clone(), item), equals(args[3].clone(), Term::list_from_vec(others))])
+                }
+            }).collect())
+        }
+    }
+}
+
+// last([X], X).
+// last([_|T], X) :- last(T, X).
+pub fn last(args: &[Term], suffix: usize) -> Vec<Term> {
+    match list_prefix(&args[0]) {
+        (items, Term::EmptyList) => items.last().map(|item| vec![equals(args[1].clone(), item.clone())]).unwrap_or_default(),
+        (_, Term::Variable(_)) => {
+            let tail = fresh("Tail", suffix);
+            vec![
+                equals(args[0].clone(), cons(args[1].clone(), Term::EmptyList)),
+                conjunction(vec![
+                    equals(args[0].clone(), cons(fresh("Head", suffix), tail.clone())),
+                    equals(tail.clone(), cons(fresh("Next", suffix), fresh("Rest", suffix))),
+                    goal("last", vec![tail, args[1].clone()]),
+                ]),
+            ]
+        }
+        _ => vec![],
+    }
+}
+
+// Keeps the first of each group of identical elements
+pub fn list_to_set(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let mut set: Vec<Term> = vec![];
+    for item in proper_list(&args[0])? {
+        if !set.contains(&item) {
+            set.push(item);
+        }
+    }
+    Ok(list_goal(&args[1], set))
+}
+
+// Sums with is/2, so the elements may be arithmetic expressions
+pub fn sum_list(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let sum = proper_list(&args[0])?.into_iter().fold(Term::Integer(0), |sum, item| goal("+", vec![sum, item]));
+    Ok(vec![goal("is", vec![args[1].clone(), sum])])
+}
+
+// max_list/2 and min_list/2 fail on an empty list
+pub fn extreme_list(max: bool, args: &[Term]) -> Result<Vec<Term>, Term> {
+    let items = integer_items(&args[0])?;
+    let extreme = if max { items.into_iter().max() } else { items.into_iter().min() };
+    Ok(extreme.map(|n| vec![equals(args[1].clone(), Term::Integer(n))]).unwrap_or_default())
+}
+
+// max_member/2 and min_member/2, in the standard order of terms
+pub fn extreme_member(max: bool, args: &[Term]) -> Result<Vec<Term>, Term> {
+    let items = proper_list(&args[1])?;
+    let extreme = match max {
+        true => items.into_iter().reduce(|best, item| if item.standard_order(&best).is_gt() { item } else { best }),
+        false => items.into_iter().reduce(|best, item| if item.standard_order(&best).is_lt() { item } else { best }),
+    };
+    Ok(extreme.map(|item| vec![equals(args[0].clone(), item)]).unwrap_or_default())
+}
+
+// max_member(Pred, Max, List) and min_member/3, ordered by call(Pred, X, Y) meaning X is at most Y
+pub fn extreme_member_by(max: bool, args: &[Term]) -> Result<Vec<Term>, Term> {
+    let items = proper_list(&args[2])?;
+    let (name, pred, extreme) = (if max { "max_member" } else { "min_member" }, &args[0], &args[1]);
+    let goals = match items.as_slice() {
+        [] => vec![],
+        [only] => vec![equals(extreme.clone(), only.clone())],
+        [first, second, rest @ ..] => {
+            let keep = |item: &Term| {
+                let list = Term::list_from_vec([item.clone()].into_iter().chain(rest.iter().cloned()).collect());
+                goal(name, vec![pred.clone(), extreme.clone(), list])
+            };
+            let (if_ordered, otherwise) = if max { (second, first) } else { (first, second) };
+            let compare = goal("call", vec![pred.clone(), first.clone(), second.clone()]);
+            vec![goal(";", vec![goal("->", vec![compare, keep(if_ordered)]), keep(otherwise)])]
+        }
+    };
+    Ok(goals)
+}
+
+// delete(List, Elem, Rest): Rest has every element of List that does not unify with Elem
+pub fn delete(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let kept = proper_list(&args[0])?.into_iter().filter(|item| !unifiable(item, &args[1])).collect();
+    Ok(list_goal(&args[2], kept))
+}
+
+// select(X, Xs, Y, Ys): Ys is Xs with one X replaced by Y
+// select(X, [X|T], Y, [Y|T]).
+// select(X, [H|T], Y, [H|T2]) :- select(X, T, Y, T2).
+pub fn select_replace(args: &[Term], suffix: usize) -> Vec<Term> {
+    let (head, tail, rest) = (fresh("Head", suffix), fresh("Tail", suffix), fresh("Rest", suffix));
+    vec![
+        conjunction(vec![
+            equals(args[1].clone(), cons(args[0].clone(), tail.clone())),
+            equals(args[3].clone(), cons(args[2].clone(), tail.clone())),
+        ]),
+        conjunction(vec![
+            equals(args[1].clone(), cons(head.clone(), tail.clone())),
+            equals(args[3].clone(), cons(head, rest.clone())),
+            goal("select", vec![args[0].clone(), tail, args[2].clone(), rest]),
+        ]),
+    ]
+}
+
+// The first solution of a goal only, for memberchk/2 and selectchk/3
+fn first_solution(name: &str, args: &[Term]) -> Vec<Term> {
+    vec![goal("->", vec![goal(name, args.to_vec()), Term::Constant("true".to_string())])]
+}
+
+// subtract/3, intersection/3 and union/3 treat elements that unify as the same
+pub fn subtract(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let delete = proper_list(&args[1])?;
+    let kept = proper_list(&args[0])?.into_iter().filter(|item| !contains(&delete, item)).collect();
+    Ok(list_goal(&args[2], kept))
+}
+
+pub fn intersection(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let other = proper_list(&args[1])?;
+    let kept = proper_list(&args[0])?.into_iter().filter(|item| contains(&other, item)).collect();
+    Ok(list_goal(&args[2], kept))
+}
+
+pub fn union(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let other = proper_list(&args[1])?;
+    let mut items: Vec<Term> = proper_list(&args[0])?.into_iter().filter(|item| !contains(&other, item)).collect();
+    items.extend(other);
+    Ok(list_goal(&args[2], items))
+}
+
+// permutation([], []).
+// permutation(L, [H|T]) :- select(H, L, R), permutation(R, T).
+// With only the second list known, the permutations of that one are the same.
+pub fn permutation(args: &[Term], suffix: usize) -> Result<Vec<Term>, Term> {
+    match (&args[0], args[0].to_vec(), args[1].to_vec()) {
+        (_, Some(items), _) if items.is_empty() => Ok(vec![equals(args[1].clone(), Term::EmptyList)]),
+        (_, Some(_), _) => {
+            let (head, tail, rest) = (fresh("Head", suffix), fresh("Tail", suffix), fresh("Rest", suffix));
+            Ok(vec![conjunction(vec![
+                equals(args[1].clone(), cons(head.clone(), tail.clone())),
+                goal("select", vec![head, args[0].clone(), rest.clone()]),
+                goal("permutation", vec![rest, tail]),
+            ])])
+        }
+        (_, None, Some(_)) => Ok(vec![goal("permutation", vec![args[1].clone(), args[0].clone()])]),
+        (Term::List(_, _) | Term::Variable(_), None, None) => Err(errors::instantiation_error()),
+        _ => Ok(vec![]),
+    }
+}
+
+// Nested lists become one list; anything that is not a list, unbound variables included, is an element
+pub fn flatten(args: &[Term]) -> Vec<Term> {
+    fn collect(term: &Term, items: &mut Vec<Term>) {
+        match term {
+            Term::EmptyList => {}
+            Term::List(head, tail) => {
+                collect(head, items);
+                collect(tail, items);
+            }
+            other => items.push(other.clone()),
+        }
+    }
+    let mut items = vec![];
+    collect(&args[0], &mut items);
+    list_goal(&args[1], items)
+}
+
+pub fn numlist(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let low = integer_argument(&args[0])?.ok_or_else(errors::instantiation_error)?;
+    let high = integer_argument(&args[1])?.ok_or_else(errors::instantiation_error)?;
+    if low > high {
+        return Ok(vec![]);
+    }
+    Ok(list_goal(&args[2], (low..=high).map(Term::Integer).collect()))
+}
+
+// Fails on partial lists, where length/2 would enumerate
+pub fn proper_length(args: &[Term]) -> Vec<Term> {
+    match list_prefix(&args[0]) {
+        (items, Term::EmptyList) => vec![equals(args[1].clone(), Term::Integer(items.len() as i64))],
+        _ => vec![],
+    }
+}
+
+// Goals for a library(lists) predicate, with its arguments already resolved
+pub fn solve(name: &str, args: &[Term], suffix: usize) -> Result<Vec<Term>, Term> {
+    Ok(match (name, args.len()) {
+        ("append", 3) => builtin_append(args, suffix),
+        ("member", 2) => builtin_member(args, suffix),
+        ("memberchk", 2) => first_solution("member", args),
+        ("select", 3) => builtin_select(args, suffix),
+        ("select", 4) => select_replace(args, suffix),
+        ("selectchk", 3) => first_solution("select", args),
+        ("nth0", 3) => builtin_nth(name, 0, args, suffix)?,
+        ("nth1", 3) => builtin_nth(name, 1, args, suffix)?,
+        ("nth0", 4) => nth_rest(0, args, suffix)?,
+        ("nth1", 4) => nth_rest(1, args, suffix)?,
+        ("reverse", 2) => builtin_reverse(args, suffix),
+        ("last", 2) => last(args, suffix),
+        ("list_to_set", 2) => list_to_set(args)?,
+        ("sum_list", 2) => sum_list(args)?,
+        ("max_list", 2) => extreme_list(true, args)?,
+        ("min_list", 2) => extreme_list(false, args)?,
+        ("max_member", 2) => extreme_member(true, args)?,
+        ("min_member", 2) => extreme_member(false, args)?,
+        ("max_member", 3) => extreme_member_by(true, args)?,
+        ("min_member", 3) => extreme_member_by(false, args)?,
+        ("delete", 3) => delete(args)?,
+        ("subtract", 3) => subtract(args)?,
+        ("intersection", 3) => intersection(args)?,
+        ("union", 3) => union(args)?,
+        ("permutation", 2) => permutation(args, suffix)?,
+        ("flatten", 2) => flatten(args),
+        ("numlist", 3) => numlist(args)?,
+        _ => proper_length(args),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::{answers, load_program, run_query};
+
+    fn var(name: &str) -> Term {
+        Term::Variable(name.to_string())
+    }
+
+    fn list(items: &[i64]) -> Term {
+        Term::list_from_vec(items.iter().map(|n| Term::Integer(*n)).collect())
+    }
+
+    #[test]
+    fn test_set_operations_compare_by_unification() {
+        let goals = subtract(&[list(&[1, 2, 3]), Term::list_from_vec(vec![var("X")]), var("R")]).unwrap();
+        assert_eq!(goals, vec![equals(var("R"), Term::EmptyList)]);
+        let goals = union(&[list(&[1, 2]), list(&[2, 3]), var("R")]).unwrap();
+        assert_eq!(goals, vec![equals(var("R"), list(&[1, 2, 3]))]);
+        assert_eq!(intersection(&[var("L"), list(&[1]), var("R")]), Err(errors::instantiation_error()));
+    }
+
+    #[test]
+    fn test_extremes_of_empty_lists_fail() {
+        assert!(extreme_list(true, &[Term::EmptyList, var("M")]).unwrap().is_empty());
+        assert!(extreme_member(false, &[var("M"), Term::EmptyList]).unwrap().is_empty());
+        let goals = extreme_member(true, &[var("M"), Term::list_from_vec(vec![Term::Integer(7), Term::Constant("a".into())])]).unwrap();
+        assert_eq!(goals, vec![equals(var("M"), Term::Constant("a".into()))]);
+    }
+
+    #[test]
+    fn test_positions_and_selection() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "nth0(1, [a, b, c], E, R), X = E-R."), "b-[a, c]");
+        assert_eq!(answers(&mut db, "nth1(I, L, x, [a, b]), X = I-L."), "1-[x, a, b] ; 2-[a, x, b] ; 3-[a, b, x]");
+        assert_eq!(answers(&mut db, "last([a, b, c], X)."), "c");
+        assert_eq!(answers(&mut db, "select(b, [a, b, c], x, X)."), "[a, x, c]");
+        assert_eq!(answers(&mut db, "selectchk(a, [a, b, a], X)."), "[b, a]");
+        assert_eq!(answers(&mut db, "memberchk(X, [a, b])."), "a");
+        assert_eq!(answers(&mut db, "delete([a, B, c, a], a, X)."), "[c]");
+    }
+
+    #[test]
+    fn test_numbers_and_extremes() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "sum_list([1, 2, 3], X)."), "6");
+        assert_eq!(answers(&mut db, "max_list([3, 9, 2], A), min_list([3, 9, 2], B), X = A-B."), "9-2");
+        assert_eq!(answers(&mut db, "max_member(X, [b, f(a), 3])."), "f(a)");
+        assert_eq!(answers(&mut db, "max_member(=<, X, [3, 9, 2])."), "9");
+        assert_eq!(answers(&mut db, "numlist(1, 4, X)."), "[1, 2, 3, 4]");
+    }
+
+    #[test]
+    fn test_whole_list_predicates() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "list_to_set([a, b, a, c, b], X)."), "[a, b, c]");
+        assert_eq!(answers(&mut db, "permutation([1, 2, 3], X)."), "[1, 2, 3] ; [1, 3, 2] ; [2, 1, 3] ; [2, 3, 1] ; [3, 1, 2] ; [3, 2, 1]");
+        assert_eq!(answers(&mut db, "flatten([a, [b, [c, []], d]], X)."), "[a, b, c, d]");
+        assert_eq!(answers(&mut db, "proper_length([a, b], X)."), "2");
+        assert_eq!(answers(&mut db, "proper_length([a|_], X)."), "");
+    }
+
+    #[test]
+    fn test_loading_and_overriding_the_library() {
+        let mut db = load_program(":- use_module(library(lists)).\nsum_list(_, mine).\nlast(_, mine).");
+        assert_eq!(answers(&mut db, "ensure_loaded(library(lists)), sum_list([1, 2], X)."), "mine");
+        assert_eq!(answers(&mut db, "last([1, 2], X)."), "mine");
+        assert_eq!(answers(&mut db, "numlist(1, 2, X)."), "[1, 2]");
+        let error = run_query(&mut db, "use_module(library(nope)).").unwrap_err();
+        assert_eq!(error, errors::existence_error("source_sink", Term::Compound("library".into(), vec![Term::Constant("nope".into())])));
+    }
+}
@@ -7,11 +7,28 @@ mod result;
 mod backtracking;
 mod environment;
 mod builtins;
-
+mod lists;
+mod apply;
+mod assoc;
+mod ordsets;
+mod pairs;
+mod globals;
+mod flags;
+mod errors;
+mod reflection;
+mod consult;
+mod dcg;
+mod read;
+mod streams;
+mod console;
+mod writer;
+mod format;
+
+use console::Console;
 use database::Database;
-use parser::parser::{parse, parse_query};
-use terms::{Clause, Term, Expression};
-use backtracking::BacktrackingStack;
+use parser::parser::{query_goal, read_term};
+use terms::{Term, Expression};
+use solver::Solver;
 
 use eframe::{egui, App, Frame};
 use std::fs;
@@ -22,6 +39,8 @@ struct PrologApp {
     query_text: String,
     query_history: Vec<String>,
     db: Option<Database>,
+    source_file: Option<String>, // File the rules in the editor were loaded from
+    console: Console,            // What queries wrote, shown apart from their answers
 }
 
 impl Default for PrologApp {
@@ -31,6 +50,8 @@ impl Default for PrologApp {
             query_text: String::new(),
             query_history: Vec::new(),
             db: None,
+            source_file: None,
+            console: Console::default(),
         }
     }
 }
@@ -45,27 +66,32 @@ impl App for PrologApp {
 
                     ui.horizontal_centered(|ui| {
                         if ui.button("Load Rules").clicked() {
-                            let file_path = "program.pl";
-                            match fs::read_to_string(file_path) {
-                                Ok(content) => self.rules_text = content,
-                                Err(err) => self.query_history.push(format!("Failed to load file: {}", err)),
+                            let picked = rfd::FileDialog::new()
+                                .add_filter("Prolog", &["pl", "pro"])
+                                .set_directory(".")
+                                .pick_file();
+                            if let Some(path) = picked {
+                                match fs::read_to_string(&path) {
+                                    Ok(content) => {
+                                        self.rules_text = content;
+                                        self.source_file = Some(path.to_string_lossy().into_owned());
+                                    }
+                                    Err(err) => self.query_history.push(format!("Failed to load file: {}", err)),
+                                }
                             }
                         }
 
                         if ui.button("Parse Rules").clicked() {
-                            match parse(&self.rules_text) {
-                                Ok(tree_clauses) => {
-                                    let clauses = tree_clauses
-                                        .into_iter()
-                                        .map(Clause::from_tree_clause)
-                                        .collect();
-                                    self.db = Some(Database::new(clauses));
-                                    self.query_history.push("Rules parsed successfully.".to_string());
-                                }
-                                Err(_) => {
-                                    self.query_history.push("Failed to parse rules.".to_string());
-                                }
+                            // Directives run as the rules load; clauses with syntax errors are
+                            // reported and the rest are loaded
+                            let mut db = Database::new(vec![]);
+                            consult::consult_text(&mut db, &self.rules_text, self.source_file.as_deref());
+                            if db.messages.is_empty() {
+                                self.query_history.push("Rules parsed successfully.".to_string());
                             }
+                            self.query_history.append(&mut db.messages);
+                            self.console.record("rules", std::mem::take(&mut db.output));
+                            self.db = Some(db);
                         }
                     });
 
@@ -82,6 +108,22 @@ impl App for PrologApp {
                                 );
                             });
                     });
+
+                    // Predicate browser: predicates outside `user` are shown with their module
+                    if let Some(ref db) = self.db {
+                        egui::CollapsingHeader::new("Predicates").show(ui, |ui| {
+                            egui::ScrollArea::vertical().max_height(150.0).show(ui, |ui| {
+                                for (module, table) in db.modules() {
+                                    for ((name, arity), _) in table.predicates() {
+                                        ui.monospace(match module {
+                                            "user" => format!("{}/{}", name, arity),
+                                            _ => format!("{}:{}/{}", module, name, arity),
+                                        });
+                                    }
+                                }
+                            });
+                        });
+                    }
                     
                 });
 
@@ -99,58 +141,76 @@ impl App for PrologApp {
 
                     ui.horizontal(|ui| {
                         if ui.button("Run Query").clicked() {
-                            if let Some(ref db) = self.db {
-                                match parse_query(&self.query_text) {
-                                    Ok(parsed_query) => {
-                                        let start_time = Instant::now();
-
-                                        let query = Term::from_tree_term(parsed_query);
-                                        let query_expr = Expression::from_term(query);
-
-                                        let mut stack = BacktrackingStack::new();
-                                        let mut counter = 0;
-                                        let solution = solver::solve(&query_expr, db, &mut stack, &mut counter);
-
-                                        let final_solution = match solution {
-                                            Some(sol) => Some(sol),
-                                            _none => {
-                                                while let Some(choice) = stack.pop() {
-                                                    let retry_solution = solver::solve(&Expression::Term(choice.alternatives[0].clone()), db, &mut stack, &mut counter);
-                                                    if retry_solution.is_some() {
-                                                        break;
-                                                    }
-                                                }
-                                                None
-                                            }
-                                        };
-
-                                        let duration = start_time.elapsed(); // End timer
-                                        if duration.as_millis() > 10 {
-                                            println!("DEBUG: Solve time {:?}ms", duration.as_millis());
-                                        }
-
-                                        let result = result::get_result(&self.query_text, final_solution, duration);
-                                        self.query_history.push(result);
-                                    }
-                                    Err(_) => {
-                                        self.query_history.push(format!("{} => Invalid query format", self.query_text));
-                                    }
+                            // Clauses asserted by earlier queries live on in the same database
+                            let db = self.db.get_or_insert_with(|| Database::new(vec![]));
+                            match read_term(&self.query_text, &db.operators) {
+                                Ok(Some(read)) => {
+                                    let start_time = Instant::now();
+
+                                    let query = Term::from_tree_term(query_goal(*read.term));
+                                    let query_expr = Expression::from_term(query);
+
+                                    let outcome = Solver::new(&query_expr, db).next_solution();
+
+                                    let duration = start_time.elapsed(); // Shown on the result line
+
+                                    let result = match outcome {
+                                        Ok(solution) => result::get_result(&self.query_text, &read.variable_names, solution, duration, &db.operators),
+                                        Err(ball) => format!("{} => uncaught exception: {}", self.query_text, ball),
+                                    };
+                                    // Messages from files the query consulted; what it wrote goes to the console
+                                    self.query_history.append(&mut db.messages);
+                                    self.console.record(&self.query_text, std::mem::take(&mut db.output));
+                                    self.query_history.push(result);
+                                }
+                                Ok(None) => {}
+                                Err((error, _)) => {
+                                    self.query_history.push(format!("{} => Syntax error, {}", self.query_text, error));
                                 }
-                            } else {
-                                self.query_history.push("No rules loaded. Please parse rules first.".to_string());
                             }
                         }
                     });
 
                     ui.add_space(10.0);
 
-                    ui.heading("Query History:");
-                    egui::ScrollArea::vertical().max_height(380.0).show(ui, |ui| {
-                        for entry in &self.query_history {
-                            ui.group(|ui| {
-                                ui.label(entry);
+                    // Answers on the left, program output on the right
+                    ui.horizontal_top(|ui| {
+                        ui.vertical(|ui| {
+                            ui.heading("Query History:");
+                            egui::ScrollArea::vertical().id_source("history").max_height(380.0).show(ui, |ui| {
+                                for entry in &self.query_history {
+                                    ui.group(|ui| {
+                                        ui.label(entry);
+                                    });
+                                }
                             });
-                        }
+                        });
+
+                        ui.separator();
+
+                        ui.vertical(|ui| {
+                            ui.horizontal(|ui| {
+                                ui.heading("Output:");
+                                if ui.button("Clear").clicked() {
+                                    self.console.clear();
+                                }
+                                if ui.button("Copy").clicked() {
+                                    ctx.copy_text(self.console.text());
+                                }
+                            });
+                            egui::ScrollArea::vertical()
+                                .id_source("console")
+                                .max_height(380.0)
+                                .stick_to_bottom(true)
+                                .show(ui, |ui| {
+                                    for entry in self.console.entries() {
+                                        ui.group(|ui| {
+                                            ui.label(egui::RichText::new(format!("?- {}", entry.query)).weak());
+                                            ui.monospace(&entry.text);
+                                        });
+                                    }
+                                });
+                        });
                     });
                 });
             });
@@ -0,0 +1,107 @@
+use std::cmp::Ordering;
+
+use crate::builtins::{equals, true_goal};
+use crate::lists::proper_list;
+use crate::terms::Term;
+
+// library(ordsets): sets as lists sorted in the standard order of terms, without duplicates.
+// The operations merge their sorted inputs in one pass; membership is a binary search.
+
+fn is_ordset(items: &[Term]) -> bool {
+    items.windows(2).all(|pair| pair[0].standard_order(&pair[1]).is_lt())
+}
+
+fn to_set(mut items: Vec<Term>) -> Vec<Term> {
+    items.sort_by(Term::standard_order);
+    items.dedup_by(|a, b| a.standard_order(b).is_eq());
+    items
+}
+
+// Walks two sets in step, keeping the elements only in the first, in both or only in the second
+fn merge(first: Vec<Term>, second: Vec<Term>, keep: [bool; 3]) -> Vec<Term> {
+    let mut merged = vec![];
+    let (mut first, mut second) = (first.into_iter().peekable(), second.into_iter().peekable());
+    loop {
+        let order = match (first.peek(), second.peek()) {
+            (Some(a), Some(b)) => a.standard_order(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => return merged,
+        };
+        let item = match order {
+            Ordering::Less => first.next(),
+            Ordering::Equal => {
+                second.next();
+                first.next()
+            }
+            Ordering::Greater => second.next(),
+        };
+        if keep[(order as i8 + 1) as usize] {
+            merged.extend(item);
+        }
+    }
+}
+
+fn check(holds: bool) -> Vec<Term> {
+    if holds { true_goal() } else { vec![] }
+}
+
+// Goals for a library(ordsets) predicate, with its arguments already resolved
+pub fn solve(name: &str, args: &[Term]) -> Result<Vec<Term>, Term> {
+    let set_goal = |target: &Term, items: Vec<Term>| vec![equals(target.clone(), Term::list_from_vec(items))];
+    Ok(match (name, args.len()) {
+        ("is_ordset", 1) => check(args[0].to_vec().is_some_and(|items| is_ordset(&items))),
+        ("ord_empty", 1) => vec![equals(args[0].clone(), Term::EmptyList)],
+        ("list_to_ord_set", 2) => set_goal(&args[1], to_set(proper_list(&args[0])?)),
+        ("ord_union", 2) => {
+            let sets = proper_list(&args[0])?.iter().map(proper_list).collect::<Result<Vec<_>, _>>()?;
+            set_goal(&args[1], sets.into_iter().fold(vec![], |union, set| merge(union, set, [true, true, true])))
+        }
+        ("ord_memberchk", 2) => check(proper_list(&args[1])?.binary_search_by(|item| item.standard_order(&args[0])).is_ok()),
+        ("ord_subset", 2) => check(merge(proper_list(&args[0])?, proper_list(&args[1])?, [true, false, false]).is_empty()),
+        ("ord_add_element", 3) | ("ord_del_element", 3) => {
+            let keep = if name == "ord_add_element" { [true, true, true] } else { [true, false, false] };
+            set_goal(&args[2], merge(proper_list(&args[0])?, vec![args[1].clone()], keep))
+        }
+        _ => {
+            let keep = match name {
+                "ord_union" => [true, true, true],
+                "ord_intersection" => [false, true, false],
+                "ord_subtract" => [true, false, false],
+                _ => [true, false, true],
+            };
+            set_goal(&args[2], merge(proper_list(&args[0])?, proper_list(&args[1])?, keep))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::{answers, load_program};
+
+    fn set(items: &[i64]) -> Vec<Term> {
+        items.iter().map(|n| Term::Integer(*n)).collect()
+    }
+
+    #[test]
+    fn test_merges() {
+        let (a, b) = (set(&[1, 3, 5, 7]), set(&[3, 4, 7]));
+        assert_eq!(merge(a.clone(), b.clone(), [true, true, true]), set(&[1, 3, 4, 5, 7]));
+        assert_eq!(merge(a.clone(), b.clone(), [false, true, false]), set(&[3, 7]));
+        assert_eq!(merge(a.clone(), b.clone(), [true, false, false]), set(&[1, 5]));
+        assert_eq!(merge(a, b, [true, false, true]), set(&[1, 4, 5]));
+        assert_eq!(to_set(set(&[3, 1, 3, 2])), set(&[1, 2, 3]));
+        assert!(!is_ordset(&set(&[1, 1])));
+    }
+
+    #[test]
+    fn test_ordset_predicates() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "list_to_ord_set([c, a, b, a], X)."), "[a, b, c]");
+        assert_eq!(answers(&mut db, "ord_union([a, c], [b, d], A), ord_subtract(A, [c], B), ord_intersection(A, [b, c, e], C), X = B/C."), "[a, b, d]/[b, c]");
+        assert_eq!(answers(&mut db, "ord_union([[c], [a], [b, c]], X)."), "[a, b, c]");
+        assert_eq!(answers(&mut db, "ord_memberchk(b, [a, b, c]), ord_subset([a, c], [a, b, c]), \\+ ord_memberchk(d, [a, b, c]), X = yes."), "yes");
+        assert_eq!(answers(&mut db, "ord_add_element([a, c], b, A), ord_del_element(A, a, X)."), "[b, c]");
+    }
+}
@@ -0,0 +1,87 @@
+use crate::builtins::{conjunction, equals, fresh, goal};
+use crate::errors;
+use crate::lists::proper_list;
+use crate::terms::Term;
+
+// library(pairs): lists of Key-Value pairs
+
+fn pair(key: Term, value: Term) -> Term {
+    goal("-", vec![key, value])
+}
+
+// pairs_keys_values(Pairs, Keys, Values), from whichever of the three is a proper list
+pub fn pairs_keys_values(args: &[Term], suffix: usize) -> Result<Vec<Term>, Term> {
+    let length = args.iter().find_map(Term::to_vec).map(|items| items.len()).ok_or_else(errors::instantiation_error)?;
+    let keys: Vec<Term> = (0..length).map(|n| fresh(&format!("Key{}", n), suffix)).collect();
+    let values: Vec<Term> = (0..length).map(|n| fresh(&format!("Value{}", n), suffix)).collect();
+    let pairs = keys.iter().zip(&values).map(|(key, value)| pair(key.clone(), value.clone())).collect();
+    Ok(vec![conjunction(vec![
+        equals(args[0].clone(), Term::list_from_vec(pairs)),
+        equals(args[1].clone(), Term::list_from_vec(keys)),
+        equals(args[2].clone(), Term::list_from_vec(values)),
+    ])])
+}
+
+// transpose_pairs(Pairs, Transposed): the Value-Key pairs, sorted by their new keys
+pub fn transpose_pairs(args: &[Term]) -> Result<Vec<Term>, Term> {
+    let mut transposed = proper_list(&args[0])?
+        .into_iter()
+        .map(|item| match item {
+            Term::Compound(name, mut args) if name == "-" && args.len() == 2 => {
+                let value = args.pop().unwrap();
+                Ok((value, args.pop().unwrap()))
+            }
+            Term::Variable(_) => Err(errors::instantiation_error()),
+            other => Err(errors::type_error("pair", other)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    transposed.sort_by(|(a, _), (b, _)| a.standard_order(b));
+    let pairs = transposed.into_iter().map(|(value, key)| pair(value, key)).collect();
+    Ok(vec![equals(args[1].clone(), Term::list_from_vec(pairs))])
+}
+
+// map_list_to_pairs(Function, List, Pairs): Key-Element for each element, with call(Function, Element, Key)
+pub fn map_list_to_pairs(args: &[Term], suffix: usize) -> Result<Vec<Term>, Term> {
+    let items = proper_list(&args[1])?;
+    let keys: Vec<Term> = (0..items.len()).map(|n| fresh(&format!("Key{}", n), suffix)).collect();
+    let mut goals: Vec<Term> = items.iter().zip(&keys).map(|(item, key)| goal("call", vec![args[0].clone(), item.clone(), key.clone()])).collect();
+    let pairs = keys.into_iter().zip(items).map(|(key, item)| pair(key, item)).collect();
+    goals.push(equals(args[2].clone(), Term::list_from_vec(pairs)));
+    Ok(vec![conjunction(goals)])
+}
+
+// Goals for a library(pairs) predicate, with its arguments already resolved
+pub fn solve(name: &str, args: &[Term], suffix: usize) -> Result<Vec<Term>, Term> {
+    let ignored = fresh("Ignored", suffix);
+    match name {
+        "pairs_keys_values" => pairs_keys_values(args, suffix),
+        "pairs_keys" => pairs_keys_values(&[args[0].clone(), args[1].clone(), ignored], suffix),
+        "pairs_values" => pairs_keys_values(&[args[0].clone(), ignored, args[1].clone()], suffix),
+        "transpose_pairs" => transpose_pairs(args),
+        _ => map_list_to_pairs(args, suffix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::{answers, load_program};
+
+    #[test]
+    fn test_transpose_pairs_is_stable() {
+        let atom = |name: &str| Term::Constant(name.to_string());
+        let pairs = Term::list_from_vec(vec![pair(atom("a"), Term::Integer(2)), pair(atom("b"), Term::Integer(1)), pair(atom("c"), Term::Integer(2))]);
+        let transposed = Term::list_from_vec(vec![pair(Term::Integer(1), atom("b")), pair(Term::Integer(2), atom("a")), pair(Term::Integer(2), atom("c"))]);
+        let result = Term::Variable("T".to_string());
+        assert_eq!(transpose_pairs(&[pairs, result.clone()]).unwrap(), vec![equals(result, transposed)]);
+    }
+
+    #[test]
+    fn test_pairs_predicates() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "pairs_keys_values(X, [a, b], [1, 2])."), "[a-1, b-2]");
+        assert_eq!(answers(&mut db, "pairs_keys([a-1, b-2], K), pairs_values([a-1, b-2], V), X = K/V."), "[a, b]/[1, 2]");
+        assert_eq!(answers(&mut db, "transpose_pairs([a-2, b-1], X)."), "[1-b, 2-a]");
+        assert_eq!(answers(&mut db, "map_list_to_pairs(length, [[a, b], [c]], X)."), "[2-[a, b], 1-[c]]");
+    }
+}
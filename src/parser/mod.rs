@@ -1,3 +1,4 @@
 pub mod tree;
 pub mod parser;
-pub mod misc;
\ No newline at end of file
+pub mod misc;
+pub mod operators;
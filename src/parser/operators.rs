@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use crate::terms::Term;
+use crate::errors;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OpType {
+    Xfx,
+    Xfy,
+    Yfx,
+    Fy,
+    Fx,
+    Xf,
+    Yf,
+}
+
+impl OpType {
+    pub fn from_name(name: &str) -> Option<OpType> {
+        match name {
+            "xfx" => Some(OpType::Xfx),
+            "xfy" => Some(OpType::Xfy),
+            "yfx" => Some(OpType::Yfx),
+            "fy" => Some(OpType::Fy),
+            "fx" => Some(OpType::Fx),
+            "xf" => Some(OpType::Xf),
+            "yf" => Some(OpType::Yf),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            OpType::Xfx => "xfx",
+            OpType::Xfy => "xfy",
+            OpType::Yfx => "yfx",
+            OpType::Fy => "fy",
+            OpType::Fx => "fx",
+            OpType::Xf => "xf",
+            OpType::Yf => "yf",
+        }
+    }
+
+    // The highest priorities allowed for the left and right arguments of an operator with this type.
+    // An `x` argument must bind tighter than the operator, a `y` argument may have the same priority.
+    pub fn argument_priorities(self, priority: u32) -> (u32, u32) {
+        let below = priority.saturating_sub(1);
+        match self {
+            OpType::Xfx => (below, below),
+            OpType::Xfy => (below, priority),
+            OpType::Yfx => (priority, below),
+            OpType::Fy => (0, priority),
+            OpType::Fx => (0, below),
+            OpType::Xf => (below, 0),
+            OpType::Yf => (priority, 0),
+        }
+    }
+}
+
+// The ISO operators, plus the usual declaration prefixes
+const DEFAULT_OPERATORS: [(u32, OpType, &str); 52] = [
+    (1200, OpType::Xfx, ":-"), (1200, OpType::Xfx, "-->"),
+    (1200, OpType::Fx, ":-"), (1200, OpType::Fx, "?-"),
+    (1150, OpType::Fx, "dynamic"), (1150, OpType::Fx, "discontiguous"), (1150, OpType::Fx, "initialization"),
+    (1150, OpType::Fx, "meta_predicate"), (1150, OpType::Fx, "multifile"), (1150, OpType::Fx, "module_transparent"),
+    (1100, OpType::Xfy, ";"), (1100, OpType::Xfy, "|"),
+    (1050, OpType::Xfy, "->"), (1050, OpType::Xfy, "*->"),
+    (1000, OpType::Xfy, ","),
+    (900, OpType::Fy, "\\+"),
+    (700, OpType::Xfx, "="), (700, OpType::Xfx, "\\="), (700, OpType::Xfx, "=="), (700, OpType::Xfx, "\\=="),
+    (700, OpType::Xfx, "@<"), (700, OpType::Xfx, "@>"), (700, OpType::Xfx, "@=<"), (700, OpType::Xfx, "@>="),
+    (700, OpType::Xfx, "=@="), (700, OpType::Xfx, "\\=@="),
+    (700, OpType::Xfx, "=.."), (700, OpType::Xfx, "is"), (700, OpType::Xfx, "=:="), (700, OpType::Xfx, "=\\="),
+    (700, OpType::Xfx, "<"), (700, OpType::Xfx, ">"), (700, OpType::Xfx, "=<"), (700, OpType::Xfx, ">="),
+    (600, OpType::Xfy, ":"),
+    (500, OpType::Yfx, "+"), (500, OpType::Yfx, "-"), (500, OpType::Yfx, "/\\"), (500, OpType::Yfx, "\\/"),
+    (500, OpType::Yfx, "xor"),
+    (400, OpType::Yfx, "*"), (400, OpType::Yfx, "/"), (400, OpType::Yfx, "//"), (400, OpType::Yfx, "rem"),
+    (400, OpType::Yfx, "mod"), (400, OpType::Yfx, "<<"), (400, OpType::Yfx, ">>"),
+    (200, OpType::Xfx, "**"), (200, OpType::Xfy, "^"),
+    (200, OpType::Fy, "-"), (200, OpType::Fy, "+"), (200, OpType::Fy, "\\"),
+];
+
+type OperatorTable = HashMap<String, (u32, OpType)>;
+
+// The operators known to the reader and writer. Every database has its own table, changed by op/3.
+#[derive(Clone, Debug)]
+pub struct Operators {
+    prefix: OperatorTable,
+    infix: OperatorTable,
+    postfix: OperatorTable,
+}
+
+impl Default for Operators {
+    fn default() -> Self {
+        let mut operators = Operators {
+            prefix: HashMap::new(),
+            infix: HashMap::new(),
+            postfix: HashMap::new(),
+        };
+        for (priority, kind, name) in DEFAULT_OPERATORS {
+            operators.add(priority, kind, name);
+        }
+        operators
+    }
+}
+
+impl Operators {
+    pub fn prefix(&self, name: &str) -> Option<(u32, OpType)> {
+        self.prefix.get(name).copied()
+    }
+
+    pub fn infix(&self, name: &str) -> Option<(u32, OpType)> {
+        self.infix.get(name).copied()
+    }
+
+    pub fn postfix(&self, name: &str) -> Option<(u32, OpType)> {
+        self.postfix.get(name).copied()
+    }
+
+    // Defines an operator, replacing the one with the same name and fixity. Priority 0 removes it.
+    pub fn add(&mut self, priority: u32, kind: OpType, name: &str) {
+        let table = match kind {
+            OpType::Fy | OpType::Fx => &mut self.prefix,
+            OpType::Xfx | OpType::Xfy | OpType::Yfx => &mut self.infix,
+            OpType::Xf | OpType::Yf => &mut self.postfix,
+        };
+        match priority {
+            0 => { table.remove(name); }
+            _ => { table.insert(name.to_string(), (priority, kind)); }
+        }
+    }
+
+    // All operators as (priority, type, name), sorted for a stable current_op/3 order
+    pub fn iter(&self) -> Vec<(u32, OpType, &str)> {
+        let mut ops: Vec<(u32, OpType, &str)> = [&self.prefix, &self.infix, &self.postfix]
+            .into_iter()
+            .flat_map(|table| table.iter().map(|(name, (priority, kind))| (*priority, *kind, name.as_str())))
+            .collect();
+        ops.sort_by(|a, b| b.0.cmp(&a.0).then(a.2.cmp(b.2)).then(a.1.name().cmp(b.1.name())));
+        ops
+    }
+
+    // op/3: checks the arguments as ISO requires, then defines each named operator
+    pub fn define(&mut self, priority: &Term, kind: &Term, names: &Term) -> Result<(), Term> {
+        let priority = match priority {
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            Term::Integer(p) if (0..=1200).contains(p) => *p as u32,
+            Term::Integer(_) => return Err(errors::domain_error("operator_priority", priority.clone())),
+            other => return Err(errors::type_error("integer", other.clone())),
+        };
+        let kind = match kind {
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            Term::Constant(name) => OpType::from_name(name)
+                .ok_or_else(|| errors::domain_error("operator_specifier", kind.clone()))?,
+            other => return Err(errors::type_error("atom", other.clone())),
+        };
+        let names = match names {
+            Term::List(_, _) | Term::EmptyList => names
+                .to_vec()
+                .ok_or_else(|| errors::type_error("list", names.clone()))?,
+            name => vec![name.clone()],
+        };
+        let mut checked = vec![];
+        for name in &names {
+            match name {
+                Term::Variable(_) => return Err(errors::instantiation_error()),
+                Term::Constant(name) if name == "," => {
+                    return Err(errors::permission_error("modify", "operator", Term::Constant(name.clone())));
+                }
+                Term::Constant(name) if name == "|" && ((priority != 0 && priority < 1001) || !is_infix(kind)) => {
+                    return Err(errors::permission_error("create", "operator", Term::Constant(name.clone())));
+                }
+                Term::Constant(name) => checked.push(name.as_str()),
+                Term::EmptyList => {
+                    return Err(errors::permission_error("create", "operator", Term::EmptyList));
+                }
+                other => return Err(errors::type_error("atom", other.clone())),
+            }
+        }
+        for name in checked {
+            self.add(priority, kind, name);
+        }
+        Ok(())
+    }
+}
+
+fn is_infix(kind: OpType) -> bool {
+    matches!(kind, OpType::Xfx | OpType::Xfy | OpType::Yfx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_define_and_remove_operator() {
+        let mut ops = Operators::default();
+        let name = Term::Constant("likes".to_string());
+        ops.define(&Term::Integer(700), &Term::Constant("xfx".to_string()), &name).unwrap();
+        assert_eq!(ops.infix("likes"), Some((700, OpType::Xfx)));
+
+        ops.define(&Term::Integer(0), &Term::Constant("xfx".to_string()), &name).unwrap();
+        assert_eq!(ops.infix("likes"), None);
+    }
+
+    #[test]
+    fn test_define_rejects_comma() {
+        let mut ops = Operators::default();
+        let result = ops.define(&Term::Integer(700), &Term::Constant("xfx".to_string()), &Term::Constant(",".to_string()));
+        assert!(result.is_err());
+        assert_eq!(ops.infix(","), Some((1000, OpType::Xfy)));
+    }
+}
@@ -1,64 +1,161 @@
 
-use std::str::Chars;
+use std::fmt;
 use std::iter::Peekable;
+use std::ops::Range;
 
 use crate::parser::tree::{ Term, TermKind, Expr, ExprKind, Clause, empty_list, cons_list };
+use crate::parser::misc::UniqueID;
+use crate::parser::operators::Operators;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
     Word(String),
+    Atom(String),           // A quoted atom, which is never a variable or a number
+    Number(String),         // Digits with any `0x`, `0o` or `0b` prefix; `0'c` is given as its code
     String(String),
+    BackQuoted(String),
     OpenBracket,
     CloseBracket,
     OpenSquare,
     CloseSquare,
+    OpenCurly,
+    CloseCurly,
     VerticalBar,
     Comma,
     Horn,
     Period,
+    Unterminated(char),     // Quoted text with no closing quote before the end of the line
+    InvalidEscape,          // Quoted text with an escape sequence that stands for no character
+    UnterminatedComment,    // A block comment with no closing `*/` before the end of the input
+}
+
+// The source text a token was read from, for error messages
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(text) | Token::Number(text) => write!(f, "{}", text),
+            Token::Atom(text) => write!(f, "'{}'", text),
+            Token::String(text) => write!(f, "\"{}\"", text),
+            Token::BackQuoted(text) => write!(f, "`{}`", text),
+            Token::OpenBracket => write!(f, "("),
+            Token::CloseBracket => write!(f, ")"),
+            Token::OpenSquare => write!(f, "["),
+            Token::CloseSquare => write!(f, "]"),
+            Token::OpenCurly => write!(f, "{{"),
+            Token::CloseCurly => write!(f, "}}"),
+            Token::VerticalBar => write!(f, "|"),
+            Token::Comma => write!(f, ","),
+            Token::Horn => write!(f, ":-"),
+            Token::Period => write!(f, "."),
+            Token::Unterminated(quote) => write!(f, "{}", quote),
+            Token::InvalidEscape => write!(f, "\\"),
+            Token::UnterminatedComment => write!(f, "/*"),
+        }
+    }
+}
+
+// A token with the byte range of the source text it was read from, and whether layout (whitespace
+// or a comment) came before it, which tells `foo(a)` apart from `foo (a)` and `-1` apart from `- 1`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lexeme {
+    pub token: Token,
+    pub span: Range<usize>,
+    pub layout_before: bool,
+}
+
+// Walks the characters of the input, keeping the byte offset of the next one
+#[derive(Clone)]
+struct Cursor<'input> {
+    input: &'input str,
+    pos: usize,
+}
+
+impl<'input> Cursor<'input> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn next_if(&mut self, f: impl FnOnce(&char) -> bool) -> Option<char> {
+        let ch = self.peek().filter(f)?;
+        self.pos += ch.len_utf8();
+        Some(ch)
+    }
+
+    fn next_if_eq(&mut self, expected: &char) -> Option<char> {
+        self.next_if(|ch| ch == expected)
+    }
+}
+
+impl<'input> Iterator for Cursor<'input> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        self.next_if(|_| true)
+    }
 }
 
 pub struct Lexer<'input> {
-    chars: Peekable<Chars<'input>>,
+    chars: Cursor<'input>,
+    layout: bool,
+    start: usize,
 }
 
 impl<'input> Lexer<'input> {
     pub fn new(input: &'input str) -> Self {
         Lexer {
-            chars: input.chars().peekable(),
+            chars: Cursor { input, pos: 0 },
+            layout: false,
+            start: 0,
         }
     }
 
     pub fn get_token(&mut self) -> Option<Token> {
         self.eat_whitespace();
+        self.start = self.chars.pos;
 
         match self.chars.next()? {
             '(' => Some(Token::OpenBracket),
             ')' => Some(Token::CloseBracket),
             '[' => Some(Token::OpenSquare),
             ']' => Some(Token::CloseSquare),
+            '{' => Some(Token::OpenCurly),
+            '}' => Some(Token::CloseCurly),
             '|' => Some(Token::VerticalBar),
-            '.' => Some(Token::Period),
             ',' => Some(Token::Comma),
+            ';' => Some(Token::Word(";".to_string())),
+            '!' => Some(Token::Word("!".to_string())),
 
-            '\'' => {
-                let string = self.get_string(None, |ch| ch != '\'');
-                self.chars.next();      // Eat the remaining \' character
-                Some(Token::Word(string))
-            },
+            // A period ends a clause only when layout or the end of the input follows it
+            '.' if self.chars.peek().is_none_or(|ch| is_whitespace(ch) || ch == '%') => Some(Token::Period),
 
-            '\"' => {
-                let string = self.get_string(None, |ch| ch != '\"');
-                self.chars.next();      // Eat the remaining \" character
-                Some(Token::String(string))
-            },
+            '\'' => Some(self.get_quoted('\'').map_or_else(|bad| bad, Token::Atom)),
+            '\"' => Some(self.get_quoted('\"').map_or_else(|bad| bad, Token::String)),
+            '`' => Some(self.get_quoted('`').map_or_else(|bad| bad, Token::BackQuoted)),
 
             '%' => {
                 // Ignore comment lines, which start with a '%' character
                 while self.chars.next_if(|ch| *ch != '\n').is_some() { }
+                self.layout = true;
                 self.get_token()
             },
 
+            '/' if self.chars.peek() == Some('*') => {
+                // Ignore block comments up to the closing `*/`
+                self.chars.next();
+                let mut last = ' ';
+                loop {
+                    match self.chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(ch) => last = ch,
+                        None => return Some(Token::UnterminatedComment),
+                    }
+                }
+                self.layout = true;
+                self.get_token()
+            },
+
+            ch if ch.is_ascii_digit() => Some(Token::Number(self.get_number(ch))),
+
             ch if is_word(ch) => {
                 Some(Token::Word(self.get_string(Some(ch), is_word)))
             },
@@ -73,20 +170,116 @@ impl<'input> Lexer<'input> {
     }
 
     fn eat_whitespace(&mut self) {
-        while self.chars.next_if(|ch| is_whitespace(*ch)).is_some() { }
+        while self.chars.next_if(|ch| is_whitespace(*ch)).is_some() {
+            self.layout = true;
+        }
     }
 
     fn get_string(&mut self, first: Option<char>, f: impl Fn(char) -> bool) -> String {
-        let mut text = first.map(|s| s.to_string()).unwrap_or(String::new());
+        let mut text = first.map(|s| s.to_string()).unwrap_or_default();
         while let Some(ch) = self.chars.next_if(|ch| f(*ch)) {
             text.push(ch);
         }
         text
     }
+
+    // The character after the next one, without consuming either
+    fn peek_second(&self) -> Option<char> {
+        let mut ahead = self.chars.clone();
+        ahead.next();
+        ahead.next()
+    }
+
+    fn get_number(&mut self, first: char) -> String {
+        if first == '0' {
+            match self.chars.peek() {
+                Some('\'') => {
+                    self.chars.next();
+                    let code = match self.chars.next() {
+                        Some('\\') => self.get_escape().ok().flatten(),
+                        Some('\'') => { self.chars.next_if_eq(&'\''); Some('\'') },    // `0'''` as well as `0''`
+                        other => other,
+                    };
+                    return code.map(|ch| (ch as u32).to_string()).unwrap_or_default();
+                },
+                Some(prefix @ ('x' | 'o' | 'b')) => {
+                    let radix = match prefix { 'x' => 16, 'o' => 8, _ => 2 };
+                    if self.peek_second().is_some_and(|ch| ch.is_digit(radix)) {
+                        self.chars.next();
+                        let digits = self.get_string(None, |ch| ch.is_digit(radix));
+                        return format!("0{}{}", prefix, digits);
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        // Digits may be grouped with underscores, as in 1_000_000
+        let mut digits = first.to_string();
+        loop {
+            match self.chars.peek() {
+                Some(ch) if ch.is_ascii_digit() => digits.push(ch),
+                Some('_') if self.peek_second().is_some_and(|ch| ch.is_ascii_digit()) => {},
+                _ => break,
+            }
+            self.chars.next();
+        }
+        digits
+    }
+
+    // Reads quoted text up to the closing quote, where a doubled quote stands for itself. Quoted
+    // text can only continue onto the next line with a `\` escape, so text that reaches the end of
+    // the line first is unterminated. Text with a bad escape is still read up to its closing quote.
+    fn get_quoted(&mut self, quote: char) -> Result<String, Token> {
+        let mut text = String::new();
+        let mut valid = true;
+        loop {
+            match self.chars.next_if(|ch| *ch != '\n').ok_or(Token::Unterminated(quote))? {
+                ch if ch == quote && self.chars.next_if_eq(&quote).is_some() => text.push(quote),
+                ch if ch == quote => return if valid { Ok(text) } else { Err(Token::InvalidEscape) },
+                '\\' => match self.get_escape() {
+                    Ok(escaped) => text.extend(escaped),
+                    Err(()) => valid = false,
+                },
+                ch => text.push(ch),
+            }
+        }
+    }
+
+    // Reads an escape sequence after its backslash. A backslash before a newline continues the
+    // text on the next line and stands for nothing; an unknown escape, or a numeric escape without
+    // digits or naming no character, is an error.
+    fn get_escape(&mut self) -> Result<Option<char>, ()> {
+        let Some(ch) = self.chars.next() else { return Ok(None) };
+        let escaped = match ch {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            'a' => '\x07',
+            'b' => '\x08',
+            'f' => '\x0c',
+            'v' => '\x0b',
+            'e' => '\x1b',
+            's' => ' ',
+            '\n' => return Ok(None),
+            'x' | '0'..='7' => {
+                let (radix, digits) = match ch {
+                    'x' => (16, String::new()),
+                    digit => (8, digit.to_string()),
+                };
+                let digits = digits + &self.get_string(None, |ch| ch.is_digit(radix));
+                self.chars.next_if_eq(&'\\');
+                return u32::from_str_radix(&digits, radix).ok().and_then(char::from_u32).map(Some).ok_or(());
+            },
+            '\\' | '\'' | '"' | '`' => ch,
+            _ => return Err(()),
+        };
+        Ok(Some(escaped))
+    }
 }
 
 fn is_whitespace(ch: char) -> bool {
-    ch == ' ' || ch == '\n' || ch == '\t'
+    ch == ' ' || ch == '\n' || ch == '\t' || ch == '\r'
 }
 
 fn is_word(ch: char) -> bool {
@@ -95,254 +288,631 @@ fn is_word(ch: char) -> bool {
 
 fn is_operator(ch: char) -> bool {
     match ch {
-        ';' | ':' | '=' | '>' | '<' | '+' | '-' | '*' | '\\' | '/' | '!' | '#' | '$' | '?' | '@' | '^' => true,
+        ':' | '=' | '>' | '<' | '+' | '-' | '*' | '\\' | '/' | '#' | '$' | '?' | '@' | '^' | '~' | '&' | '.' => true,
         _ => false,
     }
 }
 
 impl<'input> Iterator for Lexer<'input> {
-    type Item = Token;
+    type Item = Lexeme;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.get_token()
+        self.layout = false;
+        let token = self.get_token()?;
+        Some(Lexeme { token, span: self.start..self.chars.pos, layout_before: self.layout })
     }
 }
 
 
 
 
-#[derive(Clone, Debug)]
-pub enum ParseError {
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
     UnexpectedEof,
     UnexpectedToken(Token),
+    InvalidNumber(String),
+    OperatorExpected,
+    PriorityClash,
+    UnbalancedBracket,
+    Unterminated(char),
+    InvalidEscape,
+    UnterminatedComment,
 }
 
-#[inline(always)]
-fn expect_next(input: &mut Peekable<Lexer>) -> Result<Token, ParseError> {
-    input.next().ok_or(ParseError::UnexpectedEof)
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::UnexpectedEof => write!(f, "unexpected end of file"),
+            ErrorKind::UnexpectedToken(Token::Period) => write!(f, "unexpected end of clause"),
+            ErrorKind::UnexpectedToken(token) => write!(f, "unexpected `{}`", token),
+            ErrorKind::InvalidNumber(text) => write!(f, "invalid number {}", text),
+            ErrorKind::OperatorExpected => write!(f, "operator expected"),
+            ErrorKind::PriorityClash => write!(f, "operator priority clash"),
+            ErrorKind::UnbalancedBracket => write!(f, "unbalanced bracket"),
+            ErrorKind::Unterminated('\'') => write!(f, "unterminated quoted atom"),
+            ErrorKind::Unterminated(_) => write!(f, "unterminated quoted string"),
+            ErrorKind::InvalidEscape => write!(f, "invalid escape sequence"),
+            ErrorKind::UnterminatedComment => write!(f, "unterminated block comment"),
+        }
+    }
 }
 
-#[inline]
-fn expect_token(input: &mut Peekable<Lexer>, token: Token) -> Result<(), ParseError> {
-    let next = expect_next(input)?;
-    match next == token {
-        true => Ok(()),
-        false => Err(ParseError::UnexpectedToken(next)),
-    }
+// A syntax error with the position it was found at, counting lines and columns from 1, and the
+// text of that line
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    pub kind: ErrorKind,
+    pub line: usize,
+    pub column: usize,
+    pub snippet: String,
 }
 
-fn parse_number(name: String) -> Result<i64, ParseError> {
-    let (num, _) = name.chars().rev().fold((0, 1), |acc, ch| {
-        match ch {
-            '-' => (-acc.0, acc.1),
-            _ => ((ch.to_digit(10).unwrap() as i64 * acc.1) + acc.0, acc.1 * 10)
+impl ParseError {
+    fn at(kind: ErrorKind, text: &str, offset: usize) -> Self {
+        let before = &text[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[offset..].find('\n').map_or(text.len(), |i| offset + i);
+        ParseError {
+            kind,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            snippet: text[line_start..line_end].to_string(),
         }
-    });
-    Ok(num)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}\n    {}\n    {}^",
+            self.line, self.column, self.kind, self.snippet, " ".repeat(self.column - 1))
+    }
+}
+
+
+// Converts the text of a number token, which the lexer has already checked holds only digits
+fn parse_number(text: &str, negative: bool) -> Result<i64, ErrorKind> {
+    let (digits, radix) = match text.get(..2) {
+        Some("0x") => (&text[2..], 16),
+        Some("0o") => (&text[2..], 8),
+        Some("0b") => (&text[2..], 2),
+        _ => (text, 10),
+    };
+    let number = i128::from_str_radix(digits, radix).map_err(|_| ErrorKind::InvalidNumber(text.to_string()))?;
+    let number = if negative { -number } else { number };
+    i64::try_from(number).map_err(|_| ErrorKind::InvalidNumber(text.to_string()))
 }
 
-fn parse_atom_or_variable(name: String) -> Result<Term, ParseError> {
+fn parse_atom_or_variable(name: String) -> Term {
     match name.chars().next() {
-        Some(ch) if ('0'..='9').contains(&ch) || ch == '-' => {
-            Ok(Box::new(TermKind::Integer(parse_number(name)?)))
-        },
-        Some(ch) if ('A'..='Z').contains(&ch) =>
-            Ok(Box::new(TermKind::Var(name))),
+        Some(ch) if ch.is_ascii_uppercase() =>
+            Box::new(TermKind::Var(name)),
+        Some('_') if name == "_" =>
+            Box::new(TermKind::Var(format!("_G{}", UniqueID::generate()))),   // Each `_` is a distinct variable
+        Some('_') =>
+            Box::new(TermKind::Var(name)),
         _ =>
-            Ok(Box::new(TermKind::Atom(name))),
+            Box::new(TermKind::Atom(name)),
     }
 }
 
-fn parse_compound(input: &mut Peekable<Lexer>, name: String) -> Result<Term, ParseError> {
-    match expect_next(input)? {
-        Token::OpenBracket => {
-            let args = parse_comma_separated(input)?;
-            expect_token(input, Token::CloseBracket)?;
-            Ok(Box::new(TermKind::Compound(name, args)))
-        },
-        token => Err(ParseError::UnexpectedToken(token)),
-    }
+fn is_variable(name: &str) -> bool {
+    name.starts_with(|ch: char| ch.is_ascii_uppercase() || ch == '_')
+}
+
+fn is_closing(token: &Token) -> bool {
+    matches!(token, Token::CloseBracket | Token::CloseSquare | Token::CloseCurly)
+}
+
+// An operator precedence parser driven by an operator table. Every term is read together with its
+// priority, 0 for plain terms and the operator priority for operator terms, so that an operand
+// binding looser than its operator allows is left for the caller.
+struct Parser<'a, 'input> {
+    text: &'input str,
+    input: Peekable<Lexer<'input>>,
+    ops: &'a mut Operators,
+    last: Range<usize>,     // Where the most recently read token came from
+    at_end: bool,           // Whether the most recently read token ended a clause
+    variables: Vec<(String, usize)>, // Named variables of the term being read, with their number of uses
 }
 
-fn parse_list(input: &mut Peekable<Lexer>) -> Result<Term, ParseError> {
-    if let Some(Token::CloseSquare) = input.peek() {
-        input.next();
-        return Ok(empty_list());
+impl<'a, 'input> Parser<'a, 'input> {
+    fn new(text: &'input str, ops: &'a mut Operators) -> Self {
+        Parser { text, input: Lexer::new(text).peekable(), ops, last: 0..0, at_end: false, variables: vec![] }
     }
 
-    let mut new_list = vec!();
-    let mut terms = empty_list();
+    fn peek(&mut self) -> Option<&Token> {
+        self.input.peek().map(|lexeme| &lexeme.token)
+    }
 
-    loop {
-        new_list.push(parse_term(input)?);
+    // True if the next token follows the previous one without layout in between
+    fn peek_is_glued(&mut self) -> bool {
+        self.input.peek().is_some_and(|lexeme| !lexeme.layout_before)
+    }
 
-        match expect_next(input)? {
-            Token::Comma => { /* continue the loop */ },
-            Token::CloseSquare => {
-                break;
+    fn next(&mut self) -> Result<Token, ParseError> {
+        let Some(lexeme) = self.input.next() else {
+            return Err(ParseError::at(ErrorKind::UnexpectedEof, self.text, self.text.len()));
+        };
+        self.last = lexeme.span;
+        self.at_end = lexeme.token == Token::Period;
+        match lexeme.token {
+            Token::Unterminated(quote) => {
+                // The rest of the line was swallowed, so it ends the clause if it ends with a period
+                self.at_end = self.text[self.last.clone()].trim_end().ends_with('.');
+                Err(self.error(ErrorKind::Unterminated(quote)))
             },
-            Token::VerticalBar => {
-                terms = parse_term(input)?;
-                expect_token(input, Token::CloseSquare)?;
-                break;
+            Token::InvalidEscape => Err(self.error(ErrorKind::InvalidEscape)),
+            Token::UnterminatedComment => Err(self.error(ErrorKind::UnterminatedComment)),
+            token => Ok(token),
+        }
+    }
+
+    // An error at the most recently read token
+    fn error(&self, kind: ErrorKind) -> ParseError {
+        ParseError::at(kind, self.text, self.last.start)
+    }
+
+    // An error for a token found where it can't go, telling a missing operator or bracket apart
+    // from other mistakes
+    fn unexpected(&self, token: Token, expected: &Token) -> ParseError {
+        let kind = match token {
+            ref token if is_closing(token) => ErrorKind::UnbalancedBracket,
+            Token::Period if is_closing(expected) => ErrorKind::UnbalancedBracket,
+            Token::Word(ref name) | Token::Atom(ref name) if self.ops.infix(name).is_some() || self.ops.postfix(name).is_some() => {
+                ErrorKind::PriorityClash
             },
-            token => return Err(ParseError::UnexpectedToken(token)),
+            Token::Word(_) | Token::Atom(_) | Token::Number(_) | Token::String(_) | Token::BackQuoted(_)
+            | Token::OpenBracket | Token::OpenSquare | Token::OpenCurly => ErrorKind::OperatorExpected,
+            token => ErrorKind::UnexpectedToken(token),
+        };
+        self.error(kind)
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), ParseError> {
+        if self.input.peek().is_none() && is_closing(&token) {
+            return Err(ParseError::at(ErrorKind::UnbalancedBracket, self.text, self.text.len()));
+        }
+        let next = self.next()?;
+        match next == token {
+            true => Ok(()),
+            false => Err(self.unexpected(next, &token)),
         }
     }
 
-    // Build the cons list from the vec of items
-    for item in new_list.into_iter().rev() {
-        terms = cons_list(item, terms);
+    // Skips the rest of a clause with an error, so reading can go on with the next one
+    fn skip_clause(&mut self) {
+        while !self.at_end {
+            match self.input.next() {
+                Some(lexeme) => {
+                    self.at_end = lexeme.token == Token::Period;
+                    self.last = lexeme.span;
+                }
+                None => {
+                    self.last = self.text.len()..self.text.len();
+                    break;
+                }
+            }
+        }
     }
 
-    Ok(terms)
-}
+    // The name of the next token if it could be an infix or postfix operator
+    fn peek_operator(&mut self) -> Option<String> {
+        match self.peek()? {
+            Token::Word(name) if !is_variable(name) => Some(name.clone()),
+            Token::Atom(name) => Some(name.clone()),
+            Token::Comma => Some(",".to_string()),
+            Token::VerticalBar => Some("|".to_string()),
+            Token::Horn => Some(":-".to_string()),
+            _ => None,
+        }
+    }
 
-fn parse_comma_separated(input: &mut Peekable<Lexer>) -> Result<Vec<Term>, ParseError> {
-    let mut list = vec!();
+    // Whether the next token can begin an operand, which decides if a prefix operator is applied or
+    // read as a plain atom, as in `X = (-)` or `[+, -]`
+    fn peek_starts_term(&mut self) -> bool {
+        match self.peek() {
+            None | Some(Token::Period) | Some(Token::CloseBracket) | Some(Token::CloseSquare) | Some(Token::CloseCurly)
+            | Some(Token::Comma) | Some(Token::VerticalBar) | Some(Token::Horn) => false,
+            Some(Token::Word(name)) | Some(Token::Atom(name)) => {
+                let name = name.clone();
+                is_variable(&name)
+                    || self.ops.prefix(&name).is_some()
+                    || (self.ops.infix(&name).is_none() && self.ops.postfix(&name).is_none())
+            }
+            _ => true,
+        }
+    }
 
-    loop {
-        list.push(parse_term(input)?);
-        match input.peek() {
-            Some(Token::Comma) => { input.next(); },
-            _ => { break; },
+    fn parse(&mut self, max_priority: u32) -> Result<(Term, u32), ParseError> {
+        let (mut left, mut left_priority) = self.parse_primary(max_priority)?;
+
+        while let Some(name) = self.peek_operator() {
+            if let Some((priority, kind)) = self.ops.infix(&name) {
+                let (left_max, right_max) = kind.argument_priorities(priority);
+                if priority <= max_priority && left_priority <= left_max {
+                    self.next()?;
+                    let (right, _) = self.parse(right_max)?;
+                    // `a | b` in a body is the same as `a ; b`
+                    let functor = if name == "|" { ";".to_string() } else { name };
+                    left = Box::new(TermKind::Compound(functor, vec![left, right]));
+                    left_priority = priority;
+                    continue;
+                }
+            }
+            if let Some((priority, kind)) = self.ops.postfix(&name) {
+                let (left_max, _) = kind.argument_priorities(priority);
+                if priority <= max_priority && left_priority <= left_max {
+                    self.next()?;
+                    left = Box::new(TermKind::Compound(name, vec![left]));
+                    left_priority = priority;
+                    continue;
+                }
+            }
+            break;
         }
+
+        Ok((left, left_priority))
     }
 
-    Ok(list)
-}
+    fn parse_primary(&mut self, max_priority: u32) -> Result<(Term, u32), ParseError> {
+        match self.next()? {
+            Token::Number(text) => {
+                let number = parse_number(&text, false).map_err(|kind| self.error(kind))?;
+                Ok((Box::new(TermKind::Integer(number)), 0))
+            },
+            Token::String(string) => Ok((Box::new(TermKind::String(string)), 0)),
+            Token::BackQuoted(text) => {
+                // Back-quoted text is a list of character codes
+                let codes = text.chars().rev().fold(empty_list(), |list, ch| {
+                    cons_list(Box::new(TermKind::Integer(ch as i64)), list)
+                });
+                Ok((codes, 0))
+            },
+            Token::OpenSquare => Ok((self.parse_list()?, 0)),
+            Token::OpenCurly => {
+                if let Some(Token::CloseCurly) = self.peek() {
+                    self.next()?;
+                    return self.parse_name("{}".to_string(), true, max_priority);
+                }
+                let (term, _) = self.parse(1200)?;
+                self.expect(Token::CloseCurly)?;
+                Ok((Box::new(TermKind::Compound("{}".to_string(), vec![term])), 0))
+            },
+            Token::OpenBracket => {
+                let (term, _) = self.parse(1200)?;
+                self.expect(Token::CloseBracket)?;
+                Ok((term, 0))
+            },
+            Token::Horn => self.parse_name(":-".to_string(), true, max_priority),
+            Token::VerticalBar => self.parse_name("|".to_string(), true, max_priority),
+            Token::Atom(name) => self.parse_name(name, true, max_priority),
+            Token::Word(name) => self.parse_name(name, false, max_priority),
+            token => Err(self.error(ErrorKind::UnexpectedToken(token))),
+        }
+    }
 
-// Changes start here
+    // Reads what follows a name: the arguments of a compound term, an operand if the name is a
+    // prefix operator, or nothing for a plain atom or variable
+    fn parse_name(&mut self, name: String, is_atom: bool, max_priority: u32) -> Result<(Term, u32), ParseError> {
+        let is_atom = is_atom || !is_variable(&name);
+
+        if let Some(Token::OpenBracket) = self.peek() {
+            if self.peek_is_glued() && is_atom {
+                self.next()?;
+                let args = self.parse_arguments()?;
+                self.expect(Token::CloseBracket)?;
+                return Ok((Box::new(TermKind::Compound(name, args)), 0));
+            }
+        }
 
-const OPERATORS: [(&str, u8); 12] = [
-    (",", 1),
-    ("=", 2), ("\\=", 2),
-    (">", 3), (">=", 3), ("<", 3), ("=<", 3),
-    ("is", 4),
-    ("+", 5), ("-", 5),
-    ("*", 6), ("/", 6),
-];
+        // A minus sign written directly before a number is part of it
+        if name == "-" && self.peek_is_glued() {
+            if let Some(Token::Number(digits)) = self.peek() {
+                let digits = digits.clone();
+                self.next()?;
+                let number = parse_number(&digits, true).map_err(|kind| self.error(kind))?;
+                return Ok((Box::new(TermKind::Integer(number)), 0));
+            }
+        }
 
-fn operator_precedence(op: &str) -> Option<u8> {
-    OPERATORS.iter().find_map(|(name, prec)| {
-        if *name == op { Some(*prec) } else { None }
-    })
-}
+        if let Some((priority, kind)) = self.ops.prefix(&name) {
+            if self.peek_starts_term() {
+                // Be lenient about a prefix operator above the allowed priority, as in `f(:- a)`
+                let priority = priority.min(max_priority);
+                let (_, arg_max) = kind.argument_priorities(priority);
+                let (arg, _) = self.parse(arg_max)?;
+                return Ok((Box::new(TermKind::Compound(name, vec![arg])), priority));
+            }
+        }
 
-fn parse_term_with_prec(input: &mut Peekable<Lexer>, min_prec: u8) -> Result<Term, ParseError> {
-    let mut lhs = match expect_next(input)? {
-        Token::String(string) => Ok(Box::new(TermKind::String(string))),
-        Token::Word(name) => match input.peek() {
-            Some(Token::OpenBracket) => parse_compound(input, name),
-            _ => parse_atom_or_variable(name),
-        },
-        Token::OpenSquare => parse_list(input),
-        token => Err(ParseError::UnexpectedToken(token)),
-    }?;
-
-    while let Some(next_token) = input.peek().cloned() {
-        let op = match next_token {
-            Token::Word(name) => {
-                if let Some(prec) = operator_precedence(&name) {
-                    if prec < min_prec {
-                        break;
+        match is_atom {
+            true => Ok((Box::new(TermKind::Atom(name)), 0)),
+            false => {
+                if is_variable(&name) && name != "_" {
+                    match self.variables.iter_mut().find(|(seen, _)| *seen == name) {
+                        Some((_, uses)) => *uses += 1,
+                        None => self.variables.push((name.clone(), 1)),
                     }
-                    name
-                } else {
-                    break;
                 }
+                Ok((parse_atom_or_variable(name), 0))
             }
-            _ => break,
-        };
+        }
+    }
 
-        let op_prec = operator_precedence(&op).unwrap();
-        input.next(); // consume operator
+    // Arguments of a compound term, each below the priority of the comma operator
+    fn parse_arguments(&mut self) -> Result<Vec<Term>, ParseError> {
+        let mut args = vec![self.parse(999)?.0];
+        while let Some(Token::Comma) = self.peek() {
+            self.next()?;
+            args.push(self.parse(999)?.0);
+        }
+        Ok(args)
+    }
 
-        let mut rhs = parse_term_with_prec(input, op_prec + 1)?;
+    fn parse_list(&mut self) -> Result<Term, ParseError> {
+        if let Some(Token::CloseSquare) = self.peek() {
+            self.next()?;
+            return Ok(empty_list());
+        }
 
-        while let Some(next_token) = input.peek().cloned() {
-            let next_op = match next_token {
-                Token::Word(name) => name,
-                _ => break,
-            };
+        let items = self.parse_arguments()?;
+        let mut terms = empty_list();
+        match self.next()? {
+            Token::CloseSquare => {},
+            Token::VerticalBar => {
+                terms = self.parse(999)?.0;
+                self.expect(Token::CloseSquare)?;
+            },
+            token => return Err(self.unexpected(token, &Token::CloseSquare)),
+        }
 
-            let next_prec = match operator_precedence(&next_op) {
-                Some(prec) => prec,
-                _none => break,
-            };
+        // Build the cons list from the vec of items
+        for item in items.into_iter().rev() {
+            terms = cons_list(item, terms);
+        }
 
-            if next_prec <= op_prec {
-                break;
-            }
+        Ok(terms)
+    }
 
-            input.next(); // consume the next operator
-            let next_rhs = parse_term_with_prec(input, next_prec + 1)?;
-            rhs = Box::new(TermKind::Compound(next_op, vec![rhs, next_rhs]));
-        }
+    // Reads a term up to its terminating period
+    fn read_term(&mut self) -> Result<ReadTerm, ParseError> {
+        self.variables.clear();
+        let start = self.input.peek().map_or(self.text.len(), |lexeme| lexeme.span.start);
+        let (term, _) = self.parse(1200)?;
+        self.expect(Token::Period)?;
+
+        let variable_names: Vec<String> = self.variables.iter().map(|(name, _)| name.clone()).collect();
+        let singletons = self.variables.iter().filter(|(_, uses)| *uses == 1).map(|(name, _)| name.clone()).collect();
+        let mut variables = vec![];
+        collect_variables(&term, &mut variables);
+        Ok(ReadTerm { term, variable_names, singletons, variables, start, end: self.last.end })
+    }
 
-        lhs = Box::new(TermKind::Compound(op, vec![lhs, rhs]));
+    // Reads a clause up to its terminating period, running `:- op(...)` directives straight away
+    // so that the rest of the text is read with the new operators
+    fn parse_clause(&mut self) -> Result<ProgramClause, ParseError> {
+        let read = self.read_term()?;
+        let line = self.text[..read.start].matches('\n').count() + 1;
+        let clause = self.clause(*read.term);
+        Ok(ProgramClause { clause, line, singletons: read.singletons })
     }
 
-    Ok(lhs)
+    fn clause(&mut self, term: TermKind) -> Clause {
+        match term {
+            TermKind::Compound(name, mut args) if name == ":-" && args.len() == 2 => {
+                let body = args.pop().unwrap();
+                Clause::Rule(args.pop().unwrap(), body_expression(*body))
+            },
+            TermKind::Compound(name, mut args) if (name == ":-" || name == "?-") && args.len() == 1 => {
+                let body = args.pop().unwrap();
+                if let TermKind::Compound(name, args) = &*body {
+                    if name == "op" && args.len() == 3 {
+                        let args: Vec<_> = args.iter().cloned().map(crate::terms::Term::from_tree_term).collect();
+                        let _ = self.ops.define(&args[0], &args[1], &args[2]);
+                    }
+                }
+                Clause::Directive(body_expression(*body))
+            },
+            kind => Clause::Fact(Box::new(kind)),
+        }
+    }
 }
 
-fn parse_term(input: &mut Peekable<Lexer>) -> Result<Term, ParseError> {
-    parse_term_with_prec(input, 0)
+fn collect_variables(term: &TermKind, variables: &mut Vec<String>) {
+    match term {
+        TermKind::Var(name) if !variables.contains(name) => variables.push(name.clone()),
+        TermKind::Compound(_, args) => args.iter().for_each(|arg| collect_variables(arg, variables)),
+        TermKind::List(head, tail) => {
+            collect_variables(head, variables);
+            collect_variables(tail, variables);
+        }
+        _ => {}
+    }
 }
 
-// Changes end here
-
-
-fn parse_expression(input: &mut Peekable<Lexer>) -> Result<Expr, ParseError> {
-    let term = Box::new(ExprKind::Term(parse_term(input)?));
-    match input.peek() {
-        Some(Token::Comma) => {
-            input.next();
-            Ok(Box::new(ExprKind::Conjunct(term, parse_expression(input)?)))
+// Splits a clause body at its top-level commas
+fn body_expression(body: TermKind) -> Expr {
+    match body {
+        TermKind::Compound(name, mut args) if name == "," && args.len() == 2 => {
+            let right = args.pop().unwrap();
+            let left = args.pop().unwrap();
+            Box::new(ExprKind::Conjunct(body_expression(*left), body_expression(*right)))
         },
-        _ => Ok(term),
+        kind => Box::new(ExprKind::Term(Box::new(kind))),
     }
 }
 
-fn parse_clause(input: &mut Peekable<Lexer>) -> Result<Clause, ParseError> {
-    let term = parse_term(input)?;
+pub fn parse(text: &str) -> Result<Vec<Clause>, ParseError> {
+    parse_with_operators(text, &mut Operators::default())
+}
 
-    match expect_next(input)? {
-        Token::Period => Ok(Clause::Fact(term)),
-        Token::Horn => {
-            let expr = parse_expression(input)?;
-            expect_token(input, Token::Period)?;
-            Ok(Clause::Rule(term, expr))
-        },
-        _ => Err(ParseError::UnexpectedEof),
+// Parses a program, adding the operators its `:- op(...)` directives define to `ops`. Fails with
+// the first syntax error.
+pub fn parse_with_operators(text: &str, ops: &mut Operators) -> Result<Vec<Clause>, ParseError> {
+    match parse_program(text, ops) {
+        (clauses, errors) if errors.is_empty() => Ok(clauses),
+        (_, mut errors) => Err(errors.swap_remove(0)),
     }
 }
 
-pub fn parse(text: &str) -> Result<Vec<Clause>, ParseError> {
-    let mut input = Lexer::new(text).peekable();
+// Parses a whole program, carrying on after a bad clause from the end of that clause, so every
+// syntax error is found in one pass and the good clauses can still be loaded
+pub fn parse_program(text: &str, ops: &mut Operators) -> (Vec<Clause>, Vec<ParseError>) {
+    let (clauses, errors) = read_program(text, ops);
+    (clauses.into_iter().map(|read| read.clause).collect(), errors)
+}
+
+// A clause of a program, with the line it starts on and the named variables it uses only once
+#[derive(Clone, Debug)]
+pub struct ProgramClause {
+    pub clause: Clause,
+    pub line: usize,
+    pub singletons: Vec<String>,
+}
+
+// Parses a program like `parse_program`, keeping where each clause came from
+pub fn read_program(text: &str, ops: &mut Operators) -> (Vec<ProgramClause>, Vec<ParseError>) {
+    let mut parser = Parser::new(text, ops);
 
     let mut clauses = vec!();
-    loop {
-        if input.peek().is_none() {
-            break;
+    let mut errors = vec!();
+    while parser.peek().is_some() {
+        match parser.parse_clause() {
+            Ok(clause) => clauses.push(clause),
+            Err(error) => {
+                errors.push(error);
+                parser.skip_clause();
+            },
         }
-
-        let clause = parse_clause(&mut input)?;
-        clauses.push(clause);
     }
 
-    Ok(clauses)
+    (clauses, errors)
+}
+
+// A term read by `read_term`, with what the reader saw of its variables
+#[derive(Clone, Debug)]
+pub struct ReadTerm {
+    pub term: Term,
+    pub variable_names: Vec<String>, // Named variables, not `_`, in order of first appearance
+    pub singletons: Vec<String>,     // Named variables that appear only once
+    pub variables: Vec<String>,      // Every variable, `_` included, in order of first appearance
+    pub start: usize,                // Offset in the text of the first token of the term
+    pub end: usize,                  // Offset in the text just past the terminating period
+}
+
+// Reads the first term of `text`, which must end with a period. Returns None if there is nothing
+// but layout and comments left. A syntax error comes with the offset just past the end of the bad
+// term, where reading can carry on.
+pub fn read_term(text: &str, ops: &Operators) -> Result<Option<ReadTerm>, (ParseError, usize)> {
+    let mut ops = ops.clone();
+    let mut parser = Parser::new(text, &mut ops);
+    if parser.peek().is_none() {
+        return Ok(None);
+    }
+    parser.read_term().map(Some).map_err(|error| {
+        parser.skip_clause();
+        (error, parser.last.end)
+    })
 }
 
 pub fn parse_query(text: &str) -> Result<Term, ParseError> {
-    let mut input = Lexer::new(text).peekable();
+    parse_query_with_operators(text, &Operators::default())
+}
 
-    let term = parse_term(&mut input)?;
+pub fn parse_query_with_operators(text: &str, ops: &Operators) -> Result<Term, ParseError> {
+    match read_term(text, ops).map_err(|(error, _)| error)? {
+        Some(read) => Ok(query_goal(*read.term)),
+        None => Err(ParseError::at(ErrorKind::UnexpectedEof, text, text.len())),
+    }
+}
 
-    match input.next() {
-        Some(Token::Period) => Ok(term),
-        Some(token) => Err(ParseError::UnexpectedToken(token)),
-        _none => Err(ParseError::UnexpectedEof),
+// `?- Goal.` is accepted as well as a bare goal
+pub fn query_goal(term: TermKind) -> Term {
+    match term {
+        TermKind::Compound(name, mut args) if name == "?-" && args.len() == 1 => args.pop().unwrap(),
+        kind => Box::new(kind),
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::tree::{atom, compound, integer, variable};
+
+    #[test]
+    fn test_layout_decides_between_operator_and_functor() {
+        let conj = compound(",", vec![atom("a"), atom("b")]);
+        assert_eq!(parse_query("\\+ (a, b).").unwrap(), compound("\\+", vec![conj]));
+        assert_eq!(parse_query("\\+(a, b).").unwrap(), compound("\\+", vec![atom("a"), atom("b")]));
+        assert_eq!(parse_query("X = -1.").unwrap(), compound("=", vec![variable("X"), integer(-1)]));
+        assert_eq!(parse_query("X = - 1.").unwrap(), compound("=", vec![variable("X"), compound("-", vec![integer(1)])]));
+    }
+
+    #[test]
+    fn test_priorities_and_associativity() {
+        let sub = |l, r| compound("-", vec![l, r]);
+        assert_eq!(parse_query("1 - 2 - 3.").unwrap(), sub(sub(integer(1), integer(2)), integer(3)));
+        assert_eq!(
+            parse_query("(a :- b ; c -> d).").unwrap(),
+            compound(":-", vec![atom("a"), compound(";", vec![atom("b"), compound("->", vec![atom("c"), atom("d")])])]),
+        );
+        assert!(parse_query("a = b = c.").is_err());
+        assert_eq!(parse_query("X = [-, +].").unwrap().get_args().unwrap()[1], cons_list(atom("-"), cons_list(atom("+"), empty_list())));
+    }
+
+    #[test]
+    fn test_quoted_atoms_and_escapes() {
+        let arg = |text: &str| parse_query(text).unwrap().get_args().unwrap()[1].clone();
+        assert_eq!(arg("X = 'Hello world'."), atom("Hello world"));
+        assert_eq!(arg("X = 'it''s'."), atom("it's"));
+        assert_eq!(arg("X = 'a\\n\\\\\\'\\x41\\'."), atom("a\n\\'A"));
+        assert_eq!(arg("X = 'line \\\nbreak'."), atom("line break"));
+        assert_eq!(arg("X = `ab`."), cons_list(integer(97), cons_list(integer(98), empty_list())));
+    }
+
+    #[test]
+    fn test_number_syntax() {
+        let arg = |text: &str| parse_query(text).unwrap().get_args().unwrap()[1].clone();
+        assert_eq!(arg("X = 0'a."), integer(97));
+        assert_eq!(arg("X = 0' ."), integer(32));
+        assert_eq!(arg("X = 0'\\n."), integer(10));
+        assert_eq!(arg("X = 0x1F."), integer(31));
+        assert_eq!(arg("X = 0o17."), integer(15));
+        assert_eq!(arg("X = 0b101."), integer(5));
+        assert_eq!(arg("X = 1_000_000."), integer(1_000_000));
+        assert!(matches!(parse_query("X = 99999999999999999999.").unwrap_err().kind, ErrorKind::InvalidNumber(_)));
+    }
+
+    #[test]
+    fn test_comments_curly_terms_and_solo_atoms() {
+        let clauses = parse("/* a block\n comment */ p({a, b}). /* another */ q({}, [], '|').").unwrap();
+        let curly = compound("{}", vec![compound(",", vec![atom("a"), atom("b")])]);
+        assert_eq!(clauses[0], Clause::Fact(compound("p", vec![curly])));
+        assert_eq!(clauses[1], Clause::Fact(compound("q", vec![atom("{}"), empty_list(), atom("|")])));
+    }
+
+    #[test]
+    fn test_errors_have_positions_and_kinds() {
+        let error = parse("p(a).\nq(a b).").unwrap_err();
+        assert_eq!((error.kind, error.line, error.column), (ErrorKind::OperatorExpected, 2, 5));
+        assert_eq!(error.snippet, "q(a b).");
+
+        assert_eq!(parse("p(a, [b).").unwrap_err().kind, ErrorKind::UnbalancedBracket);
+        assert_eq!(parse("p(a)).").unwrap_err().kind, ErrorKind::UnbalancedBracket);
+        assert_eq!(parse("p :- X = 'abc.\n").unwrap_err().kind, ErrorKind::Unterminated('\''));
+        assert_eq!(parse("p :- X = 'a\\xz'.").unwrap_err().kind, ErrorKind::InvalidEscape);
+        assert_eq!(parse("p :- X = 'bad\\qescape'.").unwrap_err().kind, ErrorKind::InvalidEscape);
+        assert_eq!(parse("p(a).\n/* no end\nq(b).").unwrap_err().kind, ErrorKind::UnterminatedComment);
+        assert_eq!(parse("p :- a = b = c.").unwrap_err().kind, ErrorKind::PriorityClash);
+        assert_eq!(parse("p :- X = [a:-b].").unwrap_err().kind.to_string(), "unexpected `:-`");
+    }
+
+    #[test]
+    fn test_recovers_at_the_next_clause() {
+        let (clauses, errors) = parse_program("a(1).\nb(1 2).\nc(1).\nd('x).\ne(1).\nf(.\ng(1).", &mut Operators::default());
+        let lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, vec![2, 4, 6]);
+        let heads: Vec<Clause> = ["a", "c", "e", "g"].iter().map(|name| Clause::Fact(compound(name, vec![integer(1)]))).collect();
+        assert_eq!(clauses, heads);
+    }
+}
@@ -32,6 +32,7 @@ pub enum Clause {
     // you could eliminate the fact special case by having a termkind True, which is the rhs of a rule (such that True is a special case rather than a normal Atom)
     Fact(Term),
     Rule(Term, Expr),
+    Directive(Expr),
 }
 
 
@@ -0,0 +1,189 @@
+use crate::database::Database;
+use crate::errors;
+use crate::parser::operators::Operators;
+use crate::parser::parser::{self, ErrorKind, ParseError};
+use crate::solver::rename_vars;
+use crate::terms::Term;
+
+// Pairs of terms that a read has to unify: the term read, and the values of the options
+type Unifications = Vec<(Term, Term)>;
+
+// What read_term does with a syntax error, chosen with the `syntax_errors(E)` option
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnSyntaxError {
+    Error, // Throws error(syntax_error(Message), _)
+    Fail,  // Reports the error and fails
+    Quiet, // Fails without a report
+    Dec10, // Reports the error and reads the next term instead
+}
+
+// The options of read_term/2,3
+#[derive(Debug)]
+pub struct ReadOptions {
+    variable_names: Option<Term>,
+    variables: Option<Term>,
+    singletons: Option<Term>,
+    on_error: OnSyntaxError,
+}
+
+impl ReadOptions {
+    pub fn parse(options: &Term) -> Result<Self, Term> {
+        let list = match options {
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            list => list.to_vec().ok_or_else(|| errors::type_error("list", options.clone()))?,
+        };
+        let mut parsed = ReadOptions { variable_names: None, variables: None, singletons: None, on_error: OnSyntaxError::Error };
+        for option in list {
+            let Term::Compound(name, args) = &option else {
+                return Err(match option {
+                    Term::Variable(_) => errors::instantiation_error(),
+                    option => errors::domain_error("read_option", option),
+                });
+            };
+            match (name.as_str(), args.as_slice()) {
+                ("variable_names", [value]) => parsed.variable_names = Some(value.clone()),
+                ("variables", [value]) => parsed.variables = Some(value.clone()),
+                ("singletons", [value]) => parsed.singletons = Some(value.clone()),
+                ("syntax_errors", [Term::Variable(_)]) => return Err(errors::instantiation_error()),
+                ("syntax_errors", [Term::Constant(value)]) => parsed.on_error = match value.as_str() {
+                    "error" => OnSyntaxError::Error,
+                    "fail" => OnSyntaxError::Fail,
+                    "quiet" => OnSyntaxError::Quiet,
+                    "dec10" => OnSyntaxError::Dec10,
+                    _ => return Err(errors::domain_error("syntax_errors", args[0].clone())),
+                },
+                _ => return Err(errors::domain_error("read_option", option.clone())),
+            }
+        }
+        Ok(parsed)
+    }
+
+    // Unifications of the term argument and options with a term the reader produced
+    fn unifications(&self, term: &Term, read: parser::ReadTerm, suffix: usize) -> Unifications {
+        let variable = |name: &String| rename_vars(&Term::Variable(name.clone()), suffix);
+        let named = |names: &[String]| {
+            let pairs = names.iter().map(|name| {
+                Term::Compound("=".to_string(), vec![Term::Constant(name.clone()), variable(name)])
+            });
+            Term::list_from_vec(pairs.collect())
+        };
+        let mut unifications = vec![(term.clone(), rename_vars(&Term::from_tree_term(read.term), suffix))];
+        unifications.extend(self.variable_names.clone().map(|value| (value, named(&read.variable_names))));
+        unifications.extend(self.variables.clone().map(|value| (value, Term::list_from_vec(read.variables.iter().map(variable).collect()))));
+        unifications.extend(self.singletons.clone().map(|value| (value, named(&read.singletons))));
+        unifications
+    }
+
+    // At the end of the input the term read is `end_of_file`, with no variables
+    fn end_of_file(&self, term: &Term) -> Unifications {
+        let mut unifications = vec![(term.clone(), Term::Constant("end_of_file".to_string()))];
+        for value in [&self.variable_names, &self.variables, &self.singletons].into_iter().flatten() {
+            unifications.push((value.clone(), Term::EmptyList));
+        }
+        unifications
+    }
+
+    // Throws or reports a syntax error as the options ask
+    fn syntax_error(&self, error: &ParseError, messages: &mut Vec<String>) -> Result<(), Term> {
+        match self.on_error {
+            OnSyntaxError::Error => return Err(errors::syntax_error(&error.kind.to_string())),
+            OnSyntaxError::Fail | OnSyntaxError::Dec10 => messages.push(format!("Syntax error, {}", error)),
+            OnSyntaxError::Quiet => {}
+        }
+        Ok(())
+    }
+}
+
+// The outcome of reading from the start of a text
+enum Read {
+    Term(Unifications, usize), // What to unify, and the length of text the term took up
+    Incomplete,                // More text is needed to finish the term
+    SyntaxError(ParseError, usize),
+}
+
+// Reads the first term of `text`. Unless `complete` is set the text may go on later, so running out
+// of it is not an error. Variables are renamed apart with `suffix`, as the solver does for clauses.
+fn read(text: &str, ops: &Operators, term: &Term, options: &ReadOptions, suffix: usize, complete: bool) -> Read {
+    match parser::read_term(text, ops) {
+        Ok(Some(read)) => {
+            let end = read.end;
+            Read::Term(options.unifications(term, read, suffix), end)
+        }
+        Ok(None) if complete => Read::Term(options.end_of_file(term), text.len()),
+        Ok(None) => Read::Incomplete,
+        Err((error, _)) if error.kind == ErrorKind::UnexpectedEof && !complete => Read::Incomplete,
+        Err((error, end)) => Read::SyntaxError(error, end),
+    }
+}
+
+// read/1,2 and read_term/2,3: reads the next term from an input stream. Standard input is read a
+// line at a time until it holds a whole term; the rest of the line is left for the next read.
+pub fn read_stream(db: &mut Database, stream: usize, term: &Term, options: &ReadOptions, suffix: usize) -> Result<Option<Unifications>, Term> {
+    let mut at_end = false;
+    loop {
+        let text = db.streams.remaining_text(stream);
+        match read(&text, &db.operators, term, options, suffix, at_end) {
+            Read::Term(unifications, end) => {
+                db.streams.consume_text(stream, &text, end);
+                return Ok(Some(unifications));
+            }
+            Read::Incomplete => at_end = !db.streams.fill(stream),
+            Read::SyntaxError(error, end) => {
+                db.streams.consume_text(stream, &text, end);
+                options.syntax_error(&error, &mut db.messages)?;
+                if options.on_error != OnSyntaxError::Dec10 {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+// read_term_from_atom/3: reads a term from text, which needs no period at the end. Text with no
+// term in it reads as `end_of_file`.
+pub fn read_from_text(db: &mut Database, text: &str, term: &Term, options: &ReadOptions, suffix: usize) -> Result<Option<Unifications>, Term> {
+    let outcome = match read(text, &db.operators, term, options, suffix, false) {
+        Read::Incomplete => match read(text, &db.operators, term, options, suffix, true) {
+            Read::SyntaxError(_, _) => read(&format!("{} .", text), &db.operators, term, options, suffix, true),
+            empty => empty,
+        },
+        outcome => outcome,
+    };
+    match outcome {
+        Read::Term(unifications, _) => Ok(Some(unifications)),
+        Read::SyntaxError(error, _) => options.syntax_error(&error, &mut db.messages).map(|_| None),
+        Read::Incomplete => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::parse_query;
+
+    fn read_atom(text: &str, options: &str) -> Result<Option<Vec<String>>, Term> {
+        let mut db = Database::new(vec![]);
+        let options = ReadOptions::parse(&Term::from_tree_term(parse_query(options).unwrap()))?;
+        let unifications = read_from_text(&mut db, text, &Term::Variable("T".to_string()), &options, 0)?;
+        Ok(unifications.map(|pairs| pairs.iter().map(|(_, value)| value.to_string()).collect()))
+    }
+
+    #[test]
+    fn test_read_term_options() {
+        let read = read_atom("foo(X, _, 'Y', Y, X, _Z)", "[variable_names(V), variables(A), singletons(S)].").unwrap().unwrap();
+        assert!(read[0].starts_with("foo(X_0, _G"), "{}", read[0]);
+        assert_eq!(read[1], "['X'=X_0, 'Y'=Y_0, '_Z'=_Z_0]");
+        assert_eq!(read[2].matches("_0").count(), 4);
+        assert_eq!(read[3], "['Y'=Y_0, '_Z'=_Z_0]");
+        assert_eq!(read_atom("", "[].").unwrap().unwrap(), vec!["end_of_file"]);
+    }
+
+    #[test]
+    fn test_syntax_errors_option() {
+        let error = read_atom("foo(", "[].").unwrap_err();
+        assert!(matches!(&error, Term::Compound(name, args) if name == "error" && matches!(&args[0], Term::Compound(kind, _) if kind == "syntax_error")));
+        assert_eq!(read_atom("foo(", "[syntax_errors(fail)].").unwrap(), None);
+        assert_eq!(read_atom("a", "[syntax_errors(maybe)].").unwrap_err(), errors::domain_error("syntax_errors", Term::Constant("maybe".to_string())));
+        assert_eq!(read_atom("a", "[bad].").unwrap_err(), errors::domain_error("read_option", Term::Constant("bad".to_string())));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::database::{Database, Module, PredicateKey};
+use crate::errors;
+use crate::parser::operators::Operators;
+use crate::solver::{is_builtin, library_of, BUILTIN_PREDICATES};
+use crate::terms::Term;
+
+// Each function returns one goal per solution; the solver succeeds through them in turn
+
+fn atom(name: &str) -> Term {
+    Term::Constant(name.to_string())
+}
+
+fn equals(left: Term, right: Term) -> Term {
+    Term::Compound("=".to_string(), vec![left, right])
+}
+
+// Splits `M:Spec`; the module is an atom, a variable to enumerate modules, or the context module
+fn split_module<'t>(context: &str, term: &'t Term) -> (Term, &'t Term) {
+    match term {
+        Term::Compound(colon, args) if colon == ":" && args.len() == 2 => (args[0].clone(), &args[1]),
+        _ => (atom(context), term),
+    }
+}
+
+// Modules matching a module term, paired with the atom naming each
+fn matching_modules<'d>(db: &'d Database, module: &Term) -> Result<Vec<(&'d str, &'d Module)>, Term> {
+    match module {
+        Term::Variable(_) => Ok(db.modules().collect()),
+        Term::Constant(name) => Ok(db.modules().filter(|(m, _)| m == name).collect()),
+        other => Err(errors::type_error("atom", other.clone())),
+    }
+}
+
+pub fn current_predicate(db: &Database, context: &str, spec: &Term) -> Result<Vec<Term>, Term> {
+    let (module, spec) = split_module(context, spec);
+    match spec {
+        Term::Variable(_) => {}
+        Term::Compound(slash, args) if slash == "/" && args.len() == 2 => {
+            if !matches!(args[0], Term::Variable(_) | Term::Constant(_))
+                || !matches!(args[1], Term::Variable(_) | Term::Integer(_))
+            {
+                return Err(errors::type_error("predicate_indicator", spec.clone()));
+            }
+        }
+        _ => return Err(errors::type_error("predicate_indicator", spec.clone())),
+    }
+    let mut goals = vec![];
+    for (name, table) in matching_modules(db, &module)? {
+        for ((pred, arity), _) in table.predicates() {
+            let goal = equals(spec.clone(), errors::indicator(pred, *arity));
+            goals.push(Term::Compound(",".to_string(), vec![equals(module.clone(), atom(name)), goal]));
+        }
+    }
+    Ok(goals)
+}
+
+pub fn current_op(ops: &Operators, priority: &Term, kind: &Term, name: &Term) -> Result<Vec<Term>, Term> {
+    match priority {
+        Term::Variable(_) => {}
+        Term::Integer(p) if (0..=1200).contains(p) => {}
+        _ => return Err(errors::domain_error("operator_priority", priority.clone())),
+    }
+    match kind {
+        Term::Variable(_) => {}
+        Term::Constant(k) if ["xfx", "xfy", "yfx", "fy", "fx", "xf", "yf"].contains(&k.as_str()) => {}
+        _ => return Err(errors::domain_error("operator_specifier", kind.clone())),
+    }
+    if !matches!(name, Term::Variable(_) | Term::Constant(_)) {
+        return Err(errors::type_error("atom", name.clone()));
+    }
+    let pattern = Term::Compound("op".to_string(), vec![priority.clone(), kind.clone(), name.clone()]);
+    Ok(ops
+        .iter()
+        .into_iter()
+        .map(|(p, k, n)| {
+            let op = Term::Compound("op".to_string(), vec![Term::Integer(p as i64), atom(k.name()), atom(n)]);
+            equals(pattern.clone(), op)
+        })
+        .collect())
+}
+
+pub fn predicate_property(db: &Database, context: &str, head: &Term, property: &Term, counter: usize) -> Result<Vec<Term>, Term> {
+    let (module, head) = split_module(context, head);
+    let mut goals = vec![];
+    for (name, table) in matching_modules(db, &module)? {
+        let keys: Vec<PredicateKey> = match head {
+            Term::Variable(_) if name == "system" => {
+                BUILTIN_PREDICATES.iter().map(|(pred, arity)| (pred.to_string(), *arity)).collect()
+            }
+            Term::Variable(_) if name == "user" => table
+                .predicates()
+                .map(|(key, _)| key.clone())
+                .chain(BUILTIN_PREDICATES.iter().map(|(pred, arity)| (pred.to_string(), *arity)))
+                .collect(),
+            Term::Variable(_) => table.predicates().map(|(key, _)| key.clone()).collect(),
+            head => match head.predicate_key() {
+                Some(key) => vec![key],
+                None => return Err(errors::type_error("callable", head.clone())),
+            },
+        };
+
+        for key in keys {
+            let skeleton = skeleton(&key, counter);
+            for prop in properties(db, name, &key) {
+                let mut goal = equals(property.clone(), prop);
+                if let Term::Variable(_) = head {
+                    goal = Term::Compound(",".to_string(), vec![equals(head.clone(), skeleton.clone()), goal]);
+                }
+                goals.push(Term::Compound(",".to_string(), vec![equals(module.clone(), atom(name)), goal]));
+            }
+        }
+    }
+    Ok(goals)
+}
+
+fn properties(db: &Database, module: &str, key: &PredicateKey) -> Vec<Term> {
+    if is_builtin(&key.0, key.1) {
+        return match module {
+            "system" | "user" => vec![atom("built_in"), atom("defined")],
+            _ => vec![],
+        };
+    }
+    // Library predicates the program has not defined itself come from the library
+    if let Some(library) = library_of(&key.0, key.1).filter(|_| module != "system" && db.resolve(module, key).is_none()) {
+        return vec![atom("defined"), Term::Compound("imported_from".to_string(), vec![atom(library)])];
+    }
+    let Some(table) = db.module(module) else { return vec![] };
+    if let Some(source) = table.imported_from(key) {
+        let mut props = properties(db, source, key);
+        props.push(Term::Compound("imported_from".to_string(), vec![atom(source)]));
+        return props;
+    }
+    let Some((_, pred)) = table.predicates().find(|(k, _)| *k == key) else { return vec![] };
+    let mut props = vec![atom("defined")];
+    if pred.dynamic {
+        props.push(atom("dynamic"));
+    }
+    if table.exports.contains(key) {
+        props.push(atom("exported"));
+    }
+    props.push(Term::Compound("number_of_clauses".to_string(), vec![Term::Integer(pred.clauses.len() as i64)]));
+    if let Some(file) = &pred.file {
+        props.push(Term::Compound("file".to_string(), vec![atom(file)]));
+    }
+    props
+}
+
+// A most general goal for the predicate, e.g. append(_, _, _)
+fn skeleton((name, arity): &PredicateKey, counter: usize) -> Term {
+    match arity {
+        0 => atom(name),
+        _ => Term::Compound(
+            name.clone(),
+            (0..*arity).map(|i| Term::Variable(format!("_P{}_{}", counter, i))).collect(),
+        ),
+    }
+}
@@ -1,17 +1,19 @@
+use crate::parser::operators::Operators;
 use crate::terms::Term;
 use crate::unification::Substitution;
+use crate::writer::term_to_string;
 
 use std::time::Duration;
 
-pub fn get_result(query_text: &str, solution: Option<Substitution>, duration: Duration) -> String {
+// Formats the answer to a query, binding the variables named in it in the order they appear.
+// `variable_names` comes from reading the query with `parser::read_term`.
+pub fn get_result(query_text: &str, variable_names: &[String], solution: Option<Substitution>, duration: Duration, ops: &Operators) -> String {
     match solution {
         Some(subs) => {
-            let query_vars = extract_query_vars(query_text);
-
-            let results: Vec<String> = query_vars
+            let results: Vec<String> = variable_names
                 .iter()
                 .filter_map(|var| {
-                    subs.get(var).map(|term| format!("{} = {}", var, format_term(term, &subs)))
+                    subs.get(var).map(|term| format!("{} = {}", var, format_term(term, &subs, ops)))
                 })
                 .collect();
 
@@ -27,43 +29,9 @@ pub fn get_result(query_text: &str, solution: Option<Substitution>, duration: Du
     }
 }
 
-// Extract variables from query text
-fn extract_query_vars(query: &str) -> Vec<String> {
-    let re = regex::Regex::new(r"\b[A-Z_][A-Za-z0-9_]*\b").unwrap();
-    re.find_iter(query).map(|mat| mat.as_str().to_string()).collect()
-}
-
-// Ensure your format_term stays the same, as previously defined:
-fn format_term(term: &Term, subs: &Substitution) -> String {
-    match term {
-        Term::Integer(n) => n.to_string(),
-        Term::Constant(c) => c.clone(),
-        Term::Variable(v) => {
-            if let Some(resolved_term) = subs.get(v) {
-                format_term(resolved_term, subs)
-            } else {
-                v.clone()
-            }
-        }
-        Term::Compound(name, args) => {
-            let args_str: Vec<String> = args.iter().map(|arg| format_term(arg, subs)).collect();
-            format!("{}({})", name, args_str.join(", "))
-        }
-        Term::List(head, tail) => {
-            let mut items = vec![format_term(head, subs)];
-            let mut current_tail = tail.as_ref();
-            while let Term::List(head, next_tail) = current_tail {
-                items.push(format_term(head, subs));
-                current_tail = next_tail.as_ref();
-            }
-            if let Term::EmptyList = current_tail {
-                format!("[{}]", items.join(", "))
-            } else {
-                format!("[{} | {}]", items.join(", "), format_term(current_tail, subs))
-            }
-        }
-        Term::EmptyList => "[]".to_string(),
-    }
+// Writes a binding with the operators of the database the query ran against
+fn format_term(term: &Term, subs: &Substitution, ops: &Operators) -> String {
+    term_to_string(&subs.apply(term), ops)
 }
 
 #[cfg(test)]
@@ -73,13 +41,17 @@ mod tests {
     use crate::unification::Substitution;
     use std::time::Duration;
 
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
     #[test]
     fn test_result_with_variable_binding() {
         let query = "?- X = 1.";
         let mut subs = Substitution::new();
         subs.extend("X".to_string(), Term::Integer(1));
 
-        let result = get_result(query, Some(subs), Duration::from_millis(5));
+        let result = get_result(query, &names(&["X"]), Some(subs), Duration::from_millis(5), &Operators::default());
         assert_eq!(result, "?- X = 1. => X = 1");
     }
 
@@ -90,7 +62,7 @@ mod tests {
         subs.extend("X".to_string(), Term::Integer(1));
         subs.extend("Y".to_string(), Term::Constant("foo".to_string()));
 
-        let result = get_result(query, Some(subs), Duration::from_millis(50));
+        let result = get_result(query, &names(&["X", "Y"]), Some(subs), Duration::from_millis(50), &Operators::default());
         assert!(
             result.starts_with("?- X = 1, Y = foo. => X = 1, Y = foo"),
             "Expected formatted output with bindings"
@@ -103,14 +75,14 @@ mod tests {
         let query = "?- foo(bar).";
         let subs = Substitution::new();
 
-        let result = get_result(query, Some(subs), Duration::from_millis(2));
+        let result = get_result(query, &[], Some(subs), Duration::from_millis(2), &Operators::default());
         assert_eq!(result, "?- foo(bar). => true");
     }
 
     #[test]
     fn test_result_with_no_solution() {
         let query = "?- X = 1.";
-        let result = get_result(query, None, Duration::from_millis(0));
+        let result = get_result(query, &names(&["X"]), None, Duration::from_millis(0), &Operators::default());
         assert_eq!(result, "?- X = 1. => false");
     }
 
@@ -130,7 +102,7 @@ mod tests {
         );
         subs.extend("X".to_string(), list);
 
-        let result = get_result(query, Some(subs), Duration::from_millis(3));
+        let result = get_result(query, &names(&["X"]), Some(subs), Duration::from_millis(3), &Operators::default());
         assert!(result.contains("[1, 2, 3]"));
     }
 }
@@ -1,166 +1,1235 @@
-use crate::database::Database;
+use std::cmp::Ordering;
+
+use crate::database::{Database, ModuleName, PredicateKey, parse_indicator, parse_indicator_list};
 use crate::terms::{Clause, Term, Expression};
 use crate::unification::{Substitution, unify};
-use crate::backtracking::{BacktrackingStack, ChoicePoint};
+use crate::backtracking::{Alternatives, BacktrackingStack, ChoicePoint, ClauseIteration, ClauseMode, Continuation};
 use crate::environment::Environment;
+use crate::errors;
+use crate::reflection;
+use crate::format::{self, Sink};
+use crate::read::{self, ReadOptions};
+use crate::streams::{self, stream_term, StreamOptions};
+use crate::writer::{WriteOptions, Writer};
+use crate::consult;
+use crate::dcg;
 use crate::builtins::*;
+use crate::lists;
+use crate::apply;
+use crate::assoc;
+use crate::ordsets;
+use crate::pairs;
+use crate::globals;
+use crate::flags::Unknown;
+
+// Predicates handled directly by `solve_term`, reported as `built_in` by predicate_property/2
+pub const BUILTIN_PREDICATES: [(&str, usize); 157] = [
+    ("true", 0), ("fail", 0), ("false", 0), ("!", 0), (",", 2), (";", 2), ("->", 2), ("\\+", 1),
+    ("not", 1), ("once", 1), ("ignore", 1),
+    ("call", 1), ("call", 2), ("call", 3), ("call", 4), ("call", 5), ("call", 6), ("call", 7), ("call", 8),
+    ("findall", 3), ("forall", 2), ("catch", 3), ("throw", 1), (":", 2), ("use_module", 1), ("use_module", 2),
+    ("consult", 1), ("ensure_loaded", 1), ("phrase", 2), ("phrase", 3), ("dcg_translate_rule", 2),
+    ("read", 1), ("read_term", 2), ("read_term", 3), ("read_term_from_atom", 3),
+    ("write", 1), ("print", 1), ("writeq", 1), ("write_canonical", 1), ("write_term", 2),
+    ("format", 1), ("format", 2), ("format", 3),
+    ("read", 2), ("write", 2), ("print", 2), ("writeq", 2), ("write_canonical", 2), ("write_term", 3),
+    ("nl", 0), ("nl", 1), ("tab", 1), ("tab", 2), ("flush_output", 0), ("flush_output", 1),
+    ("put_char", 1), ("put_char", 2), ("put_code", 1), ("put_code", 2), ("put_byte", 1), ("put_byte", 2),
+    ("get_char", 1), ("get_char", 2), ("peek_char", 1), ("peek_char", 2), ("get_code", 1), ("get_code", 2),
+    ("peek_code", 1), ("peek_code", 2), ("get_byte", 1), ("get_byte", 2), ("peek_byte", 1), ("peek_byte", 2),
+    ("at_end_of_stream", 0), ("at_end_of_stream", 1), ("open", 3), ("open", 4), ("close", 1), ("close", 2),
+    ("current_input", 1), ("current_output", 1), ("set_input", 1), ("set_output", 1),
+    ("stream_property", 2), ("with_output_to", 2),
+    ("var", 1), ("nonvar", 1), ("atom", 1), ("number", 1), ("integer", 1), ("atomic", 1), ("compound", 1),
+    ("callable", 1), ("is_list", 1),
+    ("=", 2), ("\\=", 2), ("is", 2), ("<", 2), (">", 2), ("=<", 2), (">=", 2), ("=:=", 2), ("=\\=", 2),
+    ("assert", 1), ("asserta", 1), ("assertz", 1), ("retract", 1), ("retractall", 1), ("abolish", 1),
+    ("dynamic", 1), ("clause", 2), ("current_predicate", 1), ("current_op", 3), ("op", 3), ("predicate_property", 2),
+    ("between", 3), ("succ", 2), ("plus", 3), ("min", 3), ("max", 3), ("length", 2),
+    ("compare", 3), ("==", 2), ("\\==", 2), ("@<", 2), ("@=<", 2), ("@>", 2), ("@>=", 2),
+    ("sort", 2), ("msort", 2), ("keysort", 2), ("sort", 4), ("predsort", 3),
+    ("b_setval", 2), ("b_getval", 2), ("nb_setval", 2), ("nb_getval", 2), ("recorda", 2), ("recorda", 3),
+    ("recordz", 2), ("recordz", 3), ("recorded", 2), ("recorded", 3), ("erase", 1), ("flag", 3),
+    ("term_variables", 2), ("term_hash", 2), ("term_hash", 4), ("=@=", 2), ("\\=@=", 2), ("subsumes_term", 2),
+    ("term_subsumer", 3), ("numbervars", 3), ("set_prolog_flag", 2), ("current_prolog_flag", 2),
+    ("acyclic_term", 1), ("cyclic_term", 1),
+];
+
+pub fn is_builtin(name: &str, arity: usize) -> bool {
+    BUILTIN_PREDICATES.contains(&(name, arity))
+}
+
+// Library predicates, as (library, name, arity). They are available without loading the library,
+// and a program that defines a predicate of the same name and arity uses its own clauses instead.
+pub const LIBRARY_PREDICATES: [(&str, &str, usize); 66] = [
+    ("lists", "append", 3), ("lists", "member", 2), ("lists", "memberchk", 2), ("lists", "select", 3),
+    ("lists", "select", 4), ("lists", "selectchk", 3), ("lists", "nth0", 3), ("lists", "nth1", 3),
+    ("lists", "nth0", 4), ("lists", "nth1", 4), ("lists", "reverse", 2), ("lists", "last", 2),
+    ("lists", "list_to_set", 2), ("lists", "sum_list", 2), ("lists", "max_list", 2), ("lists", "min_list", 2),
+    ("lists", "max_member", 2), ("lists", "min_member", 2), ("lists", "max_member", 3), ("lists", "min_member", 3),
+    ("lists", "delete", 3), ("lists", "subtract", 3), ("lists", "intersection", 3), ("lists", "union", 3),
+    ("lists", "permutation", 2), ("lists", "flatten", 2), ("lists", "numlist", 3),
+    ("lists", "proper_length", 2),
+    ("apply", "maplist", 2), ("apply", "maplist", 3), ("apply", "maplist", 4), ("apply", "maplist", 5),
+    ("apply", "maplist", 6), ("apply", "maplist", 7), ("apply", "foldl", 4), ("apply", "foldl", 5),
+    ("apply", "foldl", 6), ("apply", "include", 3), ("apply", "exclude", 3), ("apply", "partition", 4),
+    ("assoc", "empty_assoc", 1), ("assoc", "put_assoc", 4), ("assoc", "get_assoc", 3), ("assoc", "list_to_assoc", 2),
+    ("assoc", "assoc_to_list", 2), ("assoc", "assoc_to_keys", 2), ("assoc", "assoc_to_values", 2),
+    ("assoc", "max_assoc", 3), ("assoc", "min_assoc", 3),
+    ("ordsets", "is_ordset", 1), ("ordsets", "ord_empty", 1), ("ordsets", "list_to_ord_set", 2),
+    ("ordsets", "ord_union", 2), ("ordsets", "ord_union", 3), ("ordsets", "ord_intersection", 3),
+    ("ordsets", "ord_subtract", 3), ("ordsets", "ord_symdiff", 3), ("ordsets", "ord_memberchk", 2),
+    ("ordsets", "ord_subset", 2), ("ordsets", "ord_add_element", 3), ("ordsets", "ord_del_element", 3),
+    ("pairs", "pairs_keys_values", 3), ("pairs", "pairs_keys", 2), ("pairs", "pairs_values", 2),
+    ("pairs", "transpose_pairs", 2), ("pairs", "map_list_to_pairs", 3),
+];
 
-pub fn solve(query: &Expression, db: &Database, back_stack: &mut BacktrackingStack, counter: &mut usize) -> Option<Substitution> {
-    println!("DEBUG: solve() called with query {:?}", query);
+// The library defining a predicate, if any
+pub fn library_of(name: &str, arity: usize) -> Option<&'static str> {
+    LIBRARY_PREDICATES.iter().find(|(_, pred, n)| *pred == name && *n == arity).map(|(library, _, _)| *library)
+}
+
+// Finds the first solution of a query
+pub fn solve(query: &Expression, db: &mut Database) -> Option<Substitution> {
+    Solver::new(query, db).next_solution().ok().flatten()
+}
+
+pub struct Solver<'a> {
+    db: &'a mut Database,
+    subs: Substitution,
+    goals: Continuation,
+    back_stack: BacktrackingStack,
+    counter: usize,
+    query_vars: Vec<String>,
+    started: bool,
+    module: ModuleName, // Context module of the goal being solved
+    b_values: Vec<(String, Term, String)>, // b_setval/2 assignments: name, value and marker variable
+}
 
-    match query {
-        Expression::Term(term) => {
-            let result = solve_term(term, db, back_stack, counter);
-            println!("DEBUG: solve_term returned {:?}", result);
-            return result;
+impl<'a> Solver<'a> {
+    pub fn new(query: &Expression, db: &'a mut Database) -> Self {
+        let query = query.to_term();
+        let mut subs = Substitution::new();
+        subs.occurs_check = db.flags.occurs_check;
+        Solver {
+            query_vars: query.variables(),
+            goals: Continuation::done().push(query, 0, "user".into()),
+            db,
+            subs,
+            back_stack: BacktrackingStack::new(),
+            counter: 0,
+            started: false,
+            module: "user".into(),
+            b_values: Vec::new(),
         }
-        Expression::Conjunct(lhs, rhs) => {
-            println!("DEBUG: Solving LHS: {:?}", lhs);
-            if let Some(lhs_subs) = solve(lhs, db, back_stack, counter) {
-                let applied_rhs = rhs.apply(&lhs_subs);
-                println!("DEBUG: LHS succeeded, applying to RHS: {:?}", applied_rhs);
+    }
 
-                if let Some(rhs_subs) = solve(&applied_rhs, db, back_stack, counter) {
-                    println!("DEBUG: RHS succeeded, merging substitutions");
-                    return lhs_subs.merge(&rhs_subs);
-                }
+    // Runs the query until its next solution, backtracking into the previous one if there was one.
+    // `Err` carries an exception that no catch/3 handled.
+    pub fn next_solution(&mut self) -> Result<Option<Substitution>, Term> {
+        if self.started && !self.backtrack() {
+            return Ok(None);
+        }
+        self.started = true;
+
+        loop {
+            let (goal, cut_barrier, module, rest) = match self.goals.pop() {
+                Some(frame) => (frame.goal.clone(), frame.cut_barrier, frame.module.clone(), frame.next.clone()),
+                None => return Ok(Some(self.answer())),
+            };
+            self.goals = rest;
+            self.module = module;
+
+            let succeeded = match self.solve_term(&goal, cut_barrier) {
+                Ok(succeeded) => match self.subs.take_occurs_error() {
+                    Some(ball) => self.recover(ball)?,
+                    None => succeeded,
+                },
+                Err(ball) => self.recover(ball)?,
+            };
+            if !succeeded && !self.backtrack() {
+                return Ok(None);
             }
-            while let Some(choice) = back_stack.pop() {
-                println!("DEBUG: Backtracking to {:?}", choice.alternatives[0]);
-                return solve(&Expression::Term(choice.alternatives[0].clone()), db, back_stack, counter);
+        }
+    }
+
+    // Bindings of the query's own variables in the current solution
+    fn answer(&self) -> Substitution {
+        let mut answer = self.subs.empty_like();
+        for var in self.query_vars.iter().filter(|var| !var.starts_with('_')) {
+            let value = self.subs.apply(&Term::Variable(var.clone()));
+            if value != Term::Variable(var.clone()) {
+                answer.extend(var.clone(), value);
             }
-            println!("DEBUG: Conjunct failed, returning None");
-            None
         }
+        answer
     }
-}
 
-fn solve_term(term: &Term, db: &Database, back_stack: &mut BacktrackingStack, counter: &mut usize) -> Option<Substitution> {
-    println!("DEBUG: solve_term() called with term {:?}", term);
-    let mut subs = Substitution::new();
-
-    if let Term::Compound(name, args) = term {
-        if name == "is" && args.len() == 2 {
-            println!("Evaluating: {:?} is {:?}", args[0], args[1]);
-        
-            let left_resolved = subs.resolve(&args[0]);  // Ensure left is evaluated
-            let right_value = evaluate_arithmetic(&args[1]);
-        
-            match (left_resolved, right_value) {
-                (Term::Variable(var), Some(value)) => {
-                    let mut result = Substitution::new();
-                    result.extend(var.clone(), Term::Integer(value));
-                    println!("DEBUG: Variable assigned, returning {:?}", result);
-                    return Some(result);
-                }
-                (Term::Integer(left_value), Some(right_value)) => {
-                    if left_value == right_value {
-                        println!("DEBUG: Arithmetic expression is already satisfied: {} = {}", left_value, right_value);
-                        return Some(Substitution::new());
+    // Resumes the most recent choice point that still has an alternative left
+    fn backtrack(&mut self) -> bool {
+        while let Some(choice) = self.back_stack.pop() {
+            self.subs.undo_to(choice.env.trail_mark);
+            self.goals = choice.goals.clone();
+            self.module = choice.module.clone();
+
+            match choice.alternatives {
+                Alternatives::Goals(mut goals) => {
+                    if goals.is_empty() {
+                        continue;
+                    }
+                    let goal = goals.remove(0);
+                    if !goals.is_empty() {
+                        self.back_stack.push(ChoicePoint {
+                            env: choice.env,
+                            goals: choice.goals.clone(),
+                            cut_barrier: choice.cut_barrier,
+                            module: choice.module.clone(),
+                            alternatives: Alternatives::Goals(goals),
+                        });
                     }
+                    self.goals = choice.goals.push(goal, choice.cut_barrier, choice.module);
+                    return true;
                 }
-                _ => {}
+                Alternatives::Clauses(iteration) => {
+                    if self.resume_clauses(iteration) {
+                        return true;
+                    }
+                }
+                Alternatives::Catch(_, _) => {}
             }
-        
-            println!("DEBUG: Evaluation failed, returning None");
-            return None;
         }
+        false
+    }
 
-        if RELATIONAL_OPERATORS.contains(&name.as_str()) && args.len() == 2 {
-            let relation_result = evaluate_relation(name, &args[0], &args[1]);
-            println!("DEBUG: Evaluating relation {:?} {} {:?} -> {:?}", args[0], name, args[1], relation_result);
-            return match relation_result {
-                Some(true) => Some(subs),
-                _ => None,
-            };            
+    // Unwinds to the innermost catch/3 whose catcher unifies with the ball
+    fn recover(&mut self, ball: Term) -> Result<bool, Term> {
+        while let Some(choice) = self.back_stack.pop() {
+            if let Alternatives::Catch(catcher, recovery) = choice.alternatives {
+                self.subs.undo_to(choice.env.trail_mark);
+                if unify(&catcher, &ball, &mut self.subs) {
+                    self.module = choice.module.clone();
+                    self.goals = choice.goals.push(recovery, choice.cut_barrier, choice.module);
+                    return Ok(true);
+                }
+            }
         }
+        self.subs.undo_to(0);
+        Err(ball)
+    }
 
-        if name == "append" && args.len() == 3 {
-            println!("DEBUG: Calling built-in append");
-            return builtin_append(args);
-        }
-        if name == "member" && args.len() == 2 {
-            println!("DEBUG: Calling built-in member");
-            return builtin_member(args);
-        }
-        if name == "between" && args.len() == 3 {
-            return builtin_between(args);
-        }
-        if name == "succ" && args.len() == 2 {
-            return builtin_succ(args)
-        }
-        if name == "min" && args.len() == 3 {
-            return builtin_min(args)
+    fn push_alternatives(&mut self, alternatives: Vec<Term>, cut_barrier: usize) {
+        self.back_stack.push(ChoicePoint {
+            env: Environment::at(self.subs.mark()),
+            goals: self.goals.clone(),
+            cut_barrier,
+            module: self.module.clone(),
+            alternatives: Alternatives::Goals(alternatives),
+        });
+    }
+
+    fn push_goal(&mut self, goal: Term, cut_barrier: usize) {
+        self.goals = self.goals.push(goal, cut_barrier, self.module.clone());
+    }
+
+    // Succeeds through each goal in turn, leaving a choice point for the ones not yet tried
+    fn try_each(&mut self, goals: Vec<Term>, cut_barrier: usize) -> bool {
+        let mut goals = goals.into_iter();
+        let Some(first) = goals.next() else { return false };
+        let rest: Vec<Term> = goals.collect();
+        if !rest.is_empty() {
+            self.push_alternatives(rest, cut_barrier);
         }
-        if name == "max" && args.len() == 3 {
-            return builtin_max(args)
+        self.push_goal(first, cut_barrier);
+        true
+    }
+
+    fn push_body(&mut self, body: &Expression, cut_barrier: usize) {
+        match body {
+            Expression::Term(term) => self.push_goal(term.clone(), cut_barrier),
+            Expression::Conjunct(lhs, rhs) => {
+                self.push_body(rhs, cut_barrier);
+                self.push_body(lhs, cut_barrier);
+            }
         }
-        if name == "reverse" && args.len() == 2 {
-            return builtin_reverse(args)
+    }
+
+    // Unifies the pairs of terms a builtin produced; None means the builtin failed
+    fn unify_all(&mut self, pairs: Option<Vec<(Term, Term)>>) -> bool {
+        pairs.is_some_and(|pairs| pairs.iter().all(|(left, right)| unify(left, right, &mut self.subs)))
+    }
+
+    fn solve_term(&mut self, term: &Term, cut_barrier: usize) -> Result<bool, Term> {
+        let term = self.subs.walk(term).clone();
+        let (name, args) = match &term {
+            Term::Constant(name) => (name.as_str(), &[][..]),
+            Term::Compound(name, args) => (name.as_str(), args.as_slice()),
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            // `[file1, file2]` consults the files
+            Term::List(_, _) => ("consult", std::slice::from_ref(&term)),
+            _ => return Err(errors::type_error("callable", term.clone())),
+        };
+
+        match (name, args.len()) {
+            ("true", 0) => Ok(true),
+            ("fail", 0) | ("false", 0) => Ok(false),
+            ("!", 0) => {
+                self.back_stack.cut(cut_barrier);
+                Ok(true)
+            }
+            ("$cut", 1) => {
+                if let Term::Integer(height) = args[0] {
+                    self.back_stack.cut(height as usize);
+                }
+                Ok(true)
+            }
+            (",", 2) => {
+                self.push_goal(args[1].clone(), cut_barrier);
+                self.push_goal(args[0].clone(), cut_barrier);
+                Ok(true)
+            }
+            (";", 2) => {
+                let height = self.back_stack.len();
+                self.push_alternatives(vec![args[1].clone()], cut_barrier);
+                match self.subs.walk(&args[0]).clone() {
+                    Term::Compound(arrow, cond) if arrow == "->" && cond.len() == 2 => {
+                        // If-then-else: once the condition succeeds, the else branch is cut away
+                        self.push_goal(cond[1].clone(), cut_barrier);
+                        self.push_goal(cut_to(height), cut_barrier);
+                        self.push_goal(cond[0].clone(), height + 1);
+                    }
+                    either => self.push_goal(either, cut_barrier),
+                }
+                Ok(true)
+            }
+            ("->", 2) => {
+                let height = self.back_stack.len();
+                self.push_goal(args[1].clone(), cut_barrier);
+                self.push_goal(cut_to(height), cut_barrier);
+                self.push_goal(args[0].clone(), height);
+                Ok(true)
+            }
+            ("\\+", 1) => {
+                // The `true` alternative is only reached if the goal fails
+                let height = self.back_stack.len();
+                self.push_alternatives(vec![Term::Constant("true".to_string())], cut_barrier);
+                self.push_goal(Term::Constant("fail".to_string()), cut_barrier);
+                self.push_goal(cut_to(height), cut_barrier);
+                self.push_goal(args[0].clone(), height + 1);
+                Ok(true)
+            }
+            ("not", 1) => {
+                self.push_goal(Term::Compound("\\+".to_string(), vec![args[0].clone()]), cut_barrier);
+                Ok(true)
+            }
+            ("once", 1) | ("ignore", 1) => {
+                // once(G) runs as (G -> true), and ignore(G) as (G -> true ; true)
+                let once = Term::Compound("->".to_string(), vec![args[0].clone(), Term::Constant("true".to_string())]);
+                let goal = match name {
+                    "once" => once,
+                    _ => Term::Compound(";".to_string(), vec![once, Term::Constant("true".to_string())]),
+                };
+                self.push_goal(goal, cut_barrier);
+                Ok(true)
+            }
+            ("call", n) if n >= 1 => {
+                let goal = add_args(self.subs.apply(&args[0]), &args[1..])?;
+                self.push_goal(goal, self.back_stack.len());
+                Ok(true)
+            }
+            ("phrase", 2) | ("phrase", 3) => {
+                let body = self.subs.apply(&args[0]);
+                let input = self.subs.apply(&args[1]);
+                if matches!(body, Term::Variable(_)) {
+                    return Err(errors::instantiation_error());
+                }
+                if !is_partial_list(&input) {
+                    return Err(errors::type_error("list", input));
+                }
+                let rest = args.get(2).cloned().unwrap_or(Term::EmptyList);
+                let goal = dcg::translate_body(&body, input, rest, self.counter)?;
+                self.counter += 1;
+                self.push_goal(goal, self.back_stack.len());
+                Ok(true)
+            }
+            ("dcg_translate_rule", 2) => match self.subs.apply(&args[0]) {
+                Term::Compound(arrow, rule) if arrow == "-->" && rule.len() == 2 => {
+                    let clause = dcg::translate_rule(&rule[0], &rule[1])?;
+                    Ok(unify(&args[1], &rename_vars(&clause, self.counter), &mut self.subs))
+                }
+                Term::Variable(_) => Err(errors::instantiation_error()),
+                other => Err(errors::type_error("grammar_rule", other)),
+            },
+            ("read", 1) | ("read", 2) | ("read_term", 2) | ("read_term", 3) => {
+                let full = if name == "read" { 2 } else { 3 };
+                let stream = self.io_stream(args, full, false, false)?;
+                let term = &args[args.len() + 1 - full];
+                let options = match name {
+                    "read" => Term::EmptyList,
+                    _ => self.subs.apply(&args[args.len() - 1]),
+                };
+                let options = ReadOptions::parse(&options)?;
+                let unifications = read::read_stream(self.db, stream, term, &options, self.counter)?;
+                self.counter += 1;
+                Ok(self.unify_all(unifications))
+            }
+            ("read_term_from_atom", 3) => {
+                let text = match self.subs.apply(&args[0]) {
+                    Term::Variable(_) => return Err(errors::instantiation_error()),
+                    Term::Constant(text) => text,
+                    other => return Err(errors::type_error("atom", other)),
+                };
+                let options = ReadOptions::parse(&self.subs.apply(&args[2]))?;
+                let unifications = read::read_from_text(self.db, &text, &args[1], &options, self.counter)?;
+                self.counter += 1;
+                Ok(self.unify_all(unifications))
+            }
+            ("write", 1) | ("print", 1) | ("writeq", 1) | ("write_canonical", 1) | ("write_term", 2)
+            | ("write", 2) | ("print", 2) | ("writeq", 2) | ("write_canonical", 2) | ("write_term", 3) => {
+                let full = if name == "write_term" { 3 } else { 2 };
+                let stream = self.io_stream(args, full, true, false)?;
+                let options = match name {
+                    "write" => WriteOptions::write(),
+                    "print" => WriteOptions::print(),
+                    "writeq" => WriteOptions::writeq(),
+                    "write_canonical" => WriteOptions::canonical(),
+                    _ => WriteOptions::parse(&self.subs.apply(&args[args.len() - 1]))?,
+                };
+                let text = self.write_term(&args[args.len() + 1 - full], options);
+                self.write_text(stream, &text)?;
+                Ok(true)
+            }
+            ("nl", 0) | ("nl", 1) => {
+                let stream = self.io_stream(args, 1, true, false)?;
+                self.write_text(stream, "\n")?;
+                Ok(true)
+            }
+            ("tab", 1) | ("tab", 2) => {
+                let stream = self.io_stream(args, 2, true, false)?;
+                let count = match self.subs.apply(&args[args.len() - 1]) {
+                    Term::Variable(_) => return Err(errors::instantiation_error()),
+                    expression => evaluate_arithmetic(&expression)?
+                        .ok_or_else(|| errors::type_error("evaluable", expression.clone()))?,
+                };
+                self.write_text(stream, &" ".repeat(count.max(0) as usize))?;
+                Ok(true)
+            }
+            ("flush_output", 0) | ("flush_output", 1) => {
+                let stream = self.io_stream(args, 1, true, false)?;
+                self.db.streams.flush(stream)?;
+                Ok(true)
+            }
+            ("put_char", 1) | ("put_char", 2) | ("put_code", 1) | ("put_code", 2) => {
+                let stream = self.io_stream(args, 2, true, false)?;
+                let ch = match (name, self.subs.apply(&args[args.len() - 1])) {
+                    (_, Term::Variable(_)) => return Err(errors::instantiation_error()),
+                    ("put_char", Term::Constant(ch)) if ch.chars().count() == 1 => ch,
+                    ("put_char", other) => return Err(errors::type_error("character", other)),
+                    (_, Term::Integer(code)) => u32::try_from(code).ok().and_then(char::from_u32)
+                        .ok_or_else(|| errors::representation_error("character_code"))?
+                        .to_string(),
+                    (_, other) => return Err(errors::type_error("integer", other)),
+                };
+                self.write_text(stream, &ch)?;
+                Ok(true)
+            }
+            ("put_byte", 1) | ("put_byte", 2) => {
+                let stream = self.io_stream(args, 2, true, true)?;
+                let byte = match self.subs.apply(&args[args.len() - 1]) {
+                    Term::Variable(_) => return Err(errors::instantiation_error()),
+                    Term::Integer(byte) if (0..=255).contains(&byte) => byte as u8,
+                    other => return Err(errors::type_error("byte", other)),
+                };
+                self.db.streams.write_byte(stream, byte)?;
+                Ok(true)
+            }
+            ("get_char", 1) | ("get_char", 2) | ("peek_char", 1) | ("peek_char", 2)
+            | ("get_code", 1) | ("get_code", 2) | ("peek_code", 1) | ("peek_code", 2)
+            | ("get_byte", 1) | ("get_byte", 2) | ("peek_byte", 1) | ("peek_byte", 2) => {
+                let binary = name.ends_with("byte");
+                let stream = self.io_stream(args, 2, false, binary)?;
+                let target = &args[args.len() - 1];
+                match (name.ends_with("char"), self.subs.apply(target)) {
+                    (_, Term::Variable(_)) => {}
+                    (true, Term::Constant(ch)) if ch.chars().count() == 1 || ch == "end_of_file" => {}
+                    (true, other) => return Err(errors::type_error("in_character", other)),
+                    (false, Term::Integer(_)) => {}
+                    (false, other) => return Err(errors::type_error(if binary { "in_byte" } else { "integer" }, other)),
+                }
+                let value = self.db.streams.get(stream, name.starts_with("peek"), binary)?;
+                let value = match (name.ends_with("char"), value) {
+                    (true, Some(ch)) => Term::Constant(char::from_u32(ch).unwrap_or(char::REPLACEMENT_CHARACTER).to_string()),
+                    (true, None) => Term::Constant("end_of_file".to_string()),
+                    (false, value) => Term::Integer(value.map_or(-1, i64::from)),
+                };
+                Ok(unify(target, &value, &mut self.subs))
+            }
+            ("at_end_of_stream", 0) | ("at_end_of_stream", 1) => {
+                let stream = match args.first() {
+                    Some(stream) => self.db.streams.lookup(&self.subs.apply(stream))?,
+                    None => self.db.streams.input,
+                };
+                Ok(self.db.streams.at_end(stream))
+            }
+            ("open", 3) | ("open", 4) => {
+                let mode = streams::Mode::parse(&self.subs.apply(&args[1]))?;
+                let options = match args.get(3) {
+                    Some(options) => StreamOptions::parse(&self.subs.apply(options))?,
+                    None => StreamOptions::parse(&Term::EmptyList)?,
+                };
+                match self.subs.apply(&args[2]) {
+                    Term::Variable(_) => {}
+                    other => return Err(errors::uninstantiation_error(other)),
+                }
+                let stream = self.db.streams.open(&self.subs.apply(&args[0]), mode, options)?;
+                Ok(unify(&args[2], &stream_term(stream), &mut self.subs))
+            }
+            ("close", 1) | ("close", 2) => {
+                let stream = self.db.streams.lookup(&self.subs.apply(&args[0]))?;
+                self.db.streams.close(stream)?;
+                Ok(true)
+            }
+            ("current_input", 1) | ("current_output", 1) => {
+                let current = match name {
+                    "current_input" => self.db.streams.input,
+                    _ => self.db.streams.output,
+                };
+                match self.subs.apply(&args[0]) {
+                    Term::Variable(_) => {}
+                    Term::Compound(name, _) if name == "$stream" => {}
+                    other => return Err(errors::domain_error("stream", other)),
+                }
+                Ok(unify(&args[0], &stream_term(current), &mut self.subs))
+            }
+            ("set_input", 1) => {
+                self.db.streams.input = self.db.streams.input_stream(&self.subs.apply(&args[0]), false)?;
+                Ok(true)
+            }
+            ("set_output", 1) => {
+                self.db.streams.output = self.db.streams.output_stream(&self.subs.apply(&args[0]), false)?;
+                Ok(true)
+            }
+            ("stream_property", 2) => {
+                let ids = match self.subs.apply(&args[0]) {
+                    Term::Variable(_) => self.db.streams.ids(),
+                    stream @ Term::Compound(..) => vec![self.db.streams.lookup(&stream)?],
+                    other => return Err(errors::domain_error("stream", other)),
+                };
+                let mut alternatives = vec![];
+                for id in ids {
+                    for property in self.db.streams.properties(id) {
+                        alternatives.push(Term::Compound(",".to_string(), vec![
+                            Term::Compound("=".to_string(), vec![args[0].clone(), stream_term(id)]),
+                            Term::Compound("=".to_string(), vec![args[1].clone(), property]),
+                        ]));
+                    }
+                }
+                Ok(self.try_each(alternatives, cut_barrier))
+            }
+            ("with_output_to", 2) => {
+                let sink = match Sink::parse(&self.subs.apply(&args[0]))? {
+                    Sink::Output | Sink::Stream(_) => return Err(errors::domain_error("output_sink", self.subs.apply(&args[0]))),
+                    sink => sink,
+                };
+                // The goal runs once, as once/1 would; its output is only kept if it succeeds
+                let capture = self.db.streams.begin_capture();
+                let found = self.find_solutions(&args[1], &args[1], 1);
+                let text = self.db.streams.end_capture(capture);
+                let Some(instance) = found?.pop() else { return Ok(false) };
+                let (term, value) = sink.result(&text).unwrap();
+                Ok(unify(&args[1], &instance, &mut self.subs) && unify(&term, &value, &mut self.subs))
+            }
+            ("format", 1) | ("format", 2) | ("format", 3) => {
+                let (sink, directives, arguments) = match args.len() {
+                    1 => (Sink::Output, &args[0], Term::EmptyList),
+                    2 => (Sink::Output, &args[0], self.subs.apply(&args[1])),
+                    _ => (Sink::parse(&self.subs.apply(&args[0]))?, &args[1], self.subs.apply(&args[2])),
+                };
+                let stream = match &sink {
+                    Sink::Output => Some(self.db.streams.output),
+                    Sink::Stream(stream) => Some(self.db.streams.output_stream(stream, false)?),
+                    _ => None,
+                };
+                // Column stops count from the start of the line the stream is on
+                let column = stream.map_or(0, |stream| self.db.streams.column(stream));
+                let directives = self.subs.apply(directives);
+                let text = format::format(&directives, &arguments, column, &mut |term, options| self.write_term(term, options))?;
+                match (stream, sink.result(&text)) {
+                    (Some(stream), _) => {
+                        self.write_text(stream, &text)?;
+                        Ok(true)
+                    }
+                    (None, Some((term, value))) => Ok(unify(&term, &value, &mut self.subs)),
+                    (None, None) => Ok(true),
+                }
+            }
+            ("findall", 3) => {
+                let results = self.find_all(&args[0], &args[1])?;
+                Ok(unify(&args[2], &Term::list_from_vec(results), &mut self.subs))
+            }
+            ("forall", 2) => {
+                let check = Term::Compound(",".to_string(), vec![
+                    args[0].clone(),
+                    Term::Compound("\\+".to_string(), vec![args[1].clone()]),
+                ]);
+                self.push_goal(Term::Compound("\\+".to_string(), vec![check]), cut_barrier);
+                Ok(true)
+            }
+            ("catch", 3) => {
+                let height = self.back_stack.len();
+                self.back_stack.push(ChoicePoint {
+                    env: Environment::at(self.subs.mark()),
+                    goals: self.goals.clone(),
+                    cut_barrier,
+                    module: self.module.clone(),
+                    alternatives: Alternatives::Catch(args[1].clone(), args[2].clone()),
+                });
+                self.push_goal(Term::Compound("$exit_catch".to_string(), vec![Term::Integer(height as i64)]), cut_barrier);
+                self.push_goal(args[0].clone(), height + 1);
+                Ok(true)
+            }
+            ("$exit_catch", 1) => {
+                // A goal that left no choice points is finished, so its catch/3 is no longer active
+                if let Term::Integer(height) = args[0] {
+                    if self.back_stack.len() == height as usize + 1 {
+                        self.back_stack.pop();
+                    }
+                }
+                Ok(true)
+            }
+            (":", 2) => {
+                let (module, goal) = self.strip_module(&term)?;
+                self.goals = self.goals.push(goal, cut_barrier, module);
+                Ok(true)
+            }
+            ("use_module", 1) | ("use_module", 2) => {
+                let only = match args.get(1) {
+                    Some(imports) => Some(parse_indicator_list(&self.subs.apply(imports))?),
+                    None => None,
+                };
+                let module = self.module.clone();
+                consult::use_module(self.db, &module, &self.subs.apply(&args[0]), only.as_deref())?;
+                Ok(true)
+            }
+            ("consult", 1) => {
+                let spec = self.subs.apply(&args[0]);
+                match spec.to_vec() {
+                    Some(files) => files.iter().try_for_each(|file| consult::consult(self.db, file).map(|_| ()))?,
+                    None => { consult::consult(self.db, &spec)?; },
+                }
+                Ok(true)
+            }
+            ("ensure_loaded", 1) => {
+                consult::ensure_loaded(self.db, &self.subs.apply(&args[0]))?;
+                Ok(true)
+            }
+            ("throw", 1) => match self.subs.apply(&args[0]) {
+                Term::Variable(_) => Err(errors::instantiation_error()),
+                ball => Err(ball),
+            },
+            ("=", 2) => Ok(unify(&args[0], &args[1], &mut self.subs)),
+            ("\\=", 2) => {
+                let mark = self.subs.mark();
+                let unifiable = unify(&args[0], &args[1], &mut self.subs);
+                self.subs.undo_to(mark);
+                Ok(!unifiable)
+            }
+            ("var", 1) | ("nonvar", 1) | ("atom", 1) | ("number", 1) | ("integer", 1) | ("atomic", 1) | ("compound", 1)
+            | ("callable", 1) => {
+                let value = self.subs.walk(&args[0]);
+                Ok(match name {
+                    "var" => matches!(value, Term::Variable(_)),
+                    "nonvar" => !matches!(value, Term::Variable(_)),
+                    "atom" => matches!(value, Term::Constant(_) | Term::EmptyList),
+                    "number" | "integer" => matches!(value, Term::Integer(_)),
+                    "atomic" => matches!(value, Term::Constant(_) | Term::Integer(_) | Term::EmptyList),
+                    "compound" => matches!(value, Term::Compound(_, _) | Term::List(_, _)),
+                    _ => !matches!(value, Term::Variable(_) | Term::Integer(_)),
+                })
+            }
+            ("is_list", 1) => Ok(self.subs.apply(&args[0]).to_vec().is_some()),
+            ("is", 2) => {
+                let value = evaluate_arithmetic(&self.subs.apply(&args[1]))?;
+                match value {
+                    Some(value) => Ok(unify(&args[0], &Term::Integer(value), &mut self.subs)),
+                    None => Ok(false),
+                }
+            }
+            (op, 2) if RELATIONAL_OPERATORS.contains(&op) => {
+                let relation_result = evaluate_relation(op, &self.subs.apply(&args[0]), &self.subs.apply(&args[1]))?;
+                Ok(relation_result == Some(true))
+            }
+            ("assert", 1) | ("assertz", 1) | ("asserta", 1) => {
+                let (module, clause) = self.strip_module(&args[0])?;
+                let clause = Clause::from_term(self.subs.apply(&clause))?;
+                let key = clause.head().and_then(Term::predicate_key).unwrap();
+                self.check_modifiable(&module, &key)?;
+                let table = self.db.module_mut(&module);
+                table.declare_dynamic(key);
+                table.add_clause(clause, name == "asserta");
+                Ok(true)
+            }
+            ("retract", 1) => {
+                let (module, clause) = self.strip_module(&args[0])?;
+                let (head, body) = split_clause_term(self.subs.apply(&clause));
+                let key = callable_key(&head)?;
+                self.check_modifiable(&module, &key)?;
+                match self.db.module(&module).and_then(|m| m.clauses(&key)) {
+                    Some(clauses) => Ok(self.resume_clauses(ClauseIteration {
+                        mode: ClauseMode::Retract, module, head, body, clauses, next: 0,
+                    })),
+                    None => Ok(false),
+                }
+            }
+            ("retractall", 1) => {
+                let (module, head) = self.strip_module(&args[0])?;
+                let head = self.subs.apply(&head);
+                let key = callable_key(&head)?;
+                self.check_modifiable(&module, &key)?;
+                self.db.module_mut(&module).declare_dynamic(key.clone());
+                for clause in self.db.module_mut(&module).clauses(&key).unwrap().iter() {
+                    let mark = self.subs.mark();
+                    let renamed = rename_clause_variables(clause, self.counter);
+                    self.counter += 1;
+                    if unify(&head, renamed.head().unwrap(), &mut self.subs) {
+                        self.db.module_mut(&module).remove_clause(&key, clause);
+                    }
+                    self.subs.undo_to(mark);
+                }
+                Ok(true)
+            }
+            ("abolish", 1) => {
+                let (module, spec) = self.strip_module(&args[0])?;
+                let key = parse_indicator(&self.subs.apply(&spec))?;
+                self.check_modifiable(&module, &key)?;
+                self.db.module_mut(&module).abolish(&key);
+                Ok(true)
+            }
+            ("dynamic", 1) => {
+                let (module, spec) = self.strip_module(&args[0])?;
+                self.db.module_mut(&module).declare_dynamic_list(&self.subs.apply(&spec))?;
+                Ok(true)
+            }
+            ("clause", 2) => {
+                let (module, head) = self.strip_module(&args[0])?;
+                let head = self.subs.apply(&head);
+                let body = self.subs.apply(&args[1]);
+                let key = callable_key(&head)?;
+                if !matches!(body, Term::Variable(_)) && !body.is_callable() {
+                    return Err(errors::type_error("callable", body));
+                }
+                if is_builtin(&key.0, key.1) {
+                    return Err(errors::permission_error("access", "private_procedure", errors::indicator(&key.0, key.1)));
+                }
+                match self.db.resolve(&module, &key) {
+                    Some((module, clauses)) => Ok(self.resume_clauses(ClauseIteration {
+                        mode: ClauseMode::Inspect, module, head, body, clauses, next: 0,
+                    })),
+                    None => Ok(false),
+                }
+            }
+            ("current_predicate", 1) => {
+                let goals = reflection::current_predicate(self.db, &self.module, &self.subs.apply(&args[0]))?;
+                Ok(self.try_each(goals, cut_barrier))
+            }
+            ("current_op", 3) => {
+                let args: Vec<Term> = args.iter().map(|arg| self.subs.apply(arg)).collect();
+                let goals = reflection::current_op(&self.db.operators, &args[0], &args[1], &args[2])?;
+                Ok(self.try_each(goals, cut_barrier))
+            }
+            ("op", 3) => {
+                let args: Vec<Term> = args.iter().map(|arg| self.subs.apply(arg)).collect();
+                self.db.operators.define(&args[0], &args[1], &args[2])?;
+                Ok(true)
+            }
+            ("predicate_property", 2) => {
+                let goals = reflection::predicate_property(
+                    self.db, &self.module, &self.subs.apply(&args[0]), &args[1], self.counter,
+                )?;
+                self.counter += 1;
+                Ok(self.try_each(goals, cut_barrier))
+            }
+            ("b_setval", 2) => {
+                let name = globals::variable_name(self.subs.walk(&args[0]))?.to_string();
+                // The value lasts as long as the binding of a marker variable, which backtracking
+                // over this call undoes
+                while self.b_values.last().is_some_and(|(_, _, marker)| self.subs.get(marker).is_none()) {
+                    self.b_values.pop();
+                }
+                let marker = format!("_Global_{}", self.counter);
+                self.counter += 1;
+                self.subs.extend(marker.clone(), Term::EmptyList);
+                self.b_values.push((name, self.subs.apply(&args[1]), marker));
+                Ok(true)
+            }
+            ("nb_setval", 2) => {
+                let name = globals::variable_name(self.subs.walk(&args[0]))?.to_string();
+                self.db.globals.set_value(&name, &self.subs.apply(&args[1]));
+                self.b_values.retain(|(assigned, _, _)| *assigned != name);
+                Ok(true)
+            }
+            ("b_getval", 2) | ("nb_getval", 2) => {
+                let name = globals::variable_name(self.subs.walk(&args[0]))?;
+                let assigned = self.b_values.iter().rev().find(|(assigned, _, marker)| assigned == name && self.subs.get(marker).is_some());
+                let value = match (assigned, self.db.globals.value(name)) {
+                    (Some((_, value, _)), _) => value.clone(),
+                    (None, Some(value)) => rename_vars(value, self.counter),
+                    (None, None) => return Err(errors::existence_error("variable", Term::Constant(name.to_string()))),
+                };
+                self.counter += 1;
+                Ok(unify(&args[1], &value, &mut self.subs))
+            }
+            ("recorda", 2) | ("recorda", 3) | ("recordz", 2) | ("recordz", 3) => {
+                let reference = self.db.globals.record(&self.subs.apply(&args[0]), &self.subs.apply(&args[1]), name == "recorda")?;
+                Ok(args.len() == 2 || unify(&args[2], &reference, &mut self.subs))
+            }
+            ("recorded", 2) | ("recorded", 3) => {
+                let suffix = self.counter;
+                self.counter += 1;
+                let goals = self.db.globals.recorded(&self.subs.apply(&args[0]))?.into_iter().map(|(key, term, reference)| {
+                    let mut goals = vec![equals(args[0].clone(), rename_vars(&key, suffix)), equals(args[1].clone(), rename_vars(&term, suffix))];
+                    if args.len() == 3 {
+                        goals.push(equals(args[2].clone(), reference));
+                    }
+                    conjunction(goals)
+                });
+                Ok(self.try_each(goals.collect(), cut_barrier))
+            }
+            ("erase", 1) => {
+                self.db.globals.erase(&self.subs.apply(&args[0]))?;
+                Ok(true)
+            }
+            ("flag", 3) => {
+                let key = self.subs.apply(&args[0]);
+                if !unify(&args[1], &self.db.globals.flag(&key)?, &mut self.subs) {
+                    return Ok(false);
+                }
+                let value = match self.subs.apply(&args[2]) {
+                    atom @ Term::Constant(_) => atom,
+                    expr => match evaluate_arithmetic(&expr)? {
+                        Some(value) => Term::Integer(value),
+                        None => return Ok(false),
+                    },
+                };
+                self.db.globals.set_flag(&key, value)?;
+                Ok(true)
+            }
+            ("set_prolog_flag", 2) => {
+                self.db.flags.set(self.subs.walk(&args[0]), &self.subs.apply(&args[1]))?;
+                self.subs.occurs_check = self.db.flags.occurs_check;
+                Ok(true)
+            }
+            ("current_prolog_flag", 2) => {
+                let goals = self.db.flags.lookup(self.subs.walk(&args[0]))?.into_iter().map(|(flag, value)| {
+                    conjunction(vec![equals(args[0].clone(), flag), equals(args[1].clone(), value)])
+                });
+                Ok(self.try_each(goals.collect(), cut_barrier))
+            }
+            ("compare", 3) => {
+                let symbol = match self.subs.compare(&args[1], &args[2]) {
+                    Ordering::Less => "<",
+                    Ordering::Equal => "=",
+                    Ordering::Greater => ">",
+                };
+                match self.subs.walk(&args[0]) {
+                    Term::Variable(_) => {}
+                    Term::Constant(order) if ["<", "=", ">"].contains(&order.as_str()) => {}
+                    order @ Term::Constant(_) => return Err(errors::domain_error("order", order.clone())),
+                    order => return Err(errors::type_error("atom", order.clone())),
+                }
+                Ok(unify(&args[0], &Term::Constant(symbol.to_string()), &mut self.subs))
+            }
+            ("==", 2) | ("\\==", 2) | ("@<", 2) | ("@=<", 2) | ("@>", 2) | ("@>=", 2) => {
+                let order = self.subs.compare(&args[0], &args[1]);
+                Ok(match name {
+                    "==" => order.is_eq(),
+                    "\\==" => order.is_ne(),
+                    "@<" => order.is_lt(),
+                    "@=<" => order.is_le(),
+                    "@>" => order.is_gt(),
+                    _ => order.is_ge(),
+                })
+            }
+            ("acyclic_term", 1) | ("cyclic_term", 1) => Ok(self.subs.is_cyclic(&args[0]) == (name == "cyclic_term")),
+            ("term_variables", 2) => {
+                let vars = self.subs.variables(&args[0]).into_iter().map(Term::Variable).collect();
+                Ok(unify(&args[1], &Term::list_from_vec(vars), &mut self.subs))
+            }
+            ("=@=", 2) | ("\\=@=", 2) => {
+                let variant = self.subs.apply(&args[0]).is_variant(&self.subs.apply(&args[1]));
+                Ok(variant == (name == "=@="))
+            }
+            ("subsumes_term", 2) => Ok(self.subs.apply(&args[0]).subsumes(&self.subs.apply(&args[1]))),
+            ("term_subsumer", 3) => {
+                let (suffix, mut count) = (self.counter, 0);
+                self.counter += 1;
+                let mut fresh_var = || {
+                    count += 1;
+                    fresh(&format!("Subsumer{}", count), suffix)
+                };
+                let general = self.subs.apply(&args[0]).generalise(&self.subs.apply(&args[1]), &mut fresh_var);
+                Ok(unify(&args[2], &general, &mut self.subs))
+            }
+            ("term_hash", 2) | ("term_hash", 4) => {
+                let (depth, range) = match args.len() {
+                    2 => (usize::MAX, None),
+                    _ => {
+                        let depth = integer_argument(&self.subs.apply(&args[1]))?.ok_or_else(errors::instantiation_error)?;
+                        let range = integer_argument(&self.subs.apply(&args[2]))?.ok_or_else(errors::instantiation_error)?;
+                        if range < 1 {
+                            return Err(errors::domain_error("not_less_than_one", Term::Integer(range)));
+                        }
+                        (usize::try_from(depth).map_err(|_| errors::domain_error("not_less_than_zero", Term::Integer(depth)))?, Some(range as u64))
+                    }
+                };
+                // A term with variables gets no hash, leaving Hash unbound
+                match self.subs.apply(&args[0]).structural_hash(depth) {
+                    Some(hash) => {
+                        let hash = match range {
+                            Some(range) => hash % range,
+                            None => hash >> 1,
+                        };
+                        Ok(unify(&args[args.len() - 1], &Term::Integer(hash as i64), &mut self.subs))
+                    }
+                    None => Ok(true),
+                }
+            }
+            ("numbervars", 3) => {
+                let start = integer_argument(&self.subs.apply(&args[1]))?.ok_or_else(errors::instantiation_error)?;
+                let vars = self.subs.variables(&args[0]);
+                let end = start + vars.len() as i64;
+                for (n, var) in (start..).zip(vars) {
+                    self.subs.extend(var, Term::Compound("$VAR".to_string(), vec![Term::Integer(n)]));
+                }
+                Ok(unify(&args[2], &Term::Integer(end), &mut self.subs))
+            }
+            ("between", 3) | ("length", 2) | ("succ", 2) | ("plus", 3) | ("max", 3) | ("min", 3) | ("sort", 2)
+            | ("msort", 2) | ("keysort", 2) | ("sort", 4) | ("predsort", 3) | ("$predmerge", 4) => {
+                let args: Vec<Term> = args.iter().map(|arg| self.subs.apply(arg)).collect();
+                let suffix = self.counter;
+                self.counter += 1;
+                let goals = match name {
+                    "between" => builtin_between(&args)?,
+                    "length" => {
+                        // A cyclic list is applied up to where it repeats, which leaves a bound variable as its tail
+                        if let Term::Variable(tail) = list_prefix(&args[0]).1 {
+                            if self.subs.get(tail).is_some() {
+                                return Err(errors::type_error("list", args[0].clone()));
+                            }
+                        }
+                        builtin_length(&args, suffix)?
+                    }
+                    "succ" => builtin_succ(&args)?,
+                    "plus" => builtin_plus(&args)?,
+                    "max" => builtin_max(&args)?,
+                    "sort" | "msort" | "keysort" => builtin_sort(name, &args)?,
+                    "predsort" => builtin_predsort(&args, suffix)?,
+                    "$predmerge" => builtin_predmerge(&args, suffix),
+                    _ => builtin_min(&args)?,
+                };
+                Ok(self.try_each(goals, cut_barrier))
+            }
+            _ => match self.library_call(name, args.len()) {
+                Some(library) => {
+                    let args: Vec<Term> = args.iter().map(|arg| self.subs.apply(arg)).collect();
+                    let suffix = self.counter;
+                    self.counter += 1;
+                    let goals = match library {
+                        "lists" => lists::solve(name, &args, suffix)?,
+                        "apply" => apply::solve(name, &args, suffix),
+                        "assoc" => assoc::solve(name, &args)?,
+                        "ordsets" => ordsets::solve(name, &args)?,
+                        _ => pairs::solve(name, &args, suffix)?,
+                    };
+                    Ok(self.try_each(goals, cut_barrier))
+                }
+                None => {
+                    let key = term.predicate_key().unwrap();
+                    self.call_predicate(&term, key)
+                }
+            },
         }
-        if name == "length" && args.len() == 2 {
-            return builtin_length(args)
+    }
+
+    // The library of a library predicate the program has not defined itself
+    fn library_call(&self, name: &str, arity: usize) -> Option<&'static str> {
+        library_of(name, arity).filter(|_| self.db.resolve(&self.module, &(name.to_string(), arity)).is_none())
+    }
+
+    // Clauses loaded from the program are static; only dynamic or new predicates may be changed
+    fn check_modifiable(&self, module: &str, key: &PredicateKey) -> Result<(), Term> {
+        let table = self.db.module(module);
+        let is_static = table.is_some_and(|m| m.is_defined(key) && !m.is_dynamic(key));
+        if is_builtin(&key.0, key.1) || is_static {
+            let culprit = errors::qualified_indicator(module, &key.0, key.1);
+            return Err(errors::permission_error("modify", "static_procedure", culprit));
         }
-        if name == "sort" && args.len() == 2 {
-            return builtin_sort(args)
+        Ok(())
+    }
+
+    // Splits `M:Term` into its module and term; an unqualified term belongs to the context module
+    fn strip_module(&self, term: &Term) -> Result<(ModuleName, Term), Term> {
+        let mut module = self.module.clone();
+        let mut term = self.subs.walk(term).clone();
+        while let Term::Compound(colon, args) = &term {
+            if colon != ":" || args.len() != 2 {
+                break;
+            }
+            module = match self.subs.walk(&args[0]) {
+                Term::Constant(name) => name.as_str().into(),
+                Term::Variable(_) => return Err(errors::instantiation_error()),
+                other => return Err(errors::type_error("module", other.clone())),
+            };
+            term = self.subs.walk(&args[1]).clone();
         }
+        Ok((module, term))
+    }
 
-        let mut matching_clauses = vec![];
+    // Calls a predicate's clauses. A predicate that has none and was never declared dynamic is
+    // unknown, and the `unknown` flag says whether that is an error, a warning or just failure.
+    fn call_predicate(&mut self, goal: &Term, key: PredicateKey) -> Result<bool, Term> {
+        let Some((module, clauses)) = self.db.resolve(&self.module, &key) else {
+            let indicator = errors::qualified_indicator(&self.module, &key.0, key.1);
+            return match self.db.flags.unknown {
+                Unknown::Error => Err(errors::existence_error("procedure", indicator)),
+                Unknown::Warning => {
+                    self.write_text(streams::USER_ERROR, &format!("Warning: unknown procedure {}\n", indicator))?;
+                    Ok(false)
+                }
+                Unknown::Fail => Ok(false),
+            };
+        };
+        let head = self.qualify_meta_arguments(&module, &key, goal);
+        Ok(self.resume_clauses(ClauseIteration {
+            mode: ClauseMode::Call,
+            module,
+            head,
+            body: Term::Constant("true".to_string()),
+            clauses,
+            next: 0,
+        }))
+    }
 
-        for clause in &db.clauses {
-            let renamed_clause = rename_clause_variables(clause, *counter);
-            match &renamed_clause {
-                Clause::Fact(fact) if unify(term, fact, &mut subs) => {
-                    println!("DEBUG: Matched fact {:?}", fact);
-                    return Some(subs);
+    // Goal arguments of a meta-predicate are qualified with the caller's module, so a library
+    // predicate calling them still finds the caller's predicates
+    fn qualify_meta_arguments(&self, module: &str, key: &PredicateKey, goal: &Term) -> Term {
+        let (Some(specs), Term::Compound(name, args)) = (self.db.module(module).and_then(|m| m.meta_predicate(key)), goal) else {
+            return goal.clone();
+        };
+        let args = args.iter().zip(specs).map(|(arg, spec)| {
+            let is_goal = matches!(spec, Term::Integer(_)) || matches!(spec, Term::Constant(s) if s == ":" || s == "^");
+            match self.subs.walk(arg) {
+                Term::Compound(colon, _) if colon == ":" => arg.clone(),
+                _ if is_goal && &*self.module != module => {
+                    Term::Compound(":".to_string(), vec![Term::Constant(self.module.to_string()), arg.clone()])
                 }
-                Clause::Rule(head, body) if unify(term, head, &mut subs) => {
-                    println!("DEBUG: Matched rule head {:?}, preparing to solve body {:?}", head, body);
-                    matching_clauses.push((head.clone(), body.clone()));
+                _ => arg.clone(),
+            }
+        }).collect();
+        Term::Compound(name.clone(), args)
+    }
+
+    // Tries the remaining clauses of an iteration in order, leaving a choice point if more could match
+    fn resume_clauses(&mut self, mut iteration: ClauseIteration) -> bool {
+        let height = self.back_stack.len();
+        let mut candidate = self.next_candidate(&iteration, iteration.next);
+
+        while let Some(index) = candidate {
+            let clause = iteration.clauses[index].clone();
+            candidate = self.next_candidate(&iteration, index + 1);
+
+            let mark = self.subs.mark();
+            let renamed = rename_clause_variables(&clause, self.counter);
+            self.counter += 1;
+
+            let matched = unify(&iteration.head, renamed.head().unwrap(), &mut self.subs)
+                && (iteration.mode == ClauseMode::Call || unify(&iteration.body, &renamed.body_term(), &mut self.subs));
+            if !matched {
+                self.subs.undo_to(mark);
+                continue;
+            }
+            if iteration.mode == ClauseMode::Retract {
+                let key = iteration.head.predicate_key().unwrap();
+                if !self.db.module_mut(&iteration.module).remove_clause(&key, &clause) {
+                    // Already retracted since this iteration started
+                    self.subs.undo_to(mark);
+                    continue;
                 }
-                _ => {}
             }
-            *counter += 1;
-        }
 
-        if !matching_clauses.is_empty() {
-            println!("DEBUG: Found matching clauses, pushing alternatives to stack");
-            for (_head, _body) in &matching_clauses[1..] {
-                back_stack.push(ChoicePoint {
-                    env: Environment::new(),
-                    alternatives: vec![Term::Compound(name.clone(), args.clone())],
+            let continuation = self.goals.clone();
+            let iteration_mode = iteration.mode.clone();
+            let module = iteration.module.clone();
+            if let Some(next) = candidate {
+                iteration.next = next;
+                self.back_stack.push(ChoicePoint {
+                    env: Environment::at(mark),
+                    goals: continuation,
+                    cut_barrier: height,
+                    module: self.module.clone(),
+                    alternatives: Alternatives::Clauses(iteration),
                 });
             }
+            if let (ClauseMode::Call, Clause::Rule(_, body)) = (&iteration_mode, &renamed) {
+                // The body runs in the module the clause is defined in
+                self.module = module;
+                self.push_body(body, height);
+            }
+            return true;
+        }
+        false
+    }
+
+    fn next_candidate(&self, iteration: &ClauseIteration, from: usize) -> Option<usize> {
+        (from..iteration.clauses.len()).find(|&index| could_match(&self.subs, &iteration.head, &iteration.clauses[index]))
+    }
+
+    // Writes a term with the database's operators. With the portray option, the user's portray/1
+    // is tried on each subterm first, and what it writes takes the place of the subterm.
+    fn write_term(&mut self, term: &Term, options: WriteOptions) -> String {
+        let term = self.subs.apply(term);
+        let portray_key = ("portray".to_string(), 1);
+        if !options.portray || self.db.resolve(&self.module, &portray_key).is_none() {
+            return Writer::new(&self.db.operators, options).write(&term);
+        }
+        let ops = self.db.operators.clone();
+        let module = Term::Constant(self.module.to_string());
+        let db = &mut *self.db;
+        let mut portray = |subterm: &Term| {
+            let goal = Term::Compound(":".to_string(), vec![
+                module.clone(),
+                Term::Compound("portray".to_string(), vec![subterm.clone()]),
+            ]);
+            let capture = db.streams.begin_capture();
+            let portrayed = matches!(Solver::new(&Expression::Term(goal), db).next_solution(), Ok(Some(_)));
+            let text = db.streams.end_capture(capture);
+            portrayed.then_some(text)
+        };
+        Writer::new(&ops, options).with_portray(&mut portray).write(&term)
+    }
 
-            let (first_head, first_body) = &matching_clauses[0];
-            let mut local_subs = subs.clone();
+    // The stream an I/O builtin works on: its first argument in the form with `full` arguments,
+    // or else the current input or output
+    fn io_stream(&self, args: &[Term], full: usize, output: bool, binary: bool) -> Result<usize, Term> {
+        let stream = match args.len() == full {
+            true => self.subs.apply(&args[0]),
+            false => stream_term(if output { self.db.streams.output } else { self.db.streams.input }),
+        };
+        match output {
+            true => self.db.streams.output_stream(&stream, binary),
+            false => self.db.streams.input_stream(&stream, binary),
+        }
+    }
 
-            if unify(term, first_head, &mut local_subs) {
-                println!("DEBUG: Unification successful, solving body: {:?}", first_body);
-                let applied_body = first_body.apply(&local_subs);
+    fn write_text(&mut self, stream: usize, text: &str) -> Result<(), Term> {
+        let db = &mut *self.db;
+        db.streams.write(stream, text, &mut db.output)
+    }
 
-                *counter += 1; // Increment counter for recursive calls
+    // Collects a copy of `template` for every solution of `goal`
+    fn find_all(&mut self, template: &Term, goal: &Term) -> Result<Vec<Term>, Term> {
+        self.find_solutions(template, goal, usize::MAX)
+    }
 
-                if let Some(body_subs) = solve(&applied_body, db, back_stack, counter) {
-                    let merged = local_subs.merge(&body_subs);
-                
-                    if merged.is_none() {
-                        println!("DEBUG: Merge failed unexpectedly, returning empty substitution instead.");
-                        return Some(Substitution::new());  // Ensure at least an empty substitution is returned
+    // Like find_all, stopping after `limit` solutions
+    fn find_solutions(&mut self, template: &Term, goal: &Term, limit: usize) -> Result<Vec<Term>, Term> {
+        let mut inner = Solver {
+            db: &mut *self.db,
+            subs: self.subs.clone(),
+            goals: Continuation::done().push(goal.clone(), 0, self.module.clone()),
+            back_stack: BacktrackingStack::new(),
+            counter: self.counter,
+            query_vars: vec![],
+            started: false,
+            module: self.module.clone(),
+            b_values: self.b_values.clone(),
+        };
+        let mut results = vec![];
+        let outcome = loop {
+            match inner.next_solution() {
+                Ok(Some(_)) => {
+                    results.push(rename_vars(&inner.subs.apply(template), inner.counter));
+                    inner.counter += 1;
+                    if results.len() == limit {
+                        break Ok(results);
                     }
-                
-                    println!("DEBUG: Returning merged substitution: {:?}", merged);
-                    return merged;
                 }
-                                
+                Ok(None) => break Ok(results),
+                Err(ball) => break Err(ball),
             }
+        };
+        self.counter = inner.counter;
+        outcome
+    }
+}
+
+fn cut_to(height: usize) -> Term {
+    Term::Compound("$cut".to_string(), vec![Term::Integer(height as i64)])
+}
+
+// Extends a goal with extra arguments, as call/N does
+fn add_args(goal: Term, extra: &[Term]) -> Result<Term, Term> {
+    match goal {
+        Term::Variable(_) => Err(errors::instantiation_error()),
+        goal if extra.is_empty() && goal.is_callable() => Ok(goal),
+        Term::Compound(colon, mut args) if colon == ":" && args.len() == 2 => {
+            let goal = add_args(args.pop().unwrap(), extra)?;
+            args.push(goal);
+            Ok(Term::Compound(colon, args))
+        }
+        Term::Constant(name) => Ok(Term::Compound(name, extra.to_vec())),
+        Term::Compound(name, mut args) => {
+            args.extend_from_slice(extra);
+            Ok(Term::Compound(name, args))
         }
+        goal => Err(errors::type_error("callable", goal)),
+    }
+}
+
+// Splits `Head :- Body` into its parts; a plain head has the body `true`
+fn split_clause_term(term: Term) -> (Term, Term) {
+    match term {
+        Term::Compound(name, mut args) if name == ":-" && args.len() == 2 => {
+            let body = args.pop().unwrap();
+            (args.pop().unwrap(), body)
+        }
+        head => (head, Term::Constant("true".to_string())),
+    }
+}
+
+// A list whose tail may still be unbound, as phrase/2,3 accept
+fn is_partial_list(term: &Term) -> bool {
+    match term {
+        Term::List(_, tail) => is_partial_list(tail),
+        Term::EmptyList | Term::Variable(_) => true,
+        _ => false,
+    }
+}
+
+fn callable_key(head: &Term) -> Result<PredicateKey, Term> {
+    match head {
+        Term::Variable(_) => Err(errors::instantiation_error()),
+        head => head.predicate_key().ok_or_else(|| errors::type_error("callable", head.clone())),
+    }
+}
+
+// Cheap pre-check on the first argument so clauses that can't match don't leave choice points
+fn could_match(subs: &Substitution, goal: &Term, clause: &Clause) -> bool {
+    let (Term::Compound(_, goal_args), Some(Term::Compound(_, head_args))) = (goal, clause.head()) else {
+        return true;
+    };
+    match (goal_args.first().map(|arg| subs.walk(arg)), head_args.first()) {
+        (Some(Term::Variable(_)), _) | (_, Some(Term::Variable(_))) => true,
+        (Some(Term::Constant(a)), Some(Term::Constant(b))) => a == b,
+        (Some(Term::Integer(a)), Some(Term::Integer(b))) => a == b,
+        (Some(Term::Compound(f, xs)), Some(Term::Compound(g, ys))) => f == g && xs.len() == ys.len(),
+        (Some(Term::List(_, _)), Some(Term::List(_, _))) => true,
+        (Some(Term::EmptyList), Some(Term::EmptyList)) => true,
+        (Some(_), Some(_)) => false,
+        _ => true,
     }
-    println!("DEBUG: No matching clause found, returning None");
-    None
 }
 
-fn rename_vars(term: &Term, suffix: usize) -> Term {
+pub fn rename_vars(term: &Term, suffix: usize) -> Term {
     match term {
         Term::Variable(var) => Term::Variable(format!("{}_{}", var, suffix)),
         Term::Compound(name, args) => Term::Compound(
@@ -179,6 +1248,7 @@ fn rename_clause_variables(clause: &Clause, suffix: usize) -> Clause {
     match clause {
         Clause::Fact(term) => Clause::Fact(rename_vars(term, suffix)),
         Clause::Rule(head, body) => Clause::Rule(rename_vars(head, suffix), rename_expr(body, suffix)),
+        Clause::Directive(body) => Clause::Directive(rename_expr(body, suffix)),
     }
 }
 
@@ -192,52 +1262,53 @@ fn rename_expr(expr: &Expression, suffix: usize) -> Expression {
     }
 }
 
-// Arithmetic evaluation (for 'is')
-fn evaluate_arithmetic(expr: &Term) -> Option<i64> {
+// Arithmetic evaluation (for 'is'); None if the expression can't be evaluated
+fn evaluate_arithmetic(expr: &Term) -> Result<Option<i64>, Term> {
     match expr {
-        Term::Integer(n) => Some(*n),
+        Term::Integer(n) => Ok(Some(*n)),
         Term::Compound(op, args) if args.len() == 2 => {
-            let left = evaluate_arithmetic(&args[0])?;
-            let right = evaluate_arithmetic(&args[1])?;
-            match op.as_str() {
+            let (Some(left), Some(right)) = (evaluate_arithmetic(&args[0])?, evaluate_arithmetic(&args[1])?) else {
+                return Ok(None);
+            };
+            Ok(match op.as_str() {
                 "+" => Some(left + right),
                 "-" => Some(left - right),
                 "*" => Some(left * right),
-                "/" => if right != 0 { Some(left / right) } else { None },
+                "/" if right == 0 => return Err(errors::evaluation_error("zero_divisor")),
+                "/" => Some(left / right),
                 _ => None,
-            }
+            })
         }
-        _ => None,
+        _ => Ok(None),
     }
 }
 
 // Relation evaluation (for <, >, =<, etc.)
-fn evaluate_relation(op: &str, left: &Term, right: &Term) -> Option<bool> {
-    let left_value = evaluate_arithmetic(left)?;
-    let right_value = evaluate_arithmetic(right)?;
-    println!("Left and Right {} {}", left_value, right_value);
+fn evaluate_relation(op: &str, left: &Term, right: &Term) -> Result<Option<bool>, Term> {
+    let (Some(left_value), Some(right_value)) = (evaluate_arithmetic(left)?, evaluate_arithmetic(right)?) else {
+        return Ok(None);
+    };
 
-    match op {
+    Ok(match op {
         "<" => Some(left_value < right_value),
         ">" => Some(left_value > right_value),
         "=<" => Some(left_value <= right_value),
         ">=" => Some(left_value >= right_value),
-        "=" => Some(left_value == right_value),
-        "\\=" => Some(left_value != right_value),
+        "=:=" => Some(left_value == right_value),
+        "=\\=" => Some(left_value != right_value),
         _ => None,
-    }
+    })
 }
 
 // Operators we want to handle
-const RELATIONAL_OPERATORS: [&str; 6] = ["<", ">", "=<", ">=", "=", "\\="];
+const RELATIONAL_OPERATORS: [&str; 6] = ["<", ">", "=<", ">=", "=:=", "=\\="];
 
 #[test]
 fn test_fact_matching() {
     use crate::terms::{Term, Clause, Expression};
     use crate::database::Database;
-    use crate::backtracking::BacktrackingStack;
 
-    let db = Database::new(vec![
+    let mut db = Database::new(vec![
         Clause::Fact(Term::Compound("parent".into(), vec![
             Term::Constant("john".into()),
             Term::Constant("mary".into()),
@@ -249,9 +1320,7 @@ fn test_fact_matching() {
         Term::Variable("X".into()),
     ]));
 
-    let mut back_stack = BacktrackingStack::new();
-    let mut counter = 0;
-    let result = crate::solver::solve(&query, &db, &mut back_stack, &mut counter);
+    let result = crate::solver::solve(&query, &mut db);
 
     assert!(result.is_some());
     let subs = result.unwrap();
@@ -262,9 +1331,8 @@ fn test_fact_matching() {
 fn test_grandparent_rule() {
     use crate::terms::{Term, Clause, Expression};
     use crate::database::Database;
-    use crate::backtracking::BacktrackingStack;
 
-    let db = Database::new(vec![
+    let mut db = Database::new(vec![
         Clause::Fact(Term::Compound("parent".into(), vec![
             Term::Constant("john".into()),
             Term::Constant("mary".into()),
@@ -296,9 +1364,7 @@ fn test_grandparent_rule() {
         Term::Constant("alice".into()),
     ]));
 
-    let mut back_stack = BacktrackingStack::new();
-    let mut counter = 0;
-    let result = crate::solver::solve(&query, &db, &mut back_stack, &mut counter);
+    let result = crate::solver::solve(&query, &mut db);
 
     assert!(result.is_some());
     let subs = result.unwrap();
@@ -309,9 +1375,8 @@ fn test_grandparent_rule() {
 fn test_arithmetic_is_operator() {
     use crate::terms::{Term, Clause, Expression};
     use crate::database::Database;
-    use crate::backtracking::BacktrackingStack;
 
-    let db = Database::new(vec![
+    let mut db = Database::new(vec![
         Clause::Rule(
             Term::Compound("test".into(), vec![Term::Variable("X".into())]),
             Expression::Term(Term::Compound("is".into(), vec![
@@ -325,11 +1390,316 @@ fn test_arithmetic_is_operator() {
         Term::Variable("X".into()),
     ]));
 
-    let mut back_stack = BacktrackingStack::new();
-    let mut counter = 0;
-    let result = crate::solver::solve(&query, &db, &mut back_stack, &mut counter);
+    let result = crate::solver::solve(&query, &mut db);
 
     assert!(result.is_some());
     let subs = result.unwrap();
     assert_eq!(subs.get("X"), Some(&Term::Integer(5)));
 }
+
+#[test]
+fn test_division_by_zero_raises_evaluation_error() {
+    let mut db = load_program("");
+    assert_eq!(run_query(&mut db, "X is 1 / 0."), Err(errors::evaluation_error("zero_divisor")));
+    assert_eq!(run_query(&mut db, "1 / (2 - 2) < 3."), Err(errors::evaluation_error("zero_divisor")));
+    assert_eq!(run_query(&mut db, "X is 1 / a."), Ok(None));
+}
+
+#[cfg(test)]
+pub(crate) fn load_program(text: &str) -> Database {
+    let mut db = Database::new(vec![]);
+    consult::consult_text(&mut db, text, None);
+    assert!(db.messages.is_empty(), "{:?}", db.messages);
+    db
+}
+
+#[cfg(test)]
+pub(crate) fn run_query(db: &mut Database, text: &str) -> Result<Option<Substitution>, Term> {
+    let query = Term::from_tree_term(crate::parser::parser::parse_query_with_operators(text, &db.operators).unwrap());
+    Solver::new(&Expression::from_term(query), db).next_solution()
+}
+
+#[test]
+fn test_asserted_clauses_persist_between_queries() {
+    let mut db = load_program("");
+    assert!(run_query(&mut db, "assertz(counter(1)).").unwrap().is_some());
+    assert!(run_query(&mut db, "asserta(counter(0)).").unwrap().is_some());
+
+    let subs = run_query(&mut db, "counter(X).").unwrap().unwrap();
+    assert_eq!(subs.get("X"), Some(&Term::Integer(0)));
+}
+
+#[test]
+fn test_logical_update_view() {
+    let mut db = load_program(":- dynamic p/1.\np(1).\np(2).\ngrow :- p(X), assertz(p(X)), fail.\ngrow.");
+    // The running call of p/1 only sees the two clauses that existed when it started
+    assert!(run_query(&mut db, "grow.").unwrap().is_some());
+
+    let subs = run_query(&mut db, "findall(X, p(X), L).").unwrap().unwrap();
+    let list = |items: &[i64]| Term::list_from_vec(items.iter().map(|n| Term::Integer(*n)).collect());
+    assert_eq!(subs.get("L"), Some(&list(&[1, 2, 1, 2])));
+}
+
+#[test]
+fn test_retract_is_nondeterministic() {
+    let mut db = load_program(":- dynamic q/1.\nq(1).\nq(2).\nq(3).");
+    let subs = run_query(&mut db, "retract(q(X)), X >= 2.").unwrap().unwrap();
+    assert_eq!(subs.get("X"), Some(&Term::Integer(2)));
+
+    let subs = run_query(&mut db, "findall(X, q(X), L).").unwrap().unwrap();
+    assert_eq!(subs.get("L"), Some(&Term::list_from_vec(vec![Term::Integer(3)])));
+}
+
+#[test]
+fn test_retractall_and_abolish() {
+    let mut db = load_program("");
+    run_query(&mut db, "assertz(r(1, a)), assertz(r(2, b)), assertz(r(1, c)).").unwrap();
+    run_query(&mut db, "retractall(r(1, X)).").unwrap();
+    let subs = run_query(&mut db, "findall(Y, r(X, Y), L).").unwrap().unwrap();
+    assert_eq!(subs.get("L"), Some(&Term::list_from_vec(vec![Term::Constant("b".into())])));
+
+    run_query(&mut db, "abolish(r/2).").unwrap();
+    assert!(!db.user().is_defined(&("r".to_string(), 2)));
+}
+
+#[test]
+fn test_modifying_static_procedure_raises_permission_error() {
+    let mut db = load_program("parent(john, mary).");
+    let error = run_query(&mut db, "assertz(parent(mary, alice)).").unwrap_err();
+    assert_eq!(
+        error,
+        errors::permission_error("modify", "static_procedure", errors::indicator("parent", 2))
+    );
+
+    let subs = run_query(&mut db, "catch(retract(parent(X, Y)), error(E, C), true).").unwrap().unwrap();
+    assert!(matches!(subs.get("E"), Some(Term::Compound(name, _)) if name == "permission_error"));
+}
+
+#[test]
+fn test_clause_returns_body_as_term() {
+    let mut db = load_program("grandparent(X, Y) :- parent(X, Z), parent(Z, Y).\nparent(john, mary).");
+    let subs = run_query(&mut db, "clause(grandparent(A, B), Body).").unwrap().unwrap();
+    let body = subs.get("Body").unwrap();
+    assert!(matches!(body, Term::Compound(name, args) if name == "," && args.len() == 2));
+
+    let subs = run_query(&mut db, "clause(parent(X, Y), Body).").unwrap().unwrap();
+    assert_eq!(subs.get("Body"), Some(&Term::Constant("true".into())));
+
+    let error = run_query(&mut db, "clause(write(X), B).").unwrap_err();
+    assert_eq!(error, errors::permission_error("access", "private_procedure", errors::indicator("write", 1)));
+}
+
+#[test]
+fn test_current_predicate_enumerates_user_predicates() {
+    let mut db = load_program("a(1).\nb(1, 2).\nb(3, 4).");
+    let subs = run_query(&mut db, "findall(P, current_predicate(P), L).").unwrap().unwrap();
+    assert_eq!(
+        subs.get("L"),
+        Some(&Term::list_from_vec(vec![errors::indicator("a", 1), errors::indicator("b", 2)]))
+    );
+    assert!(run_query(&mut db, "current_predicate(append/3).").unwrap().is_none());
+}
+
+#[test]
+fn test_predicate_property() {
+    let mut db = load_program(":- dynamic counter/1.\ncounter(0).\nfact(1).\nfact(2).");
+    assert!(run_query(&mut db, "predicate_property(counter(X), dynamic).").unwrap().is_some());
+    assert!(run_query(&mut db, "predicate_property(fact(X), dynamic).").unwrap().is_none());
+    assert!(run_query(&mut db, "predicate_property(write(X), built_in).").unwrap().is_some());
+    assert!(run_query(&mut db, "predicate_property(append(X, Y, Z), imported_from(lists)).").unwrap().is_some());
+
+    let subs = run_query(&mut db, "predicate_property(fact(X), number_of_clauses(N)).").unwrap().unwrap();
+    assert_eq!(subs.get("N"), Some(&Term::Integer(2)));
+
+    let subs = run_query(&mut db, "findall(H, predicate_property(H, dynamic), L).").unwrap().unwrap();
+    let dynamic = subs.get("L").unwrap().to_vec().unwrap();
+    assert_eq!(dynamic.len(), 1);
+    assert!(matches!(&dynamic[0], Term::Compound(name, _) if name == "counter"));
+}
+
+#[test]
+fn test_current_op() {
+    let mut db = load_program("");
+    let subs = run_query(&mut db, "current_op(P, T, is).").unwrap().unwrap();
+    assert_eq!(subs.get("P"), Some(&Term::Integer(700)));
+    assert_eq!(subs.get("T"), Some(&Term::Constant("xfx".into())));
+}
+
+#[test]
+fn test_op_directive_changes_reader() {
+    let mut db = load_program(":- op(700, xfx, likes).\njohn likes mary.\nfan(X) :- X likes mary.");
+    let subs = run_query(&mut db, "fan(Who).").unwrap().unwrap();
+    assert_eq!(subs.get("Who"), Some(&Term::Constant("john".into())));
+
+    assert!(run_query(&mut db, "op(200, xfy, likes).").unwrap().is_some());
+    let subs = run_query(&mut db, "current_op(P, T, likes).").unwrap().unwrap();
+    assert_eq!(subs.get("P"), Some(&Term::Integer(200)));
+    assert_eq!(subs.get("T"), Some(&Term::Constant("xfy".into())));
+    assert!(run_query(&mut db, "op(1201, xfx, likes).").is_err());
+}
+
+#[test]
+fn test_modules_keep_helpers_apart() {
+    let mut db = load_program("
+        :- module(a, [run_a/1]).
+        helper(X, a(X)).
+        run_a(Y) :- helper(1, Y).
+        :- module(b, [run_b/1]).
+        helper(X, b(X)).
+        run_b(Y) :- helper(2, Y).
+    ");
+    let subs = run_query(&mut db, "run_a(X), run_b(Y).").unwrap().unwrap();
+    let wrap = |f: &str, n| Term::Compound(f.into(), vec![Term::Integer(n)]);
+    assert_eq!(subs.get("X"), Some(&wrap("a", 1)));
+    assert_eq!(subs.get("Y"), Some(&wrap("b", 2)));
+
+    // helper/2 is not exported, so it is only reachable with a qualified goal
+    let error = run_query(&mut db, "helper(1, X).").unwrap_err();
+    assert_eq!(error, errors::existence_error("procedure", errors::indicator("helper", 2)));
+    let subs = run_query(&mut db, "b:helper(3, X).").unwrap().unwrap();
+    assert_eq!(subs.get("X"), Some(&wrap("b", 3)));
+}
+
+#[test]
+fn test_meta_predicate_arguments_run_in_caller_module() {
+    let mut db = load_program("
+        :- module(util, [twice/1]).
+        :- meta_predicate(twice(0)).
+        twice(G) :- call(G), call(G).
+        :- module(app, [go/1]).
+        step(X) :- assertz(app_seen(X)).
+        go(X) :- twice(step(X)).
+    ");
+    run_query(&mut db, "use_module(util).").unwrap();
+    assert!(run_query(&mut db, "app:use_module(util), go(1).").unwrap().is_some());
+    let subs = run_query(&mut db, "findall(X, app:app_seen(X), L).").unwrap().unwrap();
+    assert_eq!(subs.get("L"), Some(&Term::list_from_vec(vec![Term::Integer(1), Term::Integer(1)])));
+}
+
+#[test]
+fn test_qualified_assert_and_error_names() {
+    let mut db = load_program(":- module(m, []).\nfixed(1).");
+    assert!(run_query(&mut db, "assertz(n:counter(1)), n:counter(X).").unwrap().is_some());
+    let error = run_query(&mut db, "counter(X).").unwrap_err();
+    assert_eq!(error, errors::existence_error("procedure", errors::indicator("counter", 1)));
+
+    let error = run_query(&mut db, "assertz(m:fixed(2)).").unwrap_err();
+    let culprit = Term::Compound(":".into(), vec![Term::Constant("m".into()), errors::indicator("fixed", 1)]);
+    assert_eq!(error, errors::permission_error("modify", "static_procedure", culprit));
+}
+
+#[test]
+fn test_write_builtins_collect_output() {
+    let mut db = load_program("portray(secret(_)) :- write('<hidden>').");
+    run_query(&mut db, "write('a b'), writeq(['a b']), write_canonical(1 + x).").unwrap();
+    assert_eq!(db.output, "a b['a b']+(1, x)");
+    db.output.clear();
+    run_query(&mut db, "print(f(secret(1))), write_term(g(secret(2)), [max_depth(1)]).").unwrap();
+    assert_eq!(db.output, "f(<hidden>)g(...)");
+}
+
+#[test]
+fn test_format_sinks() {
+    let mut db = load_program("");
+    let subs = run_query(&mut db, "format(atom(A), '~w-~a', [f(x), y]), format(codes(C), \"~d\", [7]).").unwrap().unwrap();
+    assert_eq!(subs.get("A"), Some(&Term::Constant("f(x)-y".into())));
+    assert_eq!(subs.apply(&Term::Variable("C".into())), Term::list_from_vec(vec![Term::Integer(55)]));
+    run_query(&mut db, "write(ab), format('~t~w~5|~n', [c]).").unwrap();
+    assert_eq!(db.output, "ab  c\n");
+}
+
+#[test]
+fn test_stream_io() {
+    let mut db = load_program("");
+    let path = std::env::temp_dir().join(format!("solver_stream_{}.pl", std::process::id()));
+    let file = path.to_string_lossy().replace('\\', "/");
+    let write = format!("open('{}', write, S, [alias(out)]), writeq(out, f('A b', [1])), write(out, '.'), nl(out), put_char(out, z), close(S).", file);
+    assert!(run_query(&mut db, &write).unwrap().is_some());
+
+    let read = format!("open('{}', read, S), read(S, T), get_char(S, _), get_char(S, C), peek_char(S, E), close(S).", file);
+    let subs = run_query(&mut db, &read).unwrap().unwrap();
+    assert_eq!(subs.apply(&Term::Variable("T".into())).to_string(), "f('A b', [1])");
+    assert_eq!(subs.get("C"), Some(&Term::Constant("z".into())));
+    assert_eq!(subs.get("E"), Some(&Term::Constant("end_of_file".into())));
+    std::fs::remove_file(&path).unwrap();
+
+    let subs = run_query(&mut db, "with_output_to(atom(A), (member(X, [a, b]), write(X), tab(1))), stream_property(S, alias(user_error)).").unwrap().unwrap();
+    assert_eq!(subs.get("A"), Some(&Term::Constant("a ".into())));
+    assert_eq!(subs.get("S"), Some(&stream_term(streams::USER_ERROR)));
+    assert!(db.output.is_empty());
+
+    let culprit = Term::Constant("nowhere".into());
+    assert_eq!(run_query(&mut db, "write(nowhere, x).").unwrap_err(), errors::existence_error("stream", culprit));
+    let culprit = Term::Constant("user_input".into());
+    assert_eq!(run_query(&mut db, "put_char(user_input, x).").unwrap_err(), errors::permission_error("output", "stream", culprit));
+}
+
+#[cfg(test)]
+fn all_answers(db: &mut Database, text: &str, var: &str) -> Vec<String> {
+    let query = Term::from_tree_term(crate::parser::parser::parse_query_with_operators(text, &db.operators).unwrap());
+    let mut solver = Solver::new(&Expression::from_term(query), db);
+    let mut answers = vec![];
+    while let Some(subs) = solver.next_solution().unwrap() {
+        answers.push(subs.apply(&Term::Variable(var.to_string())).to_string());
+    }
+    answers
+}
+
+// What X is bound to in each answer to a query, joined by " ; "
+#[cfg(test)]
+pub(crate) fn answers(db: &mut Database, query: &str) -> String {
+    all_answers(db, query, "X").join(" ; ")
+}
+
+#[test]
+fn test_nondeterministic_list_builtins() {
+    let mut db = load_program("");
+    assert_eq!(all_answers(&mut db, "between(1, 3, X).", "X"), vec!["1", "2", "3"]);
+    assert_eq!(all_answers(&mut db, "between(1, inf, X), X > 2, !.", "X"), vec!["3"]);
+    assert_eq!(all_answers(&mut db, "member(X, [a, b, c]).", "X"), vec!["a", "b", "c"]);
+    assert_eq!(all_answers(&mut db, "append(X, Y, [1, 2]), P = X-Y.", "P"), vec!["[]-[1, 2]", "[1]-[2]", "[1, 2]-[]"]);
+    assert_eq!(all_answers(&mut db, "select(X, [a, b, c], R), P = X-R.", "P"), vec!["a-[b, c]", "b-[a, c]", "c-[a, b]"]);
+    assert_eq!(all_answers(&mut db, "select(x, L, [a, b]).", "L"), vec!["[x, a, b]", "[a, x, b]", "[a, b, x]"]);
+    assert_eq!(all_answers(&mut db, "nth0(I, [a, b], E), P = I-E.", "P"), vec!["0-a", "1-b"]);
+    assert_eq!(all_answers(&mut db, "nth1(2, [a, b, c], E).", "E"), vec!["b"]);
+    assert_eq!(all_answers(&mut db, "nth1(2, L, x), L = [y, _].", "L"), vec!["[y, x]"]);
+    assert_eq!(run_query(&mut db, "between(1, a, X)."), Err(errors::type_error("integer", Term::Constant("a".into()))));
+}
+
+#[test]
+fn test_multi_mode_arithmetic_and_lists() {
+    let mut db = load_program("");
+    assert_eq!(all_answers(&mut db, "succ(X, 5).", "X"), vec!["4"]);
+    assert_eq!(all_answers(&mut db, "plus(2, Y, 7).", "Y"), vec!["5"]);
+    assert_eq!(all_answers(&mut db, "reverse([1, 2], [2, 1]).", "_").len(), 1);
+    assert_eq!(all_answers(&mut db, "length(L, 2), L = [a|T], T = [b].", "L"), vec!["[a, b]"]);
+    assert_eq!(all_answers(&mut db, "length(L, N), N >= 2, !.", "N"), vec!["2"]);
+    assert_eq!(all_answers(&mut db, "length([a|T], N), N >= 3, !.", "N"), vec!["3"]);
+    assert_eq!(all_answers(&mut db, "reverse(L, [a, b]).", "L"), vec!["[b, a]"]);
+    assert_eq!(all_answers(&mut db, "max(3, 5, 5), min(3, 5, M).", "M"), vec!["3"]);
+    assert_eq!(run_query(&mut db, "succ(X, 0)."), Ok(None));
+    assert_eq!(run_query(&mut db, "length(L, a)."), Err(errors::type_error("integer", Term::Constant("a".into()))));
+    assert_eq!(run_query(&mut db, "plus(X, Y, 1)."), Err(errors::instantiation_error()));
+}
+
+#[test]
+fn test_type_checks() {
+    let mut db = load_program("");
+    let kinds = "findall(K, (member(K, [var, nonvar, atom, number, integer, atomic, compound, callable, is_list]), call(K, T)), X).";
+    assert_eq!(answers(&mut db, &format!("T = _, {}", kinds)), "[var]");
+    assert_eq!(answers(&mut db, &format!("T = a, {}", kinds)), "[nonvar, atom, atomic, callable]");
+    assert_eq!(answers(&mut db, &format!("T = 7, {}", kinds)), "[nonvar, number, integer, atomic]");
+    assert_eq!(answers(&mut db, &format!("T = [], {}", kinds)), "[nonvar, atom, atomic, callable, is_list]");
+    assert_eq!(answers(&mut db, &format!("T = [a|_], {}", kinds)), "[nonvar, compound, callable]");
+    assert_eq!(answers(&mut db, &format!("T = f(x), {}", kinds)), "[nonvar, compound, callable]");
+}
+
+#[test]
+fn test_once_ignore_and_not() {
+    let mut db = load_program("");
+    assert_eq!(answers(&mut db, "once(member(X, [1, 2]))."), "1");
+    assert_eq!(answers(&mut db, "ignore(member(X, [])), X = none."), "none");
+    assert_eq!(answers(&mut db, "ignore(member(X, [1, 2]))."), "1");
+    assert_eq!(answers(&mut db, "(not(member(c, [a, b])) -> X = yes ; X = no)."), "yes");
+    assert_eq!(answers(&mut db, "once((member(X, [1, 2, 3]), !, X > 0))."), "1");
+}
@@ -0,0 +1,522 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufWriter, ErrorKind, Write};
+
+use crate::errors;
+use crate::terms::Term;
+
+pub const USER_INPUT: usize = 0;
+pub const USER_OUTPUT: usize = 1;
+pub const USER_ERROR: usize = 2;
+
+// Encodings that map each byte to one character; anything else is read and written as UTF-8
+const SINGLE_BYTE_ENCODINGS: [&str; 3] = ["octet", "ascii", "iso_latin_1"];
+const ENCODINGS: [&str; 6] = ["utf8", "text", "unicode", "octet", "ascii", "iso_latin_1"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Read,
+    Write,
+    Append,
+}
+
+impl Mode {
+    pub fn parse(mode: &Term) -> Result<Self, Term> {
+        match mode {
+            Term::Variable(_) => Err(errors::instantiation_error()),
+            Term::Constant(name) => match name.as_str() {
+                "read" => Ok(Mode::Read),
+                "write" => Ok(Mode::Write),
+                "append" => Ok(Mode::Append),
+                _ => Err(errors::domain_error("io_mode", mode.clone())),
+            },
+            other => Err(errors::type_error("atom", other.clone())),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Read => "read",
+            Mode::Write => "write",
+            Mode::Append => "append",
+        }
+    }
+}
+
+// What reading past the end of a stream does
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EofAction {
+    Error,   // Raises permission_error(input, past_end_of_stream, S)
+    EofCode, // Returns end_of_file again
+    Reset,   // Tries again, for terminals where more input may come
+}
+
+impl EofAction {
+    fn name(self) -> &'static str {
+        match self {
+            EofAction::Error => "error",
+            EofAction::EofCode => "eof_code",
+            EofAction::Reset => "reset",
+        }
+    }
+}
+
+// The options of open/4
+pub struct StreamOptions {
+    binary: bool,
+    encoding: String,
+    alias: Option<String>,
+    eof_action: EofAction,
+}
+
+impl StreamOptions {
+    pub fn parse(options: &Term) -> Result<Self, Term> {
+        let list = match options {
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            list => list.to_vec().ok_or_else(|| errors::type_error("list", options.clone()))?,
+        };
+        let mut parsed = StreamOptions { binary: false, encoding: "utf8".to_string(), alias: None, eof_action: EofAction::EofCode };
+        for option in list {
+            let bad_option = || errors::domain_error("stream_option", option.clone());
+            let Term::Compound(name, args) = &option else {
+                return Err(match option {
+                    Term::Variable(_) => errors::instantiation_error(),
+                    _ => bad_option(),
+                });
+            };
+            match (name.as_str(), args.as_slice()) {
+                (_, [Term::Variable(_)]) => return Err(errors::instantiation_error()),
+                ("type", [Term::Constant(kind)]) if kind == "text" || kind == "binary" => parsed.binary = kind == "binary",
+                ("encoding", [Term::Constant(encoding)]) if ENCODINGS.contains(&encoding.as_str()) => parsed.encoding = encoding.clone(),
+                ("alias", [Term::Constant(alias)]) => parsed.alias = Some(alias.clone()),
+                ("eof_action", [Term::Constant(action)]) => parsed.eof_action = match action.as_str() {
+                    "error" => EofAction::Error,
+                    "eof_code" => EofAction::EofCode,
+                    "reset" => EofAction::Reset,
+                    _ => return Err(bad_option()),
+                },
+                ("reposition", [Term::Constant(flag)]) if flag == "true" || flag == "false" => {}
+                _ => return Err(bad_option()),
+            }
+        }
+        if parsed.binary {
+            parsed.encoding = "octet".to_string();
+        }
+        Ok(parsed)
+    }
+}
+
+#[derive(Debug)]
+enum Data {
+    Input { bytes: Vec<u8>, position: usize, stdin: bool, past_end: bool },
+    Console,          // user_output and user_error, collected in Database::output for the GUI
+    File(BufWriter<File>),
+    Memory(String),   // Output captured by with_output_to/2 and portray
+}
+
+#[derive(Debug)]
+pub struct Stream {
+    alias: Option<String>,
+    file_name: Option<String>,
+    mode: Mode,
+    binary: bool,
+    encoding: String,
+    eof_action: EofAction,
+    column: usize, // Characters written since the last newline, for format/2 column stops
+    data: Data,
+}
+
+impl Stream {
+    fn single_byte(&self) -> bool {
+        SINGLE_BYTE_ENCODINGS.contains(&self.encoding.as_str())
+    }
+
+    // The next character and the number of bytes it takes up
+    fn next_char(&self) -> Option<(char, usize)> {
+        let Data::Input { bytes, position, .. } = &self.data else { return None };
+        let rest = &bytes[*position..];
+        let first = *rest.first()?;
+        if self.single_byte() || first < 0x80 {
+            return Some((first as char, 1));
+        }
+        let length = match first {
+            0xF0.. => 4,
+            0xE0.. => 3,
+            _ => 2,
+        };
+        match std::str::from_utf8(&rest[..length.min(rest.len())]) {
+            Ok(text) => text.chars().next().map(|ch| (ch, length)),
+            Err(_) => Some((char::REPLACEMENT_CHARACTER, 1)),
+        }
+    }
+}
+
+// The open streams, with the current input and output. Streams are named by `'$stream'(N)` terms
+// or by their alias.
+#[derive(Debug)]
+pub struct Streams {
+    streams: BTreeMap<usize, Stream>,
+    next: usize,
+    pub input: usize,
+    pub output: usize,
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        let user = |alias: &str, mode, data| Stream {
+            alias: Some(alias.to_string()),
+            file_name: None,
+            mode,
+            binary: false,
+            encoding: "utf8".to_string(),
+            eof_action: EofAction::Reset,
+            column: 0,
+            data,
+        };
+        let stdin = Data::Input { bytes: vec![], position: 0, stdin: true, past_end: false };
+        let streams = BTreeMap::from([
+            (USER_INPUT, user("user_input", Mode::Read, stdin)),
+            (USER_OUTPUT, user("user_output", Mode::Append, Data::Console)),
+            (USER_ERROR, user("user_error", Mode::Append, Data::Console)),
+        ]);
+        Streams { streams, next: USER_ERROR + 1, input: USER_INPUT, output: USER_OUTPUT }
+    }
+}
+
+pub fn stream_term(id: usize) -> Term {
+    Term::Compound("$stream".to_string(), vec![Term::Integer(id as i64)])
+}
+
+impl Streams {
+    fn add(&mut self, stream: Stream) -> usize {
+        let id = self.next;
+        self.next += 1;
+        self.streams.insert(id, stream);
+        id
+    }
+
+    pub fn ids(&self) -> Vec<usize> {
+        self.streams.keys().copied().collect()
+    }
+
+    // The stream a `'$stream'(N)` term or an alias names
+    pub fn lookup(&self, term: &Term) -> Result<usize, Term> {
+        let id = match term {
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            Term::Constant(alias) => self.streams.iter()
+                .find(|(_, stream)| stream.alias.as_ref() == Some(alias))
+                .map(|(id, _)| *id),
+            Term::Compound(name, args) if name == "$stream" && args.len() == 1 => match args[0] {
+                Term::Integer(id) => usize::try_from(id).ok().filter(|id| self.streams.contains_key(id)),
+                _ => return Err(errors::domain_error("stream_or_alias", term.clone())),
+            },
+            _ => return Err(errors::domain_error("stream_or_alias", term.clone())),
+        };
+        id.ok_or_else(|| errors::existence_error("stream", term.clone()))
+    }
+
+    // A stream to read from: text streams for characters and terms, binary streams for bytes
+    pub fn input_stream(&self, term: &Term, binary: bool) -> Result<usize, Term> {
+        self.check(term, "input", binary)
+    }
+
+    pub fn output_stream(&self, term: &Term, binary: bool) -> Result<usize, Term> {
+        self.check(term, "output", binary)
+    }
+
+    fn check(&self, term: &Term, action: &str, binary: bool) -> Result<usize, Term> {
+        let id = self.lookup(term)?;
+        let stream = &self.streams[&id];
+        if (stream.mode == Mode::Read) != (action == "input") {
+            return Err(errors::permission_error(action, "stream", term.clone()));
+        }
+        match (stream.binary, binary) {
+            (true, false) => Err(errors::permission_error(action, "binary_stream", term.clone())),
+            (false, true) => Err(errors::permission_error(action, "text_stream", term.clone())),
+            _ => Ok(id),
+        }
+    }
+
+    pub fn open(&mut self, file: &Term, mode: Mode, options: StreamOptions) -> Result<usize, Term> {
+        let path = match file {
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            Term::Constant(path) => path.clone(),
+            other => return Err(errors::domain_error("source_sink", other.clone())),
+        };
+        if let Some(alias) = &options.alias {
+            if self.streams.values().any(|stream| stream.alias.as_ref() == Some(alias)) {
+                let culprit = Term::Compound("alias".to_string(), vec![Term::Constant(alias.clone())]);
+                return Err(errors::permission_error("open", "source_sink", culprit));
+            }
+        }
+        let opened = match mode {
+            Mode::Read => std::fs::read(&path).map(|bytes| Data::Input { bytes, position: 0, stdin: false, past_end: false }),
+            Mode::Write | Mode::Append => OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(mode == Mode::Append)
+                .truncate(mode == Mode::Write)
+                .open(&path)
+                .map(|file| Data::File(BufWriter::new(file))),
+        };
+        let data = opened.map_err(|error| match error.kind() {
+            ErrorKind::PermissionDenied => errors::permission_error("open", "source_sink", file.clone()),
+            _ => errors::existence_error("source_sink", file.clone()),
+        })?;
+        Ok(self.add(Stream {
+            alias: options.alias,
+            file_name: Some(path),
+            mode,
+            binary: options.binary,
+            encoding: options.encoding,
+            eof_action: options.eof_action,
+            column: 0,
+            data,
+        }))
+    }
+
+    // Closing a standard stream does nothing; closing the current input or output makes the
+    // standard one current again
+    pub fn close(&mut self, id: usize) -> Result<(), Term> {
+        if id <= USER_ERROR {
+            return Ok(());
+        }
+        if let Some(Stream { data: Data::File(mut file), .. }) = self.streams.remove(&id) {
+            file.flush().map_err(|_| errors::permission_error("output", "stream", stream_term(id)))?;
+        }
+        if self.input == id {
+            self.input = USER_INPUT;
+        }
+        if self.output == id {
+            self.output = USER_OUTPUT;
+        }
+        Ok(())
+    }
+
+    // Sends the current output to a new in-memory stream until `end_capture`, which puts the
+    // previous output back and returns what was written
+    pub fn begin_capture(&mut self) -> (usize, usize) {
+        let stream = Stream {
+            alias: None,
+            file_name: None,
+            mode: Mode::Write,
+            binary: false,
+            encoding: "utf8".to_string(),
+            eof_action: EofAction::EofCode,
+            column: 0,
+            data: Data::Memory(String::new()),
+        };
+        let id = self.add(stream);
+        (id, std::mem::replace(&mut self.output, id))
+    }
+
+    pub fn end_capture(&mut self, (id, previous): (usize, usize)) -> String {
+        self.output = if self.streams.contains_key(&previous) { previous } else { USER_OUTPUT };
+        match self.streams.remove(&id) {
+            Some(Stream { data: Data::Memory(text), .. }) => text,
+            _ => String::new(),
+        }
+    }
+
+    // Writes text; the standard output streams append to `console`
+    pub fn write(&mut self, id: usize, text: &str, console: &mut String) -> Result<(), Term> {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return Err(errors::existence_error("stream", stream_term(id)));
+        };
+        stream.column = match text.rfind('\n') {
+            Some(newline) => text[newline + 1..].chars().count(),
+            None => stream.column + text.chars().count(),
+        };
+        let single_byte = stream.single_byte();
+        let written = match &mut stream.data {
+            Data::Console => {
+                console.push_str(text);
+                Ok(())
+            }
+            Data::Memory(buffer) => {
+                buffer.push_str(text);
+                Ok(())
+            }
+            Data::File(file) if single_byte => {
+                file.write_all(&text.chars().map(|ch| u8::try_from(ch).unwrap_or(b'?')).collect::<Vec<u8>>())
+            }
+            Data::File(file) => file.write_all(text.as_bytes()),
+            Data::Input { .. } => return Err(errors::permission_error("output", "stream", stream_term(id))),
+        };
+        written.map_err(|_| errors::permission_error("output", "stream", stream_term(id)))
+    }
+
+    pub fn write_byte(&mut self, id: usize, byte: u8) -> Result<(), Term> {
+        if let Some(Stream { data: Data::File(file), .. }) = self.streams.get_mut(&id) {
+            file.write_all(&[byte]).map_err(|_| errors::permission_error("output", "stream", stream_term(id)))?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self, id: usize) -> Result<(), Term> {
+        if let Some(Stream { data: Data::File(file), .. }) = self.streams.get_mut(&id) {
+            file.flush().map_err(|_| errors::permission_error("output", "stream", stream_term(id)))?;
+        }
+        Ok(())
+    }
+
+    pub fn column(&self, id: usize) -> usize {
+        self.streams.get(&id).map_or(0, |stream| stream.column)
+    }
+
+    // Reads another line of standard input into the buffer; false once there is no more to read
+    pub fn fill(&mut self, id: usize) -> bool {
+        let Some(Stream { data: Data::Input { bytes, stdin: true, .. }, .. }) = self.streams.get_mut(&id) else {
+            return false;
+        };
+        let mut line = String::new();
+        match std::io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => false,
+            Ok(_) => {
+                bytes.extend_from_slice(line.as_bytes());
+                true
+            }
+        }
+    }
+
+    fn has_input(&mut self, id: usize) -> bool {
+        loop {
+            if matches!(&self.streams[&id].data, Data::Input { bytes, position, .. } if *position < bytes.len()) {
+                return true;
+            }
+            if !self.fill(id) {
+                return false;
+            }
+        }
+    }
+
+    // The unread text, for the term reader
+    pub fn remaining_text(&self, id: usize) -> String {
+        let stream = &self.streams[&id];
+        let Data::Input { bytes, position, .. } = &stream.data else { return String::new() };
+        let rest = &bytes[*position..];
+        if stream.single_byte() {
+            return rest.iter().map(|byte| *byte as char).collect();
+        }
+        match std::str::from_utf8(rest) {
+            Ok(text) => text.to_string(),
+            Err(error) => String::from_utf8_lossy(&rest[..error.valid_up_to()]).into_owned(),
+        }
+    }
+
+    // Moves past the first `end` bytes of text `remaining_text` returned
+    pub fn consume_text(&mut self, id: usize, text: &str, end: usize) {
+        let stream = self.streams.get_mut(&id).unwrap();
+        let length = if stream.single_byte() { text[..end].chars().count() } else { end };
+        if let Data::Input { position, .. } = &mut stream.data {
+            *position += length;
+        }
+    }
+
+    // Reads (or with `peek`, looks at) the next character or byte. None is the end of the stream;
+    // reading on past it does what the stream's eof_action says.
+    pub fn get(&mut self, id: usize, peek: bool, binary: bool) -> Result<Option<u32>, Term> {
+        let available = self.has_input(id);
+        let stream = self.streams.get_mut(&id).unwrap();
+        let eof_action = stream.eof_action;
+        let next = match binary {
+            true => match &stream.data {
+                Data::Input { bytes, position, .. } => bytes.get(*position).map(|byte| (*byte as u32, 1)),
+                _ => None,
+            },
+            false => stream.next_char().map(|(ch, length)| (ch as u32, length)),
+        };
+        let Data::Input { position, past_end, .. } = &mut stream.data else { return Ok(None) };
+        match next {
+            Some((value, length)) if available => {
+                if !peek {
+                    *position += length;
+                }
+                Ok(Some(value))
+            }
+            _ if *past_end && eof_action == EofAction::Error => {
+                Err(errors::permission_error("input", "past_end_of_stream", stream_term(id)))
+            }
+            _ => {
+                if !peek && eof_action != EofAction::Reset {
+                    *past_end = true;
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn at_end(&mut self, id: usize) -> bool {
+        matches!(self.streams[&id].data, Data::Input { .. }) && !self.has_input(id)
+    }
+
+    // The properties stream_property/2 reports
+    pub fn properties(&self, id: usize) -> Vec<Term> {
+        let stream = &self.streams[&id];
+        let property = |name: &str, value: &str| Term::Compound(name.to_string(), vec![Term::Constant(value.to_string())]);
+        let mut properties = vec![
+            Term::Constant(if stream.mode == Mode::Read { "input" } else { "output" }.to_string()),
+            property("mode", stream.mode.name()),
+            property("type", if stream.binary { "binary" } else { "text" }),
+            property("encoding", &stream.encoding),
+            property("eof_action", stream.eof_action.name()),
+        ];
+        if let Some(file_name) = &stream.file_name {
+            properties.push(property("file_name", file_name));
+        }
+        if let Some(alias) = &stream.alias {
+            properties.push(property("alias", alias));
+        }
+        if let Data::Input { bytes, position, stdin, past_end } = &stream.data {
+            let state = match (past_end, *position >= bytes.len() && !stdin) {
+                (true, _) => "past",
+                (false, true) => "at",
+                _ => "not",
+            };
+            properties.push(property("end_of_stream", state));
+        }
+        properties
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(binary: bool) -> StreamOptions {
+        StreamOptions { binary, encoding: "utf8".to_string(), alias: None, eof_action: EofAction::Error }
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("streams_test_{}.txt", std::process::id()));
+        let file = Term::Constant(path.to_string_lossy().into_owned());
+        let mut streams = Streams::default();
+        let mut console = String::new();
+        let out = streams.open(&file, Mode::Write, options(false)).unwrap();
+        streams.write(out, "hé\n", &mut console).unwrap();
+        streams.close(out).unwrap();
+
+        let input = streams.open(&file, Mode::Read, options(false)).unwrap();
+        assert_eq!(streams.get(input, true, false).unwrap(), Some('h' as u32));
+        assert_eq!(streams.get(input, false, false).unwrap(), Some('h' as u32));
+        assert_eq!(streams.get(input, false, false).unwrap(), Some('é' as u32));
+        assert_eq!(streams.get(input, false, false).unwrap(), Some('\n' as u32));
+        assert_eq!(streams.get(input, false, false).unwrap(), None);
+        assert_eq!(streams.get(input, false, false), Err(errors::permission_error("input", "past_end_of_stream", stream_term(input))));
+        std::fs::remove_file(&path).unwrap();
+        assert!(console.is_empty());
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        let mut streams = Streams::default();
+        let missing = Term::Constant("/nonexistent/dir/file".to_string());
+        assert_eq!(streams.open(&missing, Mode::Read, options(false)), Err(errors::existence_error("source_sink", missing)));
+        let user_input = Term::Constant("user_input".to_string());
+        assert_eq!(streams.output_stream(&user_input, false), Err(errors::permission_error("output", "stream", user_input)));
+        let user_output = Term::Constant("user_output".to_string());
+        assert_eq!(streams.output_stream(&user_output, true), Err(errors::permission_error("output", "text_stream", user_output)));
+        assert_eq!(streams.lookup(&stream_term(42)), Err(errors::existence_error("stream", stream_term(42))));
+    }
+}
@@ -1,5 +1,8 @@
 use crate::parser::tree::{ TermKind, ExprKind, Clause as TreeClause, Term as TreeTerm };
 use crate::unification::Substitution;
+use crate::errors;
+use crate::writer;
+use std::cmp::Ordering;
 use std::fmt;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -60,22 +63,191 @@ impl Term {
         }
         list
     }
+
+    // Name and arity of a callable term, e.g. ("parent", 2) for parent(X, Y)
+    pub fn predicate_key(&self) -> Option<(String, usize)> {
+        match self {
+            Term::Constant(name) => Some((name.clone(), 0)),
+            Term::Compound(name, args) => Some((name.clone(), args.len())),
+            _ => None,
+        }
+    }
+
+    pub fn is_callable(&self) -> bool {
+        matches!(self, Term::Constant(_) | Term::Compound(_, _))
+    }
+
+    // Names of the variables in the term, in order of first appearance
+    pub fn variables(&self) -> Vec<String> {
+        fn collect(term: &Term, vars: &mut Vec<String>) {
+            match term {
+                Term::Variable(name) if !vars.contains(name) => vars.push(name.clone()),
+                Term::Compound(_, args) => args.iter().for_each(|arg| collect(arg, vars)),
+                Term::List(head, tail) => {
+                    collect(head, vars);
+                    collect(tail, vars);
+                }
+                _ => {}
+            }
+        }
+        let mut vars = vec![];
+        collect(self, &mut vars);
+        vars
+    }
     
+    // The standard order of terms: variables, then numbers, then atoms, then compound terms by
+    // arity, name and arguments from left to right. Lists are compound terms '[|]'(Head, Tail).
+    pub fn standard_order(&self, other: &Term) -> Ordering {
+        self.standard_order_by(other, &mut |arg, other_arg| arg.standard_order(other_arg))
+    }
+
+    // The standard order, with the arguments of two compound terms compared by `compare_args`
+    pub fn standard_order_by(&self, other: &Term, compare_args: &mut dyn FnMut(&Term, &Term) -> Ordering) -> Ordering {
+        fn rank(term: &Term) -> u8 {
+            match term {
+                Term::Variable(_) => 0,
+                Term::Integer(_) => 1,
+                Term::Constant(_) | Term::EmptyList => 2,
+                Term::Compound(_, _) | Term::List(_, _) => 3,
+            }
+        }
+        fn parts(term: &Term) -> (&str, Vec<&Term>) {
+            match term {
+                Term::Compound(name, args) => (name, args.iter().collect()),
+                Term::List(head, tail) => ("[|]", vec![&**head, &**tail]),
+                Term::EmptyList => ("[]", vec![]),
+                Term::Constant(name) | Term::Variable(name) => (name, vec![]),
+                Term::Integer(_) => ("", vec![]),
+            }
+        }
+        match (self, other) {
+            (Term::Integer(a), Term::Integer(b)) => a.cmp(b),
+            _ if rank(self) != rank(other) => rank(self).cmp(&rank(other)),
+            _ => {
+                let ((name, args), (other_name, other_args)) = (parts(self), parts(other));
+                args.len().cmp(&other_args.len())
+                    .then_with(|| name.cmp(other_name))
+                    .then_with(|| args.iter().zip(&other_args).map(|(a, b)| compare_args(a, b)).find(|o| o.is_ne()).unwrap_or(Ordering::Equal))
+            }
+        }
+    }
+
+    // Whether two terms are equal up to a consistent renaming of their variables (=@=)
+    pub fn is_variant(&self, other: &Term) -> bool {
+        fn walk<'a>(a: &'a Term, b: &'a Term, pairs: &mut Vec<(&'a str, &'a str)>) -> bool {
+            match (a, b) {
+                (Term::Variable(x), Term::Variable(y)) => match pairs.iter().find(|(p, q)| p == x || q == y) {
+                    Some((p, q)) => p == x && q == y,
+                    None => {
+                        pairs.push((x, y));
+                        true
+                    }
+                },
+                (Term::Compound(f, xs), Term::Compound(g, ys)) => {
+                    f == g && xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| walk(x, y, pairs))
+                }
+                (Term::List(h1, t1), Term::List(h2, t2)) => walk(h1, h2, pairs) && walk(t1, t2, pairs),
+                (Term::Variable(_), _) | (_, Term::Variable(_)) => false,
+                _ => a == b,
+            }
+        }
+        walk(self, other, &mut vec![])
+    }
+
+    // Whether `specific` is an instance of this term, binding only variables of this term and
+    // leaving `specific` unchanged (subsumes_term/2)
+    pub fn subsumes(&self, specific: &Term) -> bool {
+        fn walk<'a>(general: &'a Term, specific: &'a Term, bindings: &mut Vec<(&'a str, &'a Term)>) -> bool {
+            match (general, specific) {
+                (Term::Variable(x), _) => match bindings.iter().find(|(var, _)| var == x) {
+                    Some((_, bound)) => *bound == specific,
+                    None => {
+                        bindings.push((x, specific));
+                        true
+                    }
+                },
+                (Term::Compound(f, xs), Term::Compound(g, ys)) => {
+                    f == g && xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| walk(x, y, bindings))
+                }
+                (Term::List(h1, t1), Term::List(h2, t2)) => walk(h1, h2, bindings) && walk(t1, t2, bindings),
+                (_, Term::Variable(_)) => false,
+                _ => general == specific,
+            }
+        }
+        let mut bindings = vec![];
+        if !walk(self, specific, &mut bindings) {
+            return false;
+        }
+        // A variable shared with `specific` may only be bound to itself
+        let shared = specific.variables();
+        bindings.iter().all(|(var, bound)| !shared.iter().any(|name| name == var) || **bound == Term::Variable(var.to_string()))
+    }
+
+    // The most specific term that both terms are instances of (term_subsumer/3). Each pair of
+    // differing subterms becomes a variable named by `fresh`, the same one wherever the pair recurs.
+    pub fn generalise(&self, other: &Term, fresh: &mut dyn FnMut() -> Term) -> Term {
+        fn walk(a: &Term, b: &Term, pairs: &mut Vec<(Term, Term, Term)>, fresh: &mut dyn FnMut() -> Term) -> Term {
+            match (a, b) {
+                _ if a == b => a.clone(),
+                (Term::Compound(f, xs), Term::Compound(g, ys)) if f == g && xs.len() == ys.len() => {
+                    Term::Compound(f.clone(), xs.iter().zip(ys).map(|(x, y)| walk(x, y, pairs, fresh)).collect())
+                }
+                (Term::List(h1, t1), Term::List(h2, t2)) => {
+                    Term::List(Box::new(walk(h1, h2, pairs, fresh)), Box::new(walk(t1, t2, pairs, fresh)))
+                }
+                _ => match pairs.iter().find(|(x, y, _)| x == a && y == b) {
+                    Some((_, _, var)) => var.clone(),
+                    None => {
+                        let var = fresh();
+                        pairs.push((a.clone(), b.clone(), var.clone()));
+                        var
+                    }
+                },
+            }
+        }
+        walk(self, other, &mut vec![], fresh)
+    }
+
+    // A hash of the term's structure down to `depth` levels, the same in every run; None if a
+    // variable is reached first
+    pub fn structural_hash(&self, depth: usize) -> Option<u64> {
+        // 64-bit FNV-1a
+        fn feed(hash: &mut u64, bytes: &[u8]) {
+            for byte in bytes {
+                *hash ^= *byte as u64;
+                *hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        fn walk(term: &Term, depth: usize, hash: &mut u64) -> Option<()> {
+            if depth == 0 {
+                return Some(());
+            }
+            match term {
+                Term::Variable(_) => return None,
+                Term::Integer(n) => feed(hash, &[&[b'i'][..], &n.to_le_bytes()].concat()),
+                Term::Constant(name) => feed(hash, &[&[b'a'][..], name.as_bytes(), &[0]].concat()),
+                Term::EmptyList => feed(hash, b"a[]\0"),
+                Term::Compound(name, args) => {
+                    feed(hash, &[&[b'c'][..], name.as_bytes(), &[0], &(args.len() as u32).to_le_bytes()].concat());
+                    args.iter().try_for_each(|arg| walk(arg, depth - 1, hash))?;
+                }
+                Term::List(head, tail) => {
+                    feed(hash, &[&b"c[|]\0"[..], &2u32.to_le_bytes()].concat());
+                    walk(head, depth - 1, hash)?;
+                    walk(tail, depth - 1, hash)?;
+                }
+            }
+            Some(())
+        }
+        let mut hash = 0xcbf29ce484222325;
+        walk(self, depth, &mut hash)?;
+        Some(hash)
+    }
 }
 
 impl fmt::Display for Term {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Term::Variable(name) => write!(f, "{}", name),
-            Term::Integer(n) => write!(f, "{}", n),
-            Term::Constant(name) => write!(f, "{}", name),
-            Term::Compound(name, args) => {
-                let args_str: Vec<String> = args.iter().map(|arg| format!("{}", arg)).collect();
-                write!(f, "{}({})", name, args_str.join(", "))
-            }
-            Term::List(head, tail) => write!(f, "[{} | {}]", head, tail),
-            Term::EmptyList => write!(f, "[]"),
-        }
+        write!(f, "{}", writer::term_to_string(self, writer::default_operators()))
     }
 }
 
@@ -84,6 +256,7 @@ impl fmt::Display for Term {
 pub enum Clause {
     Fact(Term),
     Rule(Term, Expression),
+    Directive(Expression),
 }
 
 impl Clause {
@@ -94,10 +267,72 @@ impl Clause {
                 Term::from_tree_term(head),
                 Expression::from_tree_expr(body),
             ),
+            TreeClause::Directive(body) => Clause::Directive(Expression::from_tree_expr(body)),
+        }
+    }
+
+    // Builds a clause from a term such as `foo(X) :- bar(X)`, as passed to assert/1
+    pub fn from_term(term: Term) -> Result<Self, Term> {
+        let (head, body) = match term {
+            Term::Compound(name, mut args) if name == ":-" && args.len() == 2 => {
+                let body = args.pop().unwrap();
+                (args.pop().unwrap(), Some(body))
+            }
+            term => (term, None),
+        };
+        match head {
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            ref head if !head.is_callable() => return Err(errors::type_error("callable", head.clone())),
+            _ => {}
+        }
+        match body {
+            None => Ok(Clause::Fact(head)),
+            Some(Term::Constant(ref name)) if name == "true" => Ok(Clause::Fact(head)),
+            Some(body) => {
+                if let Some(culprit) = non_callable_goal(&body) {
+                    return Err(errors::type_error("callable", culprit));
+                }
+                Ok(Clause::Rule(head, Expression::from_body_term(body)))
+            }
+        }
+    }
+
+    // The clause as a term, the inverse of `from_term`
+    pub fn to_term(&self) -> Term {
+        match self {
+            Clause::Fact(head) => head.clone(),
+            Clause::Rule(head, body) => Term::Compound(":-".to_string(), vec![head.clone(), body.to_term()]),
+            Clause::Directive(body) => Term::Compound(":-".to_string(), vec![body.to_term()]),
+        }
+    }
+
+    pub fn head(&self) -> Option<&Term> {
+        match self {
+            Clause::Fact(head) | Clause::Rule(head, _) => Some(head),
+            Clause::Directive(_) => None,
+        }
+    }
+
+    // The clause body as a term, `true` for facts
+    pub fn body_term(&self) -> Term {
+        match self {
+            Clause::Rule(_, body) | Clause::Directive(body) => body.to_term(),
+            Clause::Fact(_) => Term::Constant("true".to_string()),
         }
     }
 }
 
+// Finds a goal in a clause body that can never be called, such as an integer
+fn non_callable_goal(body: &Term) -> Option<Term> {
+    match body {
+        Term::Compound(name, args) if args.len() == 2 && [",", ";", "->"].contains(&name.as_str()) => {
+            non_callable_goal(&args[0]).or_else(|| non_callable_goal(&args[1]))
+        }
+        Term::Variable(_) | Term::Constant(_) | Term::Compound(_, _) => None,
+        _ => Some(body.clone()),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
     Term(Term),
@@ -126,12 +361,37 @@ impl Expression {
     pub fn from_term(term: Term) -> Self {
         Expression::Term(term)  // Wraps a single term into an expression
     }
+
+    // Splits a `(A, B)` term into a conjunction
+    pub fn from_body_term(term: Term) -> Self {
+        match term {
+            Term::Compound(name, mut args) if name == "," && args.len() == 2 => {
+                let right = args.pop().unwrap();
+                let left = args.pop().unwrap();
+                Expression::Conjunct(
+                    Box::new(Expression::from_body_term(left)),
+                    Box::new(Expression::from_body_term(right)),
+                )
+            }
+            term => Expression::Term(term),
+        }
+    }
+
+    pub fn to_term(&self) -> Term {
+        match self {
+            Expression::Term(term) => term.clone(),
+            Expression::Conjunct(left, right) => {
+                Term::Compound(",".to_string(), vec![left.to_term(), right.to_term()])
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::unification::Substitution;
+    use crate::solver::{answers, load_program};
 
     #[test]
     fn test_create_constant() {
@@ -184,6 +444,16 @@ mod tests {
         assert_eq!(format!("{}", term), "likes(john, pizza)");
     }
 
+    #[test]
+    fn test_structural_hash_is_fixed() {
+        let atom = |name: &str| Term::Constant(name.to_string());
+        assert_eq!(atom("a").structural_hash(usize::MAX), Some(0xe71c9d19053f18f5));
+        let (fa, fb) = (Term::Compound("f".into(), vec![atom("a")]), Term::Compound("f".into(), vec![atom("b")]));
+        assert_eq!(fa.structural_hash(1), fb.structural_hash(1));
+        assert_ne!(fa.structural_hash(2), fb.structural_hash(2));
+        assert_eq!(Term::Compound("f".into(), vec![Term::Variable("X".into())]).structural_hash(2), None);
+    }
+
     #[test]
     fn test_expression_from_term() {
         let term = Term::Variable("X".to_string());
@@ -239,5 +509,27 @@ mod tests {
         let clause = Clause::Rule(head.clone(), body.clone());
         assert_eq!(clause, Clause::Rule(head, body));
     }
-}
 
+    #[test]
+    fn test_variant_and_subsumption_builtins() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "term_variables(f(A, g(B, A), _C), X)."), "[A, B, _C]");
+        assert_eq!(answers(&mut db, "(f(A, B, A) =@= f(C, D, C) -> X = yes ; X = no)."), "yes");
+        assert_eq!(answers(&mut db, "(f(A, B, A) =@= f(C, C, C) -> X = yes ; X = no)."), "no");
+        assert_eq!(answers(&mut db, "(f(A, b) \\=@= f(B, b) -> X = yes ; X = no)."), "no");
+        assert_eq!(answers(&mut db, "(subsumes_term(f(_, b), f(a, b)) -> X = yes ; X = no)."), "yes");
+        assert_eq!(answers(&mut db, "(subsumes_term(f(a, b), f(_, b)) -> X = yes ; X = no)."), "no");
+        assert_eq!(answers(&mut db, "(subsumes_term(A, f(A)) -> X = yes ; X = no)."), "no");
+        assert_eq!(answers(&mut db, "term_subsumer(f(a, g(b), a), f(c, g(b), c), T), T = f(P, Q, R), X = Q."), "g(b)");
+        assert_eq!(answers(&mut db, "term_subsumer(f(a, a), f(c, c), f(P, Q)), P = z, X = Q."), "z");
+    }
+
+    #[test]
+    fn test_hashing_and_numbering_builtins() {
+        let mut db = load_program("");
+        assert_eq!(answers(&mut db, "term_hash(f(a, [1]), H), term_hash(f(a, [1]), H), X = equal."), "equal");
+        assert_eq!(answers(&mut db, "term_hash(f(A), H), H = unbound, X = H."), "unbound");
+        assert_eq!(answers(&mut db, "term_hash(f(a, b), 1, 10, H), term_hash(f(c, d), 1, 10, H), X = ok."), "ok");
+        assert_eq!(answers(&mut db, "T = f(A, B, A), numbervars(T, 0, End), X = T-End."), "f(A, B, A)-2");
+    }
+}
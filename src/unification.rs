@@ -1,34 +1,56 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use crate::errors;
 use crate::terms::Term;
 
+// The occurs_check flag: whether binding a variable to a term containing it fails, is allowed
+// (making a cyclic term) or raises an error
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OccursCheck {
+    #[default]
+    True,
+    False,
+    Error,
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct Substitution(HashMap<String, Term>);
+pub struct Substitution {
+    bindings: HashMap<String, Term>,
+    trail: Vec<String>, // Variables in the order they were bound, so bindings can be undone
+    pub occurs_check: OccursCheck,
+    unchecked: bool,            // A binding was made without the occurs check, so terms may be cyclic
+    occurs_error: Option<Term>, // The error a unification raised with occurs_check set to error
+}
 
 impl Substitution {
     pub fn new() -> Self {
-        Substitution(HashMap::new())
+        Substitution {
+            bindings: HashMap::new(),
+            trail: Vec::new(),
+            occurs_check: OccursCheck::True,
+            unchecked: false,
+            occurs_error: None,
+        }
+    }
+
+    // An empty substitution for terms taken from this one, which may be cyclic if its terms may be
+    pub fn empty_like(&self) -> Self {
+        Substitution { occurs_check: self.occurs_check, unchecked: self.unchecked, ..Substitution::new() }
     }
 
     pub fn resolve(&self, term: &Term) -> Term {
-        match term {
-            Term::Variable(var) => {
-                if let Some(val) = self.get(var) {
-                    self.resolve(val)
-                } else {
-                    term.clone()
-                }
-            }
-            Term::Compound(name, args) => {
-                Term::Compound(name.clone(), args.iter().map(|t| self.resolve(t)).collect())
-            }
-            _ => term.clone(),
-        }
+        self.apply(term)
     }
 
+    // Replaces bound variables by their values. In a cyclic term, a variable met again inside its
+    // own value is left in place, so `X = f(X)` gives f(X).
     pub fn apply(&self, term: &Term) -> Term {
+        if self.unchecked {
+            return self.apply_acyclic(term, &mut HashSet::new());
+        }
         match term {
             Term::Variable(name) => {
-                if let Some(substituted_term) = self.0.get(name) {
+                if let Some(substituted_term) = self.bindings.get(name) {
                     self.apply(substituted_term) // Recursively apply substitution
                 } else {
                     term.clone()
@@ -46,81 +68,234 @@ impl Substitution {
                 Term::List(new_head, new_tail)
             }
         }
-    }  
+    }
+
+    // `expanding` holds the variables whose values are being substituted
+    fn apply_acyclic(&self, term: &Term, expanding: &mut HashSet<String>) -> Term {
+        match term {
+            Term::Variable(name) => match self.bindings.get(name) {
+                Some(value) if expanding.insert(name.clone()) => {
+                    let applied = self.apply_acyclic(value, expanding);
+                    expanding.remove(name);
+                    applied
+                }
+                _ => term.clone(),
+            },
+            Term::Compound(name, args) => {
+                Term::Compound(name.clone(), args.iter().map(|arg| self.apply_acyclic(arg, expanding)).collect())
+            }
+            Term::List(head, tail) => Term::List(
+                Box::new(self.apply_acyclic(head, expanding)),
+                Box::new(self.apply_acyclic(tail, expanding)),
+            ),
+            _ => term.clone(),
+        }
+    }
+
+    // Whether the term, with the bindings followed, contains itself
+    pub fn is_cyclic(&self, term: &Term) -> bool {
+        fn walk(subs: &Substitution, term: &Term, expanding: &mut HashSet<String>) -> bool {
+            match term {
+                Term::Variable(name) => match subs.bindings.get(name) {
+                    Some(_) if expanding.contains(name) => true,
+                    Some(value) => {
+                        expanding.insert(name.clone());
+                        let cyclic = walk(subs, value, expanding);
+                        expanding.remove(name);
+                        cyclic
+                    }
+                    None => false,
+                },
+                Term::Compound(_, args) => args.iter().any(|arg| walk(subs, arg, expanding)),
+                Term::List(head, tail) => walk(subs, head, expanding) || walk(subs, tail, expanding),
+                _ => false,
+            }
+        }
+        self.unchecked && walk(self, term, &mut HashSet::new())
+    }
+
+    // Names of the unbound variables in the term with the bindings followed, in order of first
+    // appearance. Each bound variable is followed once, so a cyclic term gives its variables too.
+    pub fn variables(&self, term: &Term) -> Vec<String> {
+        fn collect(subs: &Substitution, term: &Term, followed: &mut HashSet<String>, vars: &mut Vec<String>) {
+            match term {
+                Term::Variable(name) => match subs.bindings.get(name) {
+                    Some(value) if followed.insert(name.clone()) => collect(subs, value, followed, vars),
+                    Some(_) => {}
+                    None if !vars.contains(name) => vars.push(name.clone()),
+                    None => {}
+                },
+                Term::Compound(_, args) => args.iter().for_each(|arg| collect(subs, arg, followed, vars)),
+                Term::List(head, tail) => {
+                    collect(subs, head, followed, vars);
+                    collect(subs, tail, followed, vars);
+                }
+                _ => {}
+            }
+        }
+        let mut vars = vec![];
+        collect(self, term, &mut HashSet::new(), &mut vars);
+        vars
+    }
+
+    // Compares two terms in the standard order with the bindings followed. In cyclic terms, a pair
+    // of subterms met again after following a binding compares as equal, as unification does with
+    // pairs of variables; subterms are told apart by address, as they don't move while comparing.
+    pub fn compare(&self, left: &Term, right: &Term) -> Ordering {
+        fn compare(subs: &Substitution, left: &Term, right: &Term, visited: &mut HashSet<(*const Term, *const Term)>) -> Ordering {
+            let bound = |term: &Term| matches!(term, Term::Variable(name) if subs.bindings.contains_key(name));
+            if subs.unchecked && (bound(left) || bound(right)) && !visited.insert((left, right)) {
+                return Ordering::Equal;
+            }
+            subs.walk(left).standard_order_by(subs.walk(right), &mut |arg, other_arg| compare(subs, arg, other_arg, visited))
+        }
+        compare(self, left, right, &mut HashSet::new())
+    }
+
+    // The error raised by the last unification under occurs_check=error, if any
+    pub fn take_occurs_error(&mut self) -> Option<Term> {
+        self.occurs_error.take()
+    }
+
+    // Follows variable bindings until reaching an unbound variable or a non-variable term
+    pub fn walk<'a>(&'a self, term: &'a Term) -> &'a Term {
+        let mut current = term;
+        while let Term::Variable(name) = current {
+            match self.bindings.get(name) {
+                Some(next) => current = next,
+                None => break,
+            }
+        }
+        current
+    }
 
     pub fn extend(&mut self, var: String, term: Term) {
-        self.0.insert(var, term);
+        self.trail.push(var.clone());
+        self.bindings.insert(var, term);
+    }
+
+    // Current position in the trail, used to undo bindings made after this point
+    pub fn mark(&self) -> usize {
+        self.trail.len()
+    }
+
+    pub fn undo_to(&mut self, mark: usize) {
+        while self.trail.len() > mark {
+            if let Some(var) = self.trail.pop() {
+                self.bindings.remove(&var);
+            }
+        }
     }
 
     pub fn merge(&self, other: &Substitution) -> Option<Substitution> {
-        if other.0.is_empty() {
+        if other.bindings.is_empty() {
             return Some(self.clone()); // If `other` is empty, return `self`
         }
-        if self.0.is_empty() {
+        if self.bindings.is_empty() {
             return Some(other.clone()); // If `self` is empty, return `other`
         }
         let mut merged = self.clone();
-        for (key, value) in &other.0 {
-            if let Some(existing) = merged.0.get(key) {
+        for (key, value) in &other.bindings {
+            if let Some(existing) = merged.bindings.get(key) {
                 if existing != value {
                     return None; // Conflict detected
                 }
             } else {
-                merged.0.insert(key.clone(), value.clone()); // Correctly update the HashMap
+                merged.extend(key.clone(), value.clone());
             }
         }
         Some(merged)
     }
 
     pub fn get(&self, var: &str) -> Option<&Term> {
-        self.0.get(var) // Access the internal map safely
+        self.bindings.get(var) // Access the internal map safely
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Term)> {
+        self.bindings.iter()
     }
 }
 
+// Unifies two terms, extending `subst`. On failure every binding made during the attempt is undone.
 pub fn unify(term1: &Term, term2: &Term, subst: &mut Substitution) -> bool {
+    let mark = subst.mark();
+    if unify_terms(term1, term2, subst, &mut HashSet::new()) {
+        true
+    } else {
+        subst.undo_to(mark);
+        false
+    }
+}
+
+// `visited` holds the pairs of bound variables already being unified. Meeting a pair again means
+// both terms are cyclic there, and the pair is taken to unify, so that unification terminates.
+fn unify_terms(term1: &Term, term2: &Term, subst: &mut Substitution, visited: &mut HashSet<(String, String)>) -> bool {
+    if let (Term::Variable(x), Term::Variable(y)) = (term1, term2) {
+        if subst.unchecked && subst.get(x).is_some() && subst.get(y).is_some() && !visited.insert((x.clone(), y.clone())) {
+            return true;
+        }
+    }
+    let term1 = subst.walk(term1).clone();
+    let term2 = subst.walk(term2).clone();
     if term1 == term2 { return true } // Stop immediately if the terms are already equal
-    match (term1, term2) {
+    match (&term1, &term2) {
         (Term::Variable(x), t) | (t, Term::Variable(x)) => {
-            if t != term1 && occurs_check(x, t) { return false }
-            if let Some(existing) = subst.get(x) {
-                if existing != t { return false } // Stops an overwrite if 'x' is already bound
+            match subst.occurs_check {
+                OccursCheck::True if occurs_check(x, t, subst) => return false,
+                OccursCheck::Error if occurs_check(x, t, subst) => {
+                    subst.occurs_error = Some(errors::occurs_check_error(term1.clone(), subst.apply(t)));
+                    return false;
+                }
+                OccursCheck::False => subst.unchecked = true,
+                _ => {}
             }
             subst.extend(x.clone(), t.clone()); // Variable unification
-            return true;
+            true
         }
         (Term::Constant(a), Term::Constant(b)) => a == b, // Constant unification
         (Term::Integer(a), Term::Integer(b)) => a == b, // Integer unification
         (Term::Compound(name1, args1), 
         Term::Compound(name2, args2)) => {
-            name1 == name2 && unify_lists(args1, args2, subst)
+            name1 == name2 && unify_lists(args1, args2, subst, visited)
         }
         (Term::List(head1, tail1), 
         Term::List(head2, tail2)) => {
-            unify(head1, head2, subst) && unify(tail1, tail2, subst)
+            unify_terms(head1, head2, subst, visited) && unify_terms(tail1, tail2, subst, visited)
         }
         (Term::EmptyList, Term::EmptyList) => true, // Empty lists are equal
         _ => false, // Mismatched structures
     }
 }
 
-fn unify_lists(list1: &[Term], list2: &[Term], subst: &mut Substitution) -> bool {
+fn unify_lists(list1: &[Term], list2: &[Term], subst: &mut Substitution, visited: &mut HashSet<(String, String)>) -> bool {
     if list1.len() != list2.len() { return false }
-    list1.iter().zip(list2.iter()).all(|(t1, t2)| unify(t1, t2, subst))
+    list1.iter().zip(list2.iter()).all(|(t1, t2)| unify_terms(t1, t2, subst, visited))
 }
 
-fn occurs_check(var: &str, term: &Term) -> bool {
-    match term {
-        Term::Variable(v) => v == var,
-        Term::Compound(_, args) => args.iter().any(|t| occurs_check(var, t)),
-        Term::List(head, tail) => occurs_check(var, head) || occurs_check(var, tail),
-        _ => false,
+// Whether `var` occurs in `term`. Terms are acyclic unless a binding was made with the check off;
+// only then are the variables already followed remembered, so that a cycle is not followed forever.
+fn occurs_check(var: &str, term: &Term, subst: &Substitution) -> bool {
+    fn walk(var: &str, term: &Term, subst: &Substitution, followed: &mut Option<HashSet<String>>) -> bool {
+        match term {
+            Term::Variable(v) if v == var => true,
+            Term::Variable(v) => match subst.get(v) {
+                Some(_) if followed.as_mut().is_some_and(|followed| !followed.insert(v.clone())) => false,
+                Some(value) => walk(var, value, subst, followed),
+                None => false,
+            },
+            Term::Compound(_, args) => args.iter().any(|t| walk(var, t, subst, followed)),
+            Term::List(head, tail) => walk(var, head, subst, followed) || walk(var, tail, subst, followed),
+            _ => false,
+        }
     }
+    walk(var, term, subst, &mut subst.unchecked.then(HashSet::new))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::terms::Term;
+    use crate::solver::{answers, load_program};
 
     #[test]
     fn test_extend_and_get() {
@@ -234,4 +409,43 @@ mod tests {
         let t2 = Term::Compound("f".to_string(), vec![Term::Variable("X".to_string())]);
         assert!(!unify(&t1, &t2, &mut subs)); // Should fail due to occurs check
     }
+
+    #[test]
+    fn test_failed_unification_leaves_no_bindings() {
+        let mut subs = Substitution::new();
+        let t1 = Term::Compound("f".to_string(), vec![Term::Variable("X".to_string()), Term::Integer(1)]);
+        let t2 = Term::Compound("f".to_string(), vec![Term::Integer(5), Term::Integer(2)]);
+        assert!(!unify(&t1, &t2, &mut subs));
+        assert_eq!(subs.get("X"), None);
+    }
+
+    #[test]
+    fn test_undo_to_mark() {
+        let mut subs = Substitution::new();
+        subs.extend("X".to_string(), Term::Integer(1));
+        let mark = subs.mark();
+        assert!(unify(&Term::Variable("Y".to_string()), &Term::Variable("X".to_string()), &mut subs));
+        assert_eq!(subs.walk(&Term::Variable("Y".to_string())), &Term::Integer(1));
+        subs.undo_to(mark);
+        assert_eq!(subs.get("Y"), None);
+        assert_eq!(subs.get("X"), Some(&Term::Integer(1)));
+    }
+
+    #[test]
+    fn test_cyclic_terms_without_the_occurs_check() {
+        let mut db = load_program(":- set_prolog_flag(occurs_check, false).");
+        assert_eq!(answers(&mut db, "X = f(X)."), "f(X)");
+        assert_eq!(answers(&mut db, "A = f(A), B = f(B), A = B, (cyclic_term(A) -> X = cyclic ; X = acyclic)."), "cyclic");
+        assert_eq!(answers(&mut db, "A = [1|A], B = [1, 1|B], A = B, X = ok."), "ok");
+        assert_eq!(answers(&mut db, "A = f(A, b), B = f(B, c), (A = B -> X = unified ; X = failed)."), "failed");
+        assert_eq!(answers(&mut db, "A = f(A), format(atom(_), \"~w\", [A]), X = printed."), "printed");
+        assert_eq!(answers(&mut db, "A = f(B), (acyclic_term(A) -> X = acyclic ; X = cyclic)."), "acyclic");
+        assert_eq!(answers(&mut db, "A = f(A, B), term_variables(A, X)."), "[B]");
+        assert_eq!(answers(&mut db, "A = f(A), B = f(B), C = f(f(C)), (A == B, A == C, compare(=, A, C) -> X = equal ; X = different)."), "equal");
+        assert_eq!(answers(&mut db, "A = f(A, a), B = f(B, b), compare(X, A, B), A @< B."), "<");
+        assert_eq!(answers(&mut db, "A = f(f(A)), W = f(B), B = f(f(B)), (A == W -> X = equal ; X = different)."), "equal");
+        assert_eq!(answers(&mut db, "A = [a|A], catch(length(A, _), error(E, _), true), E = type_error(X, _)."), "list");
+        // Turning the check back on still terminates on the cycles made while it was off
+        assert_eq!(answers(&mut db, "A = f(A), set_prolog_flag(occurs_check, true), C = g(A), X = ok."), "ok");
+    }
 }
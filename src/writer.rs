@@ -0,0 +1,353 @@
+use std::sync::OnceLock;
+
+use crate::errors;
+use crate::parser::operators::{OpType, Operators};
+use crate::terms::Term;
+
+// How write_term/2 and its relatives write a term
+#[derive(Clone, Debug, Default)]
+pub struct WriteOptions {
+    pub quoted: bool,     // Quote atoms where needed, so the text reads back as the same term
+    pub ignore_ops: bool, // Write every compound term as `f(A, B)`
+    pub numbervars: bool, // Write '$VAR'(N) as a variable name
+    pub portray: bool,    // Let the user's portray/1 write subterms
+    pub max_depth: usize, // Write deeper subterms as `...`; 0 for no limit
+}
+
+impl WriteOptions {
+    // write/1
+    pub fn write() -> Self {
+        WriteOptions { numbervars: true, ..WriteOptions::default() }
+    }
+
+    // writeq/1, also used for answers and messages
+    pub fn writeq() -> Self {
+        WriteOptions { quoted: true, numbervars: true, ..WriteOptions::default() }
+    }
+
+    // print/1
+    pub fn print() -> Self {
+        WriteOptions { portray: true, ..WriteOptions::writeq() }
+    }
+
+    // write_canonical/1
+    pub fn canonical() -> Self {
+        WriteOptions { quoted: true, ignore_ops: true, ..WriteOptions::default() }
+    }
+
+    // Reads a write_term/2 option list
+    pub fn parse(options: &Term) -> Result<Self, Term> {
+        let list = match options {
+            Term::Variable(_) => return Err(errors::instantiation_error()),
+            list => list.to_vec().ok_or_else(|| errors::type_error("list", options.clone()))?,
+        };
+        let mut parsed = WriteOptions::default();
+        for option in list {
+            let flag = match &option {
+                Term::Variable(_) => return Err(errors::instantiation_error()),
+                Term::Compound(name, args) if args.len() == 1 => match (name.as_str(), &args[0]) {
+                    (_, Term::Variable(_)) => return Err(errors::instantiation_error()),
+                    ("max_depth", Term::Integer(depth)) if *depth >= 0 => {
+                        parsed.max_depth = *depth as usize;
+                        continue;
+                    }
+                    ("quoted", _) => &mut parsed.quoted,
+                    ("ignore_ops", _) => &mut parsed.ignore_ops,
+                    ("numbervars", _) => &mut parsed.numbervars,
+                    ("portray", _) => &mut parsed.portray,
+                    _ => return Err(errors::domain_error("write_option", option.clone())),
+                },
+                _ => return Err(errors::domain_error("write_option", option.clone())),
+            };
+            *flag = match option {
+                Term::Compound(_, args) if args[0] == Term::Constant("true".to_string()) => true,
+                Term::Compound(_, args) if args[0] == Term::Constant("false".to_string()) => false,
+                _ => return Err(errors::domain_error("write_option", option)),
+            };
+        }
+        Ok(parsed)
+    }
+}
+
+// Called with each subterm when portraying; returns the text to write in its place, if any
+pub type Portray<'a> = &'a mut dyn FnMut(&Term) -> Option<String>;
+
+// Writes terms with operators in operator notation, using the same table as the reader so the
+// text reads back as the same term. Brackets are only added where an operand binds looser than
+// its operator allows.
+pub struct Writer<'a> {
+    ops: &'a Operators,
+    options: WriteOptions,
+    portray: Option<Portray<'a>>,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(ops: &'a Operators, options: WriteOptions) -> Self {
+        Writer { ops, options, portray: None }
+    }
+
+    pub fn with_portray(self, portray: Portray<'a>) -> Self {
+        Writer { portray: Some(portray), ..self }
+    }
+
+    pub fn write(&mut self, term: &Term) -> String {
+        self.term(term, 1200, 1)
+    }
+
+    fn term(&mut self, term: &Term, max_priority: u32, depth: usize) -> String {
+        if self.options.max_depth > 0 && depth > self.options.max_depth {
+            return "...".to_string();
+        }
+        if let Some(portray) = self.portray.as_mut().filter(|_| self.options.portray) {
+            if let Some(text) = portray(term) {
+                return text;
+            }
+        }
+        match term {
+            Term::Variable(name) => name.clone(),
+            Term::Integer(n) => n.to_string(),
+            Term::Constant(name) => match self.atom_priority(name) {
+                // An operator standing for itself is bracketed where it could be read as an operator
+                Some(priority) if priority > max_priority => format!("({})", self.atom(name)),
+                _ => self.atom(name),
+            },
+            Term::EmptyList => "[]".to_string(),
+            Term::List(_, _) => self.list(term, depth),
+            Term::Compound(name, args) if name == "{}" && args.len() == 1 && !self.options.ignore_ops => {
+                format!("{{{}}}", self.term(&args[0], 1200, depth + 1))
+            }
+            Term::Compound(name, args) if name == "$VAR" && args.len() == 1 && self.options.numbervars => {
+                match &args[0] {
+                    Term::Integer(n) if *n >= 0 => variable_name(*n),
+                    Term::Constant(name) => name.clone(),
+                    _ => self.canonical(name, args, depth),
+                }
+            }
+            Term::Compound(name, args) => match (args.as_slice(), self.operator(name, args.len())) {
+                ([left, right], Some((priority, kind))) => {
+                    let (left_max, right_max) = kind.argument_priorities(priority);
+                    let left = self.operand(left, left_max, depth);
+                    let right = self.operand(right, right_max, depth);
+                    let op = self.atom(name);
+                    let text = match name.as_str() {
+                        "," => format!("{}, {}", left, right),
+                        _ if name.starts_with(is_alphanumeric) => format!("{} {} {}", left, op, right),
+                        _ => join(&join(&left, &op), &right),
+                    };
+                    bracket(text, priority, max_priority)
+                }
+                ([arg], Some((priority, kind))) if matches!(kind, OpType::Fy | OpType::Fx) => {
+                    let (_, arg_max) = kind.argument_priorities(priority);
+                    let operand = self.operand(arg, arg_max, depth);
+                    let op = self.atom(name);
+                    // `- 1` is the compound -(1), while `-1` would read back as a number
+                    let text = match arg {
+                        Term::Integer(n) if *n >= 0 && (name == "-" || name == "+") => format!("{} {}", op, operand),
+                        _ if operand.starts_with('(') => format!("{} {}", op, operand),
+                        _ => join(&op, &operand),
+                    };
+                    bracket(text, priority, max_priority)
+                }
+                ([arg], Some((priority, kind))) => {
+                    let (arg_max, _) = kind.argument_priorities(priority);
+                    let text = join(&self.operand(arg, arg_max, depth), &self.atom(name));
+                    bracket(text, priority, max_priority)
+                }
+                _ => self.canonical(name, args, depth),
+            },
+        }
+    }
+
+    // An operand of an operator, where an atom that is itself an operator is always bracketed
+    fn operand(&mut self, term: &Term, max_priority: u32, depth: usize) -> String {
+        match term {
+            Term::Constant(name) if self.atom_priority(name).is_some() => format!("({})", self.atom(name)),
+            term => self.term(term, max_priority, depth + 1),
+        }
+    }
+
+    fn canonical(&mut self, name: &str, args: &[Term], depth: usize) -> String {
+        let args: Vec<String> = args.iter().map(|arg| self.term(arg, 999, depth + 1)).collect();
+        format!("{}({})", self.atom(name), args.join(", "))
+    }
+
+    fn list(&mut self, list: &Term, depth: usize) -> String {
+        let mut items = vec![];
+        let mut current = list;
+        while let Term::List(head, tail) = current {
+            if self.options.max_depth > 0 && items.len() + 1 >= self.options.max_depth {
+                return format!("[{} | ...]", items.join(", "));
+            }
+            items.push(self.term(head, 999, depth + 1));
+            current = tail;
+        }
+        match current {
+            Term::EmptyList => format!("[{}]", items.join(", ")),
+            tail => format!("[{} | {}]", items.join(", "), self.term(tail, 999, depth + 1)),
+        }
+    }
+
+    // The operator a compound term with this name and arity is written with, if any
+    fn operator(&self, name: &str, arity: usize) -> Option<(u32, OpType)> {
+        match (arity, self.options.ignore_ops) {
+            (_, true) => None,
+            (2, _) => self.ops.infix(name),
+            (1, _) => self.ops.prefix(name).or_else(|| self.ops.postfix(name)),
+            _ => None,
+        }
+    }
+
+    // The highest priority of the operators named by an atom
+    fn atom_priority(&self, name: &str) -> Option<u32> {
+        [self.ops.prefix(name), self.ops.infix(name), self.ops.postfix(name)]
+            .into_iter()
+            .flatten()
+            .map(|(priority, _)| priority)
+            .max()
+    }
+
+    fn atom(&self, name: &str) -> String {
+        match self.options.quoted && needs_quotes(name) {
+            true => quote(name),
+            false => name.to_string(),
+        }
+    }
+}
+
+// Writes a term as writeq/1 does, with the operators of a database
+pub fn term_to_string(term: &Term, ops: &Operators) -> String {
+    Writer::new(ops, WriteOptions::writeq()).write(term)
+}
+
+// The standard operator table, for writing terms when there is no database at hand
+pub fn default_operators() -> &'static Operators {
+    static DEFAULT: OnceLock<Operators> = OnceLock::new();
+    DEFAULT.get_or_init(Operators::default)
+}
+
+// '$VAR'(0) is A, '$VAR'(25) is Z, '$VAR'(26) is A1 and so on
+fn variable_name(n: i64) -> String {
+    let letter = (b'A' + (n % 26) as u8) as char;
+    match n / 26 {
+        0 => letter.to_string(),
+        round => format!("{}{}", letter, round),
+    }
+}
+
+// Atoms that read back as themselves without quotes: `foo_Bar1`, symbol atoms such as `=..`,
+// and the solo atoms. The atom '[]' is quoted, as `[]` reads as the empty list.
+fn needs_quotes(name: &str) -> bool {
+    let mut chars = name.chars();
+    let plain = match chars.next() {
+        None => false,
+        Some(first) if first.is_lowercase() => chars.all(is_alphanumeric),
+        Some(_) if ["{}", "!", ";"].contains(&name) => true,
+        // `/*` would start a comment, and a lone `.` would end the clause
+        Some(_) if name.starts_with("/*") || name == "." => false,
+        Some(_) => name.chars().all(is_symbol),
+    };
+    !plain
+}
+
+fn quote(name: &str) -> String {
+    let mut quoted = String::from("'");
+    for ch in name.chars() {
+        match ch {
+            '\'' => quoted.push_str("\\'"),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            ch if ch.is_control() => quoted.push_str(&format!("\\x{:x}\\", ch as u32)),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+fn bracket(text: String, priority: u32, max_priority: u32) -> String {
+    match priority > max_priority {
+        true => format!("({})", text),
+        false => text,
+    }
+}
+
+// Puts two pieces of text together, with a space between them where they would otherwise read
+// back as one token, as in `a- -1` or `X is Y`
+fn join(left: &str, right: &str) -> String {
+    let glued = match (left.chars().last(), right.chars().next()) {
+        (Some(l), Some(r)) => (is_symbol(l) && is_symbol(r)) || (is_alphanumeric(l) && is_alphanumeric(r)),
+        _ => false,
+    };
+    match glued {
+        true => format!("{} {}", left, right),
+        false => format!("{}{}", left, right),
+    }
+}
+
+fn is_symbol(ch: char) -> bool {
+    "+-*/\\^<>=~:.?@#&$".contains(ch)
+}
+
+fn is_alphanumeric(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parser::parse_query;
+
+    fn round_trip(text: &str) -> String {
+        let term = Term::from_tree_term(parse_query(text).unwrap());
+        term_to_string(&term, &Operators::default())
+    }
+
+    fn write_with(text: &str, options: WriteOptions) -> String {
+        let term = Term::from_tree_term(parse_query(text).unwrap());
+        Writer::new(&Operators::default(), options).write(&term)
+    }
+
+    #[test]
+    fn test_writes_operators_with_minimal_brackets() {
+        assert_eq!(round_trip("X = (1 + 2) * 3."), "X=(1+2)*3");
+        assert_eq!(round_trip("X = 1 + 2 * 3."), "X=1+2*3");
+        assert_eq!(round_trip("X = 1 - (2 - 3)."), "X=1-(2-3)");
+        assert_eq!(round_trip("X is - 1 - -1."), "X is - 1- -1");
+        assert_eq!(round_trip("(a :- b, c ; \\+ d)."), "a:-b, c;\\+d");
+        assert_eq!(round_trip("X = f(- , (a :- b), [c|T])."), "X=f(-, (a:-b), [c | T])");
+        assert_eq!(round_trip("(-) = (+)."), "(-)=(+)");
+    }
+
+    #[test]
+    fn test_quoting_and_options() {
+        assert_eq!(round_trip("X = ['hello world', [], 'it''s', 'A', -, '\\n', f('B')]."), "X=['hello world', [], 'it\\'s', 'A', -, '\\n', f('B')]");
+        assert_eq!(write_with("X = 'hello world'.", WriteOptions::write()), "X=hello world");
+        assert_eq!(write_with("f('$VAR'(1), '$VAR'(27), - 1).", WriteOptions::writeq()), "f(B, B1, - 1)");
+        assert_eq!(write_with("f('$VAR'(1), 1 + a).", WriteOptions::canonical()), "f('$VAR'(1), +(1, a))");
+        let shallow = WriteOptions { max_depth: 3, ..WriteOptions::write() };
+        assert_eq!(write_with("f([1, 2, 3, 4], g(h(i(j)))).", shallow), "f([1, 2 | ...], g(h(...)))");
+    }
+
+    #[test]
+    fn test_written_terms_read_back() {
+        let ops = Operators::default();
+        let texts = [
+            "f(- (1), -(-(a)), 1 - -2).", "X = (a , b).", "[a = b, c | T] = (p :- q).", "X = {a, b}.",
+            "X = ['a b', 'don''t', [], '', (:-), ';', '\\\\'].", "X = (- (-)).", "X = f(;, '|', '[]', {}).",
+        ];
+        for text in texts {
+            let term = Term::from_tree_term(parse_query(text).unwrap());
+            let written = format!("{}.", term_to_string(&term, &ops));
+            assert_eq!(Term::from_tree_term(parse_query(&written).unwrap()), term, "{}", written);
+        }
+    }
+
+    #[test]
+    fn test_portray_writes_subterms() {
+        let term = Term::from_tree_term(parse_query("f(secret, [secret]).").unwrap());
+        let mut hide = |term: &Term| (*term == Term::Constant("secret".to_string())).then(|| "***".to_string());
+        let written = Writer::new(&Operators::default(), WriteOptions::print()).with_portray(&mut hide).write(&term);
+        assert_eq!(written, "f(***, [***])");
+    }
+}